@@ -0,0 +1,1018 @@
+use std::collections::{HashMap, HashSet};
+use std::fs::File;
+use std::io::{BufReader, Error, ErrorKind, Read, Write};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use flate2::write::DeflateEncoder;
+use tokio::sync::mpsc;
+use tokio::task::JoinHandle;
+use zip::ZipArchive;
+
+use crate::conflict::{self, ConflictPolicy};
+use crate::metadata::{self, Preserve};
+use crate::walk::{Entry, EntryKind};
+use crate::{date, paths, resume, sparse, CHUNK_SIZE};
+
+/// Files at least this large get zip64 sizes. Below `u32::MAX` because the
+/// compressed size is only known afterwards, and can come out a little larger.
+const ZIP64_THRESHOLD: u64 = 0xF000_0000;
+
+/// Most entries an archive may have to be extracted.
+const MAX_EXTRACTED_ENTRIES: usize = 1_000_000;
+
+const LOCAL_HEADER: u32 = 0x0403_4b50;
+const DATA_DESCRIPTOR: u32 = 0x0807_4b50;
+const CENTRAL_HEADER: u32 = 0x0201_4b50;
+const ZIP64_END: u32 = 0x0606_4b50;
+const ZIP64_LOCATOR: u32 = 0x0706_4b50;
+const END_OF_CENTRAL: u32 = 0x0605_4b50;
+
+/// Sizes and CRC follow the data; names are UTF-8.
+const FLAG_DESCRIPTOR: u16 = 1 << 3;
+const FLAG_UTF8: u16 = 1 << 11;
+const METHOD_STORED: u16 = 0;
+const METHOD_DEFLATE: u16 = 8;
+/// Made by a Unix host, so that readers take the permissions from the external attributes.
+const MADE_BY_UNIX: u16 = 3 << 8;
+
+/// How the body of an archive is laid out.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ArchiveFormat {
+    /// Stored as a `.zip` file, or unpacked once complete with `--extract`.
+    Zip,
+    /// Always unpacked, as it arrives, keeping permissions, times and links.
+    Tar,
+}
+
+impl ArchiveFormat {
+    pub fn to_u8(self) -> u8 {
+        match self {
+            ArchiveFormat::Zip => 1,
+            ArchiveFormat::Tar => 2,
+        }
+    }
+
+    pub fn from_u8(value: u8) -> Option<Self> {
+        match value {
+            1 => Some(ArchiveFormat::Zip),
+            2 => Some(ArchiveFormat::Tar),
+            _ => None,
+        }
+    }
+
+    pub fn extension(self) -> &'static str {
+        match self {
+            ArchiveFormat::Zip => "zip",
+            ArchiveFormat::Tar => "tar",
+        }
+    }
+}
+
+/// Limits on what one archive unpacks to, against archives built to fill the
+/// disk: zip archives with `--extract`, and every tar archive.
+#[derive(Clone, Copy)]
+pub struct Extraction {
+    /// Most bytes all of an archive's files may add up to.
+    pub max_bytes: u64,
+    /// How many times larger than the archive, and each file than its compressed
+    /// data, the contents may be.
+    pub max_ratio: u64,
+}
+
+impl Default for Extraction {
+    fn default() -> Self {
+        Extraction { max_bytes: 10 * 1024 * 1024 * 1024, max_ratio: 100 }
+    }
+}
+
+/// What came out of an extracted archive.
+#[derive(Debug, Default)]
+pub struct Extracted {
+    pub files: u64,
+    pub bytes: u64,
+    pub skipped: u64,
+}
+
+/// Starts building an archive of `entries` on a blocking thread. It is handed
+/// over in chunks of at most `CHUNK_SIZE` as it is written, never touching the
+/// disk; an error ends the stream.
+pub fn build(format: ArchiveFormat, entries: Vec<Entry>) -> mpsc::Receiver<std::io::Result<Vec<u8>>> {
+    let (chunks, receiver) = mpsc::channel(2);
+    tokio::task::spawn_blocking(move || {
+        let mut out = ChunkWriter { chunks: chunks.clone(), buffer: Vec::with_capacity(CHUNK_SIZE) };
+        let built = match format {
+            ArchiveFormat::Zip => write_zip(&mut out, &entries),
+            ArchiveFormat::Tar => write_tar(&mut out, &entries),
+        };
+        let result = built.and_then(|()| out.send_buffered());
+        if let Err(e) = result {
+            let _ = chunks.blocking_send(Err(e));
+        }
+    });
+    receiver
+}
+
+/// The most an archive of `entries` can come to, going by their sizes now. It
+/// is the size the archive is announced at: the receiver stops reading, and
+/// unpacking, past it, so a file that grows while it is archived fails the
+/// transfer.
+pub fn max_len(format: ArchiveFormat, entries: &[Entry]) -> u64 {
+    // A hard link in a tar names another entry, so any name can be a link target.
+    let longest_name = entries.iter().map(|entry| entry.name.len() as u64 + 1).max().unwrap_or(0);
+    let per_entry = entries.iter().map(|entry| match format {
+        // Zip archives hold what links point to.
+        ArchiveFormat::Zip => {
+            let size = match entry.kind {
+                EntryKind::Directory => 0,
+                EntryKind::File | EntryKind::Symlink => std::fs::metadata(&entry.path).map_or(0, |metadata| metadata.len()),
+            };
+            // Deflate adds 5 bytes to every 64 KiB it cannot compress. Around
+            // that go the local header, data descriptor and central header,
+            // the last two with zip64 sizes and timestamps at most.
+            size + size / 8192 + 2 * (entry.name.len() as u64 + 1) + 256
+        }
+        ArchiveFormat::Tar => {
+            let size = std::fs::symlink_metadata(&entry.path).ok().filter(|metadata| metadata.is_file()).map_or(0, |metadata| metadata.len());
+            let target = std::fs::read_link(&entry.path).map_or(0, |target| target.as_os_str().len() as u64 + 1);
+            // A header, GNU long name and long link headers, the data padded to
+            // whole blocks, and room for the map of a sparse file's holes.
+            512 + 2 * (512 + longest_name.max(target).next_multiple_of(512)) + size.next_multiple_of(512) + size / 64
+        }
+    });
+    // The end of the archive: zip's closing records, or tar's two zero blocks.
+    per_entry.sum::<u64>() + 1024
+}
+
+fn write_zip(out: &mut ChunkWriter, entries: &[Entry]) -> std::io::Result<()> {
+    let mut zip = ZipStream::new(out);
+    for entry in entries {
+        let added = match entry.kind {
+            // Zip archives only ever hold what links point to.
+            EntryKind::File | EntryKind::Symlink => zip.add_file(&entry.name, &entry.path),
+            EntryKind::Directory => zip.add_directory(&entry.name, &entry.path),
+        };
+        added.map_err(|e| std::io::Error::new(e.kind(), format!("{:?}: {}", entry.path, e)))?;
+    }
+    zip.finish()
+}
+
+fn write_tar(out: &mut ChunkWriter, entries: &[Entry]) -> std::io::Result<()> {
+    let mut tar = tar::Builder::new(out);
+    tar.follow_symlinks(false);
+    // Files with several names are sent once, then linked to by their other names.
+    let mut linked = HashMap::new();
+    for entry in entries {
+        let added = match entry.kind {
+            EntryKind::File | EntryKind::Symlink => add_tar_file(&mut tar, entry, &mut linked),
+            EntryKind::Directory => tar.append_dir(&entry.name, &entry.path),
+        };
+        added.map_err(|e| std::io::Error::new(e.kind(), format!("{:?}: {}", entry.path, e)))?;
+    }
+    tar.into_inner()?;
+    Ok(())
+}
+
+/// Adds a file, a symbolic link as a link, or a hard link to a file already added.
+fn add_tar_file<W: Write>(tar: &mut tar::Builder<W>, entry: &Entry, linked: &mut HashMap<(u64, u64), String>) -> std::io::Result<()> {
+    #[cfg(unix)]
+    {
+        use std::collections::hash_map::Entry as Slot;
+        use std::os::unix::fs::MetadataExt;
+
+        let metadata = std::fs::symlink_metadata(&entry.path)?;
+        if metadata.is_file() && metadata.nlink() > 1 {
+            match linked.entry((metadata.dev(), metadata.ino())) {
+                Slot::Occupied(first) => {
+                    let mut header = tar::Header::new_gnu();
+                    header.set_metadata(&metadata);
+                    header.set_entry_type(tar::EntryType::Link);
+                    header.set_size(0);
+                    return tar.append_link(&mut header, &entry.name, first.get());
+                }
+                Slot::Vacant(slot) => {
+                    slot.insert(entry.name.clone());
+                }
+            }
+        }
+    }
+    #[cfg(not(unix))]
+    let _ = linked;
+    tar.append_path_with_name(&entry.path, &entry.name)
+}
+
+/// Collects what is written into chunks and passes each one on as it fills up.
+struct ChunkWriter {
+    chunks: mpsc::Sender<std::io::Result<Vec<u8>>>,
+    buffer: Vec<u8>,
+}
+
+impl ChunkWriter {
+    fn send_buffered(&mut self) -> std::io::Result<()> {
+        if self.buffer.is_empty() {
+            return Ok(());
+        }
+        let chunk = std::mem::replace(&mut self.buffer, Vec::with_capacity(CHUNK_SIZE));
+        // Nobody is listening once the transfer has failed; stop building.
+        self.chunks.blocking_send(Ok(chunk)).map_err(|_| Error::new(ErrorKind::BrokenPipe, "transfer stopped"))
+    }
+}
+
+impl Write for ChunkWriter {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let n = buf.len().min(CHUNK_SIZE - self.buffer.len());
+        self.buffer.extend_from_slice(&buf[..n]);
+        if self.buffer.len() == CHUNK_SIZE {
+            self.send_buffered()?;
+        }
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Counts the bytes written through it, which gives the offsets a zip needs.
+struct Counter<W> {
+    inner: W,
+    count: u64,
+}
+
+impl<W: Write> Write for Counter<W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let n = self.inner.write(buf)?;
+        self.count += n as u64;
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// Writes a zip archive front to back, without seeking: each file's CRC and
+/// sizes follow its data in a data descriptor, and are repeated in the central
+/// directory at the end, which is where readers look for them.
+struct ZipStream<W> {
+    out: Counter<W>,
+    central: Bytes,
+    entries: u64,
+}
+
+/// The fields local and central headers share.
+struct Header<'a> {
+    name: &'a str,
+    method: u16,
+    flags: u16,
+    mtime: Option<SystemTime>,
+    /// Unix file type and permission bits.
+    mode: u32,
+    zip64: bool,
+}
+
+impl<W: Write> ZipStream<W> {
+    fn new(out: W) -> Self {
+        ZipStream { out: Counter { inner: out, count: 0 }, central: Bytes::default(), entries: 0 }
+    }
+
+    fn add_file(&mut self, name: &str, path: &Path) -> std::io::Result<()> {
+        let mut file = File::open(path)?;
+        let metadata = file.metadata()?;
+        let header = Header {
+            name,
+            method: METHOD_DEFLATE,
+            flags: FLAG_DESCRIPTOR | FLAG_UTF8,
+            mtime: metadata.modified().ok(),
+            mode: 0o100000 | permissions(&metadata, 0o644),
+            zip64: metadata.len() >= ZIP64_THRESHOLD,
+        };
+        let offset = self.out.count;
+        self.write_local_header(&header)?;
+
+        let data_start = self.out.count;
+        let mut crc = crc32fast::Hasher::new();
+        let mut size = 0;
+        let mut encoder = DeflateEncoder::new(&mut self.out, flate2::Compression::default());
+        let mut buffer = vec![0; 64 * 1024];
+        loop {
+            let n = file.read(&mut buffer)?;
+            if n == 0 {
+                break;
+            }
+            crc.update(&buffer[..n]);
+            encoder.write_all(&buffer[..n])?;
+            size += n as u64;
+        }
+        encoder.finish()?;
+        let compressed = self.out.count - data_start;
+        if !header.zip64 && (size > u32::MAX as u64 || compressed > u32::MAX as u64) {
+            return Err(Error::other(format!("'{}' grew past 4 GiB while being archived", name)));
+        }
+        let crc = crc.finalize();
+
+        let mut descriptor = Bytes::default();
+        descriptor.u32(DATA_DESCRIPTOR);
+        descriptor.u32(crc);
+        if header.zip64 {
+            descriptor.u64(compressed);
+            descriptor.u64(size);
+        } else {
+            descriptor.u32(compressed as u32);
+            descriptor.u32(size as u32);
+        }
+        self.out.write_all(&descriptor.0)?;
+        self.add_central_header(&header, crc, compressed, size, offset);
+        Ok(())
+    }
+
+    fn add_directory(&mut self, name: &str, path: &Path) -> std::io::Result<()> {
+        let metadata = std::fs::metadata(path)?;
+        let name = format!("{}/", name);
+        let header = Header {
+            name: &name,
+            method: METHOD_STORED,
+            flags: FLAG_UTF8,
+            mtime: metadata.modified().ok(),
+            mode: 0o040000 | permissions(&metadata, 0o755),
+            zip64: false,
+        };
+        let offset = self.out.count;
+        self.write_local_header(&header)?;
+        self.add_central_header(&header, 0, 0, 0, offset);
+        Ok(())
+    }
+
+    fn write_local_header(&mut self, header: &Header) -> std::io::Result<()> {
+        let mut extra = Bytes::default();
+        if header.zip64 {
+            // The sizes themselves are in the data descriptor.
+            extra.u16(0x0001);
+            extra.u16(16);
+            extra.u64(0);
+            extra.u64(0);
+        }
+        extra.timestamp(header.mtime);
+
+        let mut local = Bytes::default();
+        local.u32(LOCAL_HEADER);
+        local.u16(if header.zip64 { 45 } else { 20 });
+        local.u16(header.flags);
+        local.u16(header.method);
+        local.dos_time(header.mtime);
+        local.u32(0);
+        let size = if header.zip64 { u32::MAX } else { 0 };
+        local.u32(size);
+        local.u32(size);
+        local.u16(header.name.len() as u16);
+        local.u16(extra.0.len() as u16);
+        local.0.extend_from_slice(header.name.as_bytes());
+        local.0.extend_from_slice(&extra.0);
+        self.out.write_all(&local.0)
+    }
+
+    fn add_central_header(&mut self, header: &Header, crc: u32, compressed: u64, size: u64, offset: u64) {
+        let zip64 = header.zip64 || offset >= u32::MAX as u64;
+        let mut extra = Bytes::default();
+        if zip64 {
+            extra.u16(0x0001);
+            extra.u16(24);
+            extra.u64(size);
+            extra.u64(compressed);
+            extra.u64(offset);
+        }
+        extra.timestamp(header.mtime);
+
+        let central = &mut self.central;
+        central.u32(CENTRAL_HEADER);
+        central.u16(MADE_BY_UNIX | 45);
+        central.u16(if zip64 { 45 } else { 20 });
+        central.u16(header.flags);
+        central.u16(header.method);
+        central.dos_time(header.mtime);
+        central.u32(crc);
+        let clamp = |value: u64| if zip64 { u32::MAX } else { value as u32 };
+        central.u32(clamp(compressed));
+        central.u32(clamp(size));
+        central.u16(header.name.len() as u16);
+        central.u16(extra.0.len() as u16);
+        central.u16(0);
+        central.u16(0);
+        central.u16(0);
+        // MS-DOS readers only understand the directory bit.
+        let dos_directory = if header.mode & 0o040000 != 0 { 0x10 } else { 0 };
+        central.u32(header.mode << 16 | dos_directory);
+        central.u32(clamp(offset));
+        central.0.extend_from_slice(header.name.as_bytes());
+        central.0.extend_from_slice(&extra.0);
+        self.entries += 1;
+    }
+
+    fn finish(mut self) -> std::io::Result<()> {
+        let central_offset = self.out.count;
+        let central_size = self.central.0.len() as u64;
+        self.out.write_all(&self.central.0)?;
+
+        let mut end = Bytes::default();
+        let zip64 = self.entries >= u16::MAX as u64 || central_offset >= u32::MAX as u64 || central_size >= u32::MAX as u64;
+        if zip64 {
+            let zip64_end_offset = self.out.count;
+            end.u32(ZIP64_END);
+            end.u64(44);
+            end.u16(MADE_BY_UNIX | 45);
+            end.u16(45);
+            end.u32(0);
+            end.u32(0);
+            end.u64(self.entries);
+            end.u64(self.entries);
+            end.u64(central_size);
+            end.u64(central_offset);
+            end.u32(ZIP64_LOCATOR);
+            end.u32(0);
+            end.u64(zip64_end_offset);
+            end.u32(1);
+        }
+        end.u32(END_OF_CENTRAL);
+        end.u16(0);
+        end.u16(0);
+        let entries = if zip64 { u16::MAX } else { self.entries as u16 };
+        end.u16(entries);
+        end.u16(entries);
+        end.u32(if zip64 { u32::MAX } else { central_size as u32 });
+        end.u32(if zip64 { u32::MAX } else { central_offset as u32 });
+        end.u16(0);
+        self.out.write_all(&end.0)
+    }
+}
+
+fn permissions(metadata: &std::fs::Metadata, default: u32) -> u32 {
+    metadata::mode(metadata).unwrap_or(default)
+}
+
+/// Little-endian fields, as zip has them.
+#[derive(Default)]
+struct Bytes(Vec<u8>);
+
+impl Bytes {
+    fn u16(&mut self, value: u16) {
+        self.0.extend_from_slice(&value.to_le_bytes());
+    }
+
+    fn u32(&mut self, value: u32) {
+        self.0.extend_from_slice(&value.to_le_bytes());
+    }
+
+    fn u64(&mut self, value: u64) {
+        self.0.extend_from_slice(&value.to_le_bytes());
+    }
+
+    /// The MS-DOS time and date every header has, in UTC, since the format
+    /// has no time zone. Times before 1980 are clamped to its start.
+    fn dos_time(&mut self, time: Option<SystemTime>) {
+        let secs = time.and_then(|time| time.duration_since(UNIX_EPOCH).ok()).map_or(0, |since| since.as_secs());
+        let (year, month, day) = date::civil_from_days((secs / 86_400) as i64);
+        let (time, date) = if year < 1980 {
+            (0, 1 << 5 | 1)
+        } else {
+            let of_day = secs % 86_400;
+            let time = ((of_day / 3600) << 11) | ((of_day / 60 % 60) << 5) | (of_day % 60 / 2);
+            let date = (((year - 1980).min(127) as u64) << 9) | ((month as u64) << 5) | day as u64;
+            (time as u16, date as u16)
+        };
+        self.u16(time);
+        self.u16(date);
+    }
+
+    /// An extended timestamp field with the modification time, which unlike the
+    /// MS-DOS one is exact to the second and in UTC by definition.
+    fn timestamp(&mut self, time: Option<SystemTime>) {
+        let Some(secs) = time.and_then(|time| time.duration_since(UNIX_EPOCH).ok()).and_then(|since| u32::try_from(since.as_secs()).ok()) else {
+            return;
+        };
+        self.u16(0x5455);
+        self.u16(5);
+        self.0.push(1);
+        self.u32(secs);
+    }
+}
+
+fn invalid(message: impl Into<String>) -> Error {
+    Error::new(ErrorKind::InvalidData, message.into())
+}
+
+/// Unpacks the zip archive at `archive` into `root`, applying `policy` to files
+/// that already exist there. Every entry is checked against the limits, and
+/// its name confined to `root`, before anything is written, and nothing is put
+/// in place unless every file comes out intact.
+pub fn extract_zip(
+    archive: &Path,
+    root: &Path,
+    policy: ConflictPolicy,
+    limits: Extraction,
+    max_file_size: Option<u64>,
+) -> std::io::Result<Extracted> {
+    let archive_len = std::fs::metadata(archive)?.len();
+    let mut zip = ZipArchive::new(BufReader::new(File::open(archive)?)).map_err(|e| invalid(format!("not a usable zip archive: {}", e)))?;
+    if zip.len() > MAX_EXTRACTED_ENTRIES {
+        return Err(invalid(format!("archive has {} entries, more than the {} allowed", zip.len(), MAX_EXTRACTED_ENTRIES)));
+    }
+
+    // The sizes in the central directory are only claims, but checking them
+    // first turns away most bombs before a byte is written. Extraction itself
+    // holds each entry to its claimed size.
+    let mut total: u64 = 0;
+    let mut names = HashSet::new();
+    let mut files = HashSet::new();
+    for index in 0..zip.len() {
+        let entry = zip.by_index_raw(index).map_err(|e| invalid(e.to_string()))?;
+        let name = entry.name().trim_end_matches('/');
+        paths::sanitize(name).map_err(|reason| invalid(format!("unsafe path {:?} in archive: {}", entry.name(), reason)))?;
+        // Each entry is put in place at the end, so no two may land on the same path.
+        if !names.insert(name.to_string()) {
+            return Err(invalid(format!("{:?} is in the archive more than once", name)));
+        }
+        if entry.is_dir() {
+            continue;
+        }
+        files.insert(name.to_string());
+        if entry.is_symlink() {
+            return Err(invalid(format!("{:?} is a symbolic link, which is not extracted from a zip archive", name)));
+        }
+        if let Some(max_size) = max_file_size.filter(|max_size| entry.size() > *max_size) {
+            return Err(Error::new(
+                ErrorKind::PermissionDenied,
+                format!("{:?} is {} bytes, over the {} byte limit", name, entry.size(), max_size),
+            ));
+        }
+        if entry.size() > entry.compressed_size().max(1).saturating_mul(limits.max_ratio) {
+            return Err(invalid(format!(
+                "{:?} would expand {} times, more than the {}:1 allowed",
+                name, entry.size() / entry.compressed_size().max(1), limits.max_ratio
+            )));
+        }
+        total = total.saturating_add(entry.size());
+    }
+    for name in &names {
+        let mut ancestors = name.match_indices('/').map(|(at, _)| &name[..at]);
+        if let Some(file) = ancestors.find(|ancestor| files.contains(*ancestor)) {
+            return Err(invalid(format!("{:?} is in the archive both as a file and as a directory", file)));
+        }
+    }
+    if total > limits.max_bytes {
+        return Err(invalid(format!("archive would extract to {} bytes, over the {} byte limit", total, limits.max_bytes)));
+    }
+    if total > archive_len.max(1).saturating_mul(limits.max_ratio) {
+        return Err(invalid(format!("archive would expand more than the {}:1 allowed", limits.max_ratio)));
+    }
+
+    // Files are written next to their destinations, and only put in place
+    // once every one has come out intact; an error removes them all again.
+    let mut unpacked = Unpacked::default();
+    for index in 0..zip.len() {
+        let mut entry = zip.by_index(index).map_err(|e| invalid(e.to_string()))?;
+        let name = entry.name().trim_end_matches('/').to_string();
+        let path = paths::sanitize(&name)
+            .and_then(|relative| paths::resolve(root, &relative))
+            .map_err(|reason| Error::new(ErrorKind::PermissionDenied, format!("unsafe path {:?} in archive: {}", name, reason)))?;
+        if entry.is_dir() {
+            std::fs::create_dir_all(&path)?;
+            continue;
+        }
+        if let Some(parent) = path.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+
+        let mtime = entry.extra_data_fields().find_map(|field| match field {
+            zip::ExtraField::ExtendedTimestamp(timestamp) => timestamp.mod_time(),
+            _ => None,
+        });
+        let mtime = mtime.map(|secs| UNIX_EPOCH + Duration::from_secs(secs as u64));
+        let path = match conflict::resolve(path, policy, mtime)? {
+            Some(path) => path,
+            None => {
+                unpacked.extracted.skipped += 1;
+                continue;
+            }
+        };
+
+        let partial = resume::partial_path(&path);
+        let mut file = File::create(&partial).map_err(|e| Error::new(e.kind(), format!("{:?}: {}", name, e)))?;
+        unpacked.pending.push((partial, path));
+        let claimed = entry.size();
+        let copied = std::io::copy(&mut (&mut entry).take(claimed + 1), &mut file)
+            .and_then(|copied| file.sync_all().map(|()| copied))
+            .map_err(|e| Error::new(e.kind(), format!("{:?}: {}", name, e)))?;
+        if copied != claimed {
+            return Err(invalid(format!("{:?} does not hold the {} bytes its header says", name, claimed)));
+        }
+        unpacked.extracted.files += 1;
+        unpacked.extracted.bytes += copied;
+    }
+    unpacked.commit()
+}
+
+/// Starts unpacking a tar archive into `root` on a blocking thread, from the
+/// chunks sent to the returned channel; closing it ends the archive. Files are
+/// written next to their destinations, and only put in place by `Unpacked::commit`
+/// once the whole archive is known to be intact.
+pub fn unpack_tar(
+    root: PathBuf,
+    policy: ConflictPolicy,
+    limits: Extraction,
+    max_file_size: Option<u64>,
+    preserve: Preserve,
+) -> (mpsc::Sender<Vec<u8>>, JoinHandle<std::io::Result<Unpacked>>) {
+    let (sender, chunks) = mpsc::channel(2);
+    let unpacking = tokio::task::spawn_blocking(move || {
+        let mut reader = ChunkReader { chunks, chunk: Vec::new(), read: 0 };
+        let unpacked = read_tar(&mut reader, &root, policy, limits, max_file_size, preserve)?;
+        // Anything after the end of the archive still counts towards its hash.
+        std::io::copy(&mut reader, &mut std::io::sink())?;
+        Ok(unpacked)
+    });
+    (sender, unpacking)
+}
+
+/// Reads the chunks passed to it as one stream, which ends when the channel closes.
+struct ChunkReader {
+    chunks: mpsc::Receiver<Vec<u8>>,
+    chunk: Vec<u8>,
+    read: usize,
+}
+
+impl Read for ChunkReader {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        while self.read == self.chunk.len() {
+            match self.chunks.blocking_recv() {
+                Some(chunk) => {
+                    self.chunk = chunk;
+                    self.read = 0;
+                }
+                None => return Ok(0),
+            }
+        }
+        let n = buf.len().min(self.chunk.len() - self.read);
+        buf[..n].copy_from_slice(&self.chunk[self.read..self.read + n]);
+        self.read += n;
+        Ok(n)
+    }
+}
+
+/// An unpacked archive waiting to be put in place. Dropping it removes what
+/// was unpacked, other than directories.
+#[derive(Default)]
+pub struct Unpacked {
+    /// Files, links and hard links, each at its partial path and the destination it is renamed to.
+    pending: Vec<(PathBuf, PathBuf)>,
+    /// Directories with their permissions and modification time, which are
+    /// applied last since adding to a directory changes its time.
+    directories: Vec<(PathBuf, Option<u32>, Option<SystemTime>)>,
+    extracted: Extracted,
+}
+
+impl Unpacked {
+    /// Moves everything unpacked into place.
+    pub fn commit(mut self) -> std::io::Result<Extracted> {
+        self.pending.reverse();
+        while let Some((partial, path)) = self.pending.pop() {
+            if let Err(e) = std::fs::rename(&partial, &path) {
+                let _ = std::fs::remove_file(&partial);
+                return Err(Error::new(e.kind(), format!("{:?}: {}", path, e)));
+            }
+        }
+        // Deepest first, so setting a directory's time is the last change to it.
+        for (path, mode, mtime) in self.directories.iter().rev() {
+            if let Some(mode) = mode {
+                let _ = metadata::set_mode(path, *mode);
+            }
+            if let Some(mtime) = mtime {
+                let _ = File::open(path).and_then(|dir| dir.set_modified(*mtime));
+            }
+        }
+        Ok(std::mem::take(&mut self.extracted))
+    }
+}
+
+impl Drop for Unpacked {
+    fn drop(&mut self) {
+        for (partial, _) in &self.pending {
+            let _ = std::fs::remove_file(partial);
+        }
+    }
+}
+
+fn read_tar(
+    reader: impl Read,
+    root: &Path,
+    policy: ConflictPolicy,
+    limits: Extraction,
+    max_file_size: Option<u64>,
+    preserve: Preserve,
+) -> std::io::Result<Unpacked> {
+    let mut unpacked = Unpacked::default();
+    // What the entries so far unpack to. A sparse entry unpacks to its real
+    // size, which the data sent for it need not come anywhere near.
+    let mut total: u64 = 0;
+    // Where each file unpacked so far can be found, for hard links to it.
+    let mut contents: HashMap<String, PathBuf> = HashMap::new();
+    let mut archive = tar::Archive::new(reader);
+    for (index, entry) in archive.entries()?.enumerate() {
+        if index >= MAX_EXTRACTED_ENTRIES {
+            return Err(invalid(format!("archive has more than the {} entries allowed", MAX_EXTRACTED_ENTRIES)));
+        }
+        let mut entry = entry?;
+        let kind = entry.header().entry_type();
+        if kind.is_pax_global_extensions() {
+            continue;
+        }
+        let name = String::from_utf8(entry.path_bytes().into_owned()).map_err(|_| invalid("archive has a name that is not valid UTF-8"))?;
+        let name = name.trim_end_matches('/').to_string();
+        let unsafe_path = |reason: String| Error::new(ErrorKind::PermissionDenied, format!("unsafe path {:?} in archive: {}", name, reason));
+        let relative = paths::sanitize(&name).map_err(unsafe_path)?;
+        let mode = entry.header().mode().ok().filter(|_| preserve.permissions);
+        let mtime = entry.header().mtime().ok().map(|secs| UNIX_EPOCH + Duration::from_secs(secs));
+
+        // A link unpacked earlier only becomes one on commit, so nothing may be unpacked into it.
+        let mut ancestors = name.match_indices('/').map(|(at, _)| &name[..at]);
+        if let Some(ancestor) = ancestors.find(|ancestor| contents.contains_key(*ancestor)) {
+            return Err(unsafe_path(format!("{:?} is not a directory in the archive", ancestor)));
+        }
+
+        if kind.is_dir() {
+            let path = paths::resolve(root, &relative).map_err(unsafe_path)?;
+            std::fs::create_dir_all(&path)?;
+            unpacked.directories.push((path, mode, mtime.filter(|_| preserve.times)));
+            continue;
+        }
+        // A file with holes comes as a sparse entry, which reads as the whole file.
+        let is_file = kind.is_file() || kind.is_gnu_sparse();
+        if !(is_file || kind.is_symlink() || kind.is_hard_link()) {
+            return Err(invalid(format!("{:?} is not a file, directory or link, which is all that is extracted", name)));
+        }
+        if let Some(max_size) = max_file_size.filter(|max_size| entry.size() > *max_size) {
+            return Err(Error::new(
+                ErrorKind::PermissionDenied,
+                format!("{:?} is {} bytes, over the {} byte limit", name, entry.size(), max_size),
+            ));
+        }
+        total = total.saturating_add(entry.size());
+        if total > limits.max_bytes {
+            return Err(invalid(format!("archive would unpack to more than the {} byte limit", limits.max_bytes)));
+        }
+        let archive_len = entry.raw_file_position().saturating_add(entry.header().entry_size()?);
+        if total > archive_len.saturating_mul(limits.max_ratio) {
+            return Err(invalid(format!("archive would expand more than the {}:1 allowed", limits.max_ratio)));
+        }
+
+        let target = match entry.link_name_bytes() {
+            Some(target) => Some(String::from_utf8(target.into_owned()).map_err(|_| invalid(format!("{:?} links to a name that is not valid UTF-8", name)))?),
+            None => None,
+        };
+        if let Some(target) = target.as_deref().filter(|target| kind.is_symlink() && !paths::link_stays_within(&relative, target)) {
+            return Err(unsafe_path(format!("it points to {:?}, outside the destination", target)));
+        }
+
+        // Only what leads up to the entry has to be free of links: an existing
+        // link by the entry's own name is replaced, not written through.
+        let parent = paths::resolve(root, relative.parent().unwrap_or(Path::new(""))).map_err(unsafe_path)?;
+        std::fs::create_dir_all(&parent)?;
+        let path = parent.join(relative.file_name().unwrap_or_default());
+        let Some(path) = conflict::resolve(path.clone(), policy, mtime)? else {
+            contents.insert(name, path);
+            unpacked.extracted.skipped += 1;
+            continue;
+        };
+
+        let partial = resume::partial_path(&path);
+        match std::fs::remove_file(&partial) {
+            Err(e) if e.kind() != ErrorKind::NotFound => return Err(e),
+            _ => {}
+        }
+        if is_file {
+            let mut file = File::create(&partial)?;
+            unpacked.pending.push((partial.clone(), path));
+            let copied = if kind.is_gnu_sparse() { sparse::copy_sparse(&mut entry, &mut file)? } else { std::io::copy(&mut entry, &mut file)? };
+            if let Some(mtime) = mtime.filter(|_| preserve.times) {
+                file.set_modified(mtime)?;
+            }
+            if let Some(mode) = mode {
+                metadata::set_mode(&partial, mode)?;
+            }
+            file.sync_all()?;
+            unpacked.extracted.bytes += copied;
+        } else {
+            let target = target.unwrap_or_default();
+            if kind.is_symlink() {
+                paths::symlink(&target, &partial)?;
+            } else {
+                let original = contents
+                    .get(target.trim_end_matches('/'))
+                    .ok_or_else(|| invalid(format!("{:?} is a hard link to {:?}, which is not earlier in the archive", name, target)))?;
+                std::fs::hard_link(original, &partial)?;
+            }
+            unpacked.pending.push((partial.clone(), path));
+        }
+        contents.insert(name, partial);
+        unpacked.extracted.files += 1;
+    }
+    Ok(unpacked)
+}
+
+#[cfg(test)]
+mod tests {
+    use zip::write::SimpleFileOptions;
+    use zip::{CompressionMethod, ZipWriter};
+    use super::*;
+
+    const LIMITS: Extraction = Extraction { max_bytes: 1024 * 1024, max_ratio: 100 };
+
+    /// A directory of its own for a test, holding `files` zipped up as
+    /// `archive.zip` and an empty `out` to extract them into.
+    fn zipped(name: &str, files: &[(&str, Vec<u8>)]) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("streamline-archive-{}-{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(dir.join("out")).unwrap();
+        let mut zip = ZipWriter::new(File::create(dir.join("archive.zip")).unwrap());
+        for (name, content) in files {
+            zip.start_file(*name, SimpleFileOptions::default().compression_method(CompressionMethod::Deflated)).unwrap();
+            zip.write_all(content).unwrap();
+        }
+        zip.finish().unwrap();
+        dir
+    }
+
+    fn extract(dir: &Path, limits: Extraction, max_file_size: Option<u64>) -> std::io::Result<Extracted> {
+        extract_zip(&dir.join("archive.zip"), &dir.join("out"), ConflictPolicy::Fail, limits, max_file_size)
+    }
+
+    /// Bytes that don't compress, so they stay well within any ratio.
+    fn noise(len: usize) -> Vec<u8> {
+        let mut state = 0x2545_f491_4f6c_dd1d_u64;
+        (0..len).map(|_| {
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            state as u8
+        }).collect()
+    }
+
+    fn is_empty(dir: &Path) -> bool {
+        std::fs::read_dir(dir).unwrap().next().is_none()
+    }
+
+    #[tokio::test]
+    async fn built_archives_stay_within_their_announced_size() {
+        let dir = std::env::temp_dir().join(format!("streamline-archive-max-len-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        let long = "n".repeat(200);
+        std::fs::create_dir_all(dir.join("top").join(&long)).unwrap();
+        std::fs::write(dir.join("top/noise"), noise(300 * 1024)).unwrap();
+        std::fs::write(dir.join("top").join(&long).join(&long), noise(10)).unwrap();
+        std::fs::write(dir.join("top/empty"), b"").unwrap();
+        #[cfg(unix)]
+        std::os::unix::fs::symlink(format!("{}noise", "./".repeat(300)), dir.join("top/link")).unwrap();
+        let mut entries: Vec<Entry> = ["top", "top/noise", "top/empty"]
+            .iter()
+            .map(|name| Entry { path: dir.join(name), name: name.to_string(), kind: if *name == "top" { EntryKind::Directory } else { EntryKind::File } })
+            .collect();
+        entries.push(Entry { path: dir.join("top").join(&long), name: format!("top/{}", long), kind: EntryKind::Directory });
+        entries.push(Entry { path: dir.join("top").join(&long).join(&long), name: format!("top/{}/{}", long, long), kind: EntryKind::File });
+        #[cfg(unix)]
+        entries.push(Entry { path: dir.join("top/link"), name: "top/link".to_string(), kind: EntryKind::Symlink });
+
+        for format in [ArchiveFormat::Zip, ArchiveFormat::Tar] {
+            let max_len = max_len(format, &entries);
+            let mut chunks = build(format, entries.iter().map(|entry| Entry { path: entry.path.clone(), name: entry.name.clone(), kind: entry.kind }).collect());
+            let mut len = 0;
+            while let Some(chunk) = chunks.recv().await {
+                len += chunk.unwrap().len() as u64;
+            }
+            assert!(len > 300 * 1024 && len <= max_len, "{:?}: {} bytes, announced at {}", format.extension(), len, max_len);
+        }
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn archive_within_limits_is_extracted() {
+        let dir = zipped("within", &[("a.txt", noise(1000)), ("sub/b.txt", noise(2000))]);
+        let extracted = extract(&dir, LIMITS, None).unwrap();
+        assert_eq!((extracted.files, extracted.bytes, extracted.skipped), (2, 3000, 0));
+        assert_eq!(std::fs::read(dir.join("out/sub/b.txt")).unwrap(), noise(2000));
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn file_expanding_past_the_ratio_is_refused() {
+        // A megabyte of zeros deflates to about a kilobyte.
+        let dir = zipped("ratio", &[("small.txt", noise(100)), ("bomb", vec![0; 1024 * 1024])]);
+        let error = extract(&dir, Extraction { max_bytes: u64::MAX, ..LIMITS }, None).unwrap_err();
+        assert!(error.to_string().contains("\"bomb\" would expand"), "{}", error);
+        assert!(is_empty(&dir.join("out")));
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn archive_over_the_size_limit_is_refused() {
+        let dir = zipped("size", &[("a", noise(600 * 1024)), ("b", noise(600 * 1024))]);
+        let error = extract(&dir, LIMITS, None).unwrap_err();
+        assert!(error.to_string().contains("would extract to"), "{}", error);
+        assert!(is_empty(&dir.join("out")));
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn file_over_the_size_limit_is_refused() {
+        let dir = zipped("file-size", &[("a", noise(1000)), ("b", noise(5000))]);
+        let error = extract(&dir, LIMITS, Some(4096)).unwrap_err();
+        assert_eq!(error.kind(), ErrorKind::PermissionDenied);
+        assert!(is_empty(&dir.join("out")));
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn a_late_failure_leaves_nothing_behind() {
+        let dir = zipped("late", &[("a.txt", noise(10)), ("sub/b.txt", noise(10)), ("c.txt", noise(10))]);
+        std::fs::write(dir.join("out/c.txt"), b"mine").unwrap();
+        let error = extract(&dir, LIMITS, None).unwrap_err();
+        assert!(error.to_string().contains("c.txt"), "{}", error);
+        let left: Vec<_> = std::fs::read_dir(dir.join("out")).unwrap().map(|entry| entry.unwrap().file_name()).collect();
+        assert!(left.iter().all(|name| name == "c.txt" || name == "sub"), "{:?}", left);
+        assert!(is_empty(&dir.join("out/sub")));
+        assert_eq!(std::fs::read(dir.join("out/c.txt")).unwrap(), b"mine");
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn unsafe_names_are_refused_before_anything_is_written() {
+        for name in ["../escape", "/etc/escape", "a/../../escape"] {
+            let dir = zipped("unsafe", &[("fine.txt", noise(10)), (name, noise(10))]);
+            let error = extract(&dir, LIMITS, None).unwrap_err();
+            assert!(error.to_string().contains("unsafe path"), "{}", error);
+            assert!(is_empty(&dir.join("out")));
+            std::fs::remove_dir_all(dir).unwrap();
+        }
+    }
+
+    /// A tar archive holding `data` as the end of a sparse file of `real_size`
+    /// bytes, the rest of it a hole, after a file of `padding` ordinary bytes.
+    fn sparse_tar(padding: usize, real_size: u64, data: &[u8]) -> Vec<u8> {
+        let mut tar = tar::Builder::new(Vec::new());
+        let mut header = tar::Header::new_gnu();
+        header.set_size(padding as u64);
+        header.set_mode(0o644);
+        tar.append_data(&mut header, "padding", &noise(padding)[..]).unwrap();
+
+        let mut header = tar::Header::new_gnu();
+        header.set_path("sparse").unwrap();
+        header.set_entry_type(tar::EntryType::GNUSparse);
+        header.set_mode(0o644);
+        header.set_size(data.len() as u64);
+        let gnu = header.as_gnu_mut().unwrap();
+        gnu.sparse[0].set_offset(real_size - data.len() as u64);
+        gnu.sparse[0].set_length(data.len() as u64);
+        gnu.set_real_size(real_size);
+        header.set_cksum();
+        tar.append(&header, data).unwrap();
+        tar.into_inner().unwrap()
+    }
+
+    fn unpack(name: &str, tar: &[u8], limits: Extraction) -> (PathBuf, std::io::Result<Extracted>) {
+        let root = std::env::temp_dir().join(format!("streamline-archive-{}-{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&root);
+        std::fs::create_dir_all(&root).unwrap();
+        let preserve = Preserve { times: true, permissions: true, xattrs: false };
+        let unpacked = read_tar(tar, &root, ConflictPolicy::Fail, limits, None, preserve).and_then(Unpacked::commit);
+        (root, unpacked)
+    }
+
+    #[test]
+    fn sparse_entries_unpack_to_their_real_size() {
+        let tar = sparse_tar(64 * 1024, 512 * 1024, b"the end");
+        let (root, extracted) = unpack("sparse", &tar, LIMITS);
+        assert_eq!(extracted.unwrap().bytes, (64 + 512) * 1024);
+        let unpacked = std::fs::read(root.join("sparse")).unwrap();
+        assert_eq!(unpacked.len(), 512 * 1024);
+        assert!(unpacked.ends_with(b"the end") && unpacked[..512 * 1024 - 7].iter().all(|&byte| byte == 0));
+        std::fs::remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn sparse_entry_over_the_size_limit_is_refused() {
+        // A terabyte, from a few kilobytes sent.
+        let tar = sparse_tar(0, 1 << 40, b"the end");
+        let (root, extracted) = unpack("sparse-size", &tar, Extraction { max_ratio: u64::MAX, ..LIMITS });
+        let error = extracted.unwrap_err();
+        assert!(error.to_string().contains("byte limit"), "{}", error);
+        assert!(is_empty(&root));
+        std::fs::remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn sparse_entry_expanding_past_the_ratio_is_refused() {
+        let tar = sparse_tar(1024, 1024 * 1024, b"the end");
+        let (root, extracted) = unpack("sparse-ratio", &tar, Extraction { max_bytes: u64::MAX, max_ratio: 100 });
+        let error = extracted.unwrap_err();
+        assert!(error.to_string().contains("100:1"), "{}", error);
+        assert!(is_empty(&root));
+        std::fs::remove_dir_all(root).unwrap();
+    }
+}
@@ -0,0 +1,204 @@
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+use sha2::{Digest, Sha256};
+
+use crate::{date, paths};
+
+/// Something a client can ask the server to do.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Operation {
+    Push,
+    Pull,
+    List,
+    Delete,
+}
+
+impl Operation {
+    fn parse(value: &str) -> Result<Self, String> {
+        match value {
+            "push" => Ok(Operation::Push),
+            "pull" => Ok(Operation::Pull),
+            "list" => Ok(Operation::List),
+            "delete" => Ok(Operation::Delete),
+            other => Err(format!("unknown operation '{}' (expected push, pull, list or delete)", other)),
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Operation::Push => "push",
+            Operation::Pull => "pull",
+            Operation::List => "list",
+            Operation::Delete => "delete",
+        }
+    }
+}
+
+/// One line of the token file.
+pub struct Token {
+    pub label: String,
+    /// SHA-256 of the token itself, so lookups compare digests rather than secrets.
+    digest: [u8; 32],
+    operations: Vec<Operation>,
+    /// Subdirectory the token is confined to, within the output directory and the export.
+    pub dir: Option<PathBuf>,
+    /// Largest file, in bytes, the token may upload.
+    pub max_size: Option<u64>,
+    /// The last day the token is valid, in days since the Unix epoch (UTC).
+    expires: Option<i64>,
+}
+
+impl Token {
+    pub fn allows(&self, operation: Operation) -> bool {
+        self.operations.contains(&operation)
+    }
+
+    pub fn expired(&self) -> bool {
+        let secs = SystemTime::now().duration_since(UNIX_EPOCH).map(|since| since.as_secs()).unwrap_or(0);
+        self.expires.is_some_and(|last_day| (secs / 86_400) as i64 > last_day)
+    }
+}
+
+/// The tokens a server started with `--tokens` accepts.
+pub struct Tokens(Vec<Token>);
+
+impl Tokens {
+    /// Reads a token file. Each line holds a label, the token, then settings:
+    ///
+    /// ```text
+    /// alice  s3cr3t-token  ops=push,list  dir=alice  max-size=500M  expires=2027-06-30
+    /// ```
+    ///
+    /// `ops` is required; the others are optional. A `#` at the start of a line
+    /// or after whitespace starts a comment, so tokens may contain `#` but not
+    /// start with it.
+    pub fn load(path: &Path) -> Result<Self, String> {
+        let contents = std::fs::read_to_string(path).map_err(|e| format!("{}: {}", path.display(), e))?;
+        let mut tokens = Vec::new();
+        for (index, line) in contents.lines().enumerate() {
+            let line = strip_comment(line);
+            if line.trim().is_empty() {
+                continue;
+            }
+            let token = parse_line(line).map_err(|e| format!("{}, line {}: {}", path.display(), index + 1, e))?;
+            tokens.push(token);
+        }
+        Ok(Tokens(tokens))
+    }
+
+    pub fn count(&self) -> usize {
+        self.0.len()
+    }
+
+    pub fn find(&self, presented: &str) -> Option<&Token> {
+        let digest: [u8; 32] = Sha256::digest(presented.as_bytes()).into();
+        self.0.iter().find(|token| token.digest == digest)
+    }
+}
+
+/// The line up to the `#` that starts a comment in it, if any.
+fn strip_comment(line: &str) -> &str {
+    let mut after_space = true;
+    for (at, c) in line.char_indices() {
+        if c == '#' && after_space {
+            return &line[..at];
+        }
+        after_space = c.is_whitespace();
+    }
+    line
+}
+
+fn parse_line(line: &str) -> Result<Token, String> {
+    let mut fields = line.split_whitespace();
+    let label = fields.next().unwrap_or_default().to_string();
+    let secret = fields.next().ok_or("expected a label, a token and its settings")?;
+    let mut token = Token {
+        label,
+        digest: Sha256::digest(secret.as_bytes()).into(),
+        operations: Vec::new(),
+        dir: None,
+        max_size: None,
+        expires: None,
+    };
+
+    for field in fields {
+        let (key, value) = field.split_once('=').ok_or_else(|| format!("'{}' is not a key=value setting", field))?;
+        match key {
+            "ops" => token.operations = value.split(',').map(Operation::parse).collect::<Result<_, _>>()?,
+            "dir" => token.dir = Some(paths::sanitize(value.trim_end_matches('/')).map_err(|e| format!("dir '{}': {}", value, e))?),
+            "max-size" => token.max_size = Some(parse_size(value)?),
+            "expires" => token.expires = Some(parse_date(value)?),
+            other => return Err(format!("unknown setting '{}' (expected ops, dir, max-size or expires)", other)),
+        }
+    }
+    if token.operations.is_empty() {
+        return Err(format!("token '{}' has no ops=... setting", token.label));
+    }
+    Ok(token)
+}
+
+/// Parses a byte count with an optional binary suffix, such as `500M`.
+pub fn parse_size(value: &str) -> Result<u64, String> {
+    let invalid = || format!("'{}' is not a size (e.g. 4096, 500K, 20M or 1G)", value);
+    let (digits, multiplier) = match value.char_indices().last().ok_or_else(invalid)? {
+        (at, 'K' | 'k') => (&value[..at], 1 << 10),
+        (at, 'M' | 'm') => (&value[..at], 1 << 20),
+        (at, 'G' | 'g') => (&value[..at], 1 << 30),
+        (at, 'T' | 't') => (&value[..at], 1 << 40),
+        _ => (value, 1),
+    };
+    let number: u64 = digits.parse().map_err(|_| invalid())?;
+    number.checked_mul(multiplier).ok_or_else(invalid)
+}
+
+/// Parses a `YYYY-MM-DD` date into days since the Unix epoch.
+fn parse_date(value: &str) -> Result<i64, String> {
+    let invalid = || format!("'{}' is not a date in the form YYYY-MM-DD", value);
+    let mut parts = value.splitn(3, '-').map(|part| part.parse::<i64>().map_err(|_| invalid()));
+    let (year, month, day) = match (parts.next(), parts.next(), parts.next()) {
+        (Some(year), Some(month), Some(day)) => (year?, month?, day?),
+        _ => return Err(invalid()),
+    };
+    let leap = year % 4 == 0 && (year % 100 != 0 || year % 400 == 0);
+    let month_len = match month {
+        2 if leap => 29,
+        2 => 28,
+        4 | 6 | 9 | 11 => 30,
+        1..=12 => 31,
+        _ => return Err(invalid()),
+    };
+    if !(1..=month_len).contains(&day) {
+        return Err(invalid());
+    }
+    Ok(date::days_from_civil(year, month as u32, day as u32))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn comments_start_a_line_or_follow_whitespace() {
+        assert_eq!(strip_comment("# label token settings"), "");
+        assert_eq!(strip_comment("alice  s3cr3t  ops=push  # laptop"), "alice  s3cr3t  ops=push  ");
+        assert_eq!(strip_comment("alice  s3#cr3t#  ops=push\t#laptop"), "alice  s3#cr3t#  ops=push\t");
+        assert_eq!(strip_comment("alice  s3cr3t#  ops=push"), "alice  s3cr3t#  ops=push");
+    }
+
+    #[test]
+    fn tokens_may_contain_hashes() {
+        let token = parse_line(strip_comment("alice  a#b#c  ops=push,list  # the laptop")).unwrap();
+        assert_eq!(token.label, "alice");
+        assert_eq!(token.digest, <[u8; 32]>::from(Sha256::digest(b"a#b#c")));
+        assert!(token.allows(Operation::Push) && token.allows(Operation::List) && !token.allows(Operation::Delete));
+    }
+
+    #[test]
+    fn expiry_dates_are_checked() {
+        assert_eq!(parse_date("1970-01-01"), Ok(0));
+        assert_eq!(parse_date("2024-02-29"), Ok(19_782));
+        for date in ["2023-02-29", "2024-13-01", "2024-04-31", "2024-1", "tomorrow"] {
+            assert!(parse_date(date).is_err(), "{:?} was accepted", date);
+        }
+    }
+}
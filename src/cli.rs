@@ -0,0 +1,56 @@
+use std::collections::{HashMap, HashSet};
+
+/// Command-line arguments for one mode, split into positionals, `--switch`es and
+/// `--option value` pairs (also accepted as `--option=value`).
+pub struct Options {
+    pub positional: Vec<String>,
+    switches: HashSet<String>,
+    values: HashMap<String, Vec<String>>,
+}
+
+impl Options {
+    pub fn parse(args: &[String], switches: &[&str], options: &[&str]) -> Result<Self, String> {
+        let mut parsed = Options {
+            positional: Vec::new(),
+            switches: HashSet::new(),
+            values: HashMap::new(),
+        };
+        let mut args = args.iter();
+        while let Some(arg) = args.next() {
+            if !arg.starts_with("--") {
+                parsed.positional.push(arg.clone());
+                continue;
+            }
+            let (name, inline) = match arg.split_once('=') {
+                Some((name, value)) => (name, Some(value.to_string())),
+                None => (arg.as_str(), None),
+            };
+            if switches.contains(&name) && inline.is_none() {
+                parsed.switches.insert(name.to_string());
+            } else if options.contains(&name) {
+                let value = match inline {
+                    Some(value) => value,
+                    None => args.next().cloned().ok_or_else(|| format!("{} needs a value", name))?,
+                };
+                parsed.values.entry(name.to_string()).or_default().push(value);
+            } else {
+                return Err(format!("unknown option '{}'", arg));
+            }
+        }
+        Ok(parsed)
+    }
+
+    pub fn has(&self, switch: &str) -> bool {
+        self.switches.contains(switch)
+    }
+
+    /// The last value given for an option.
+    pub fn value(&self, option: &str) -> Option<&str> {
+        self.values.get(option).and_then(|values| values.last()).map(String::as_str)
+    }
+
+    /// Every value given for a repeatable option.
+    pub fn values(&self, option: &str) -> &[String] {
+        self.values.get(option).map(Vec::as_slice).unwrap_or_default()
+    }
+}
@@ -0,0 +1,210 @@
+use std::path::PathBuf;
+use std::sync::Arc;
+use tokio::net::TcpStream;
+
+use crate::archive::{self, ArchiveFormat, Extraction};
+use crate::conflict::ConflictPolicy;
+use crate::metadata::Preserve;
+use crate::{discovery, known_hosts, list};
+use crate::mux::{self, Session};
+use crate::pairing::{self, Side};
+use crate::protocol::{self, Message, StatusCode};
+use crate::receive::{self, Destination};
+use crate::resume::BatchJournal;
+use crate::send::{self, SendOptions};
+use crate::tls::{self, Conn, Fingerprint};
+use crate::walk::Entry;
+
+/// How the client secures its connection to the server.
+pub struct ConnectOptions {
+    /// Speak plain TCP, for servers running with `--insecure`.
+    pub insecure: bool,
+    /// The server certificate to expect. Without one, the certificate remembered
+    /// for the server in the known hosts file is expected instead.
+    pub pin: Option<Fingerprint>,
+    /// The code printed by a server started with `--code`.
+    pub code: Option<String>,
+    /// Access token for a server started with `--tokens`.
+    pub token: Option<String>,
+}
+
+async fn connect(address: &str, options: &ConnectOptions) -> tokio::io::Result<Session> {
+    // Servers found by name are remembered by that name, not their current address.
+    let target = discovery::resolve(address).await?;
+    let socket = TcpStream::connect(&target).await?;
+    let mut conn: Box<dyn Conn> = if options.insecure {
+        Box::new(socket)
+    } else {
+        let (conn, fingerprint) = tls::connect(socket, &target).await?;
+        verify_server(address, fingerprint, options)?;
+        conn
+    };
+    let hello = protocol::handshake(&mut conn, options.code.is_some(), false).await?;
+    let keys = match (&options.code, hello.pairing) {
+        (Some(code), true) => Some(pairing::pair(&mut conn, code, Side::Client).await?),
+        (Some(_), false) => {
+            return Err(std::io::Error::new(std::io::ErrorKind::InvalidInput, "the server does not use a pairing code; leave out --code"));
+        }
+        (None, true) => {
+            return Err(std::io::Error::new(std::io::ErrorKind::PermissionDenied, "the server requires a pairing code; pass it with --code"));
+        }
+        (None, false) => None,
+    };
+    let (reader, writer) = tokio::io::split(conn);
+    Ok(mux::start(reader, writer, keys, hello.capabilities))
+}
+
+/// Connects to the server and makes `request`, presenting the access token if
+/// there is one, then waits for the server to accept it.
+async fn open(address: &str, options: &ConnectOptions, request: Message) -> tokio::io::Result<Session> {
+    let session = connect(address, options).await?;
+    if let Some(token) = &options.token {
+        session.send_control(Message::Auth { token: token.clone() }).await?;
+    }
+    session.send_control(request).await?;
+    match session.recv_control().await? {
+        Message::Status { code: StatusCode::Ok, .. } => Ok(session),
+        Message::Status { message, .. } => Err(std::io::Error::other(format!("refused by server: {}", message))),
+        other => Err(protocol::unexpected(&other)),
+    }
+}
+
+/// Decides whether to trust the certificate the server presented: the pinned one
+/// if there is one, otherwise the one it had last time, remembering it on first use.
+fn verify_server(address: &str, fingerprint: Fingerprint, options: &ConnectOptions) -> tokio::io::Result<()> {
+    if let Some(pin) = options.pin {
+        if pin != fingerprint {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::PermissionDenied,
+                format!("server certificate {} does not match the pinned {}", fingerprint, pin),
+            ));
+        }
+        if let Err(e) = known_hosts::remember(address, fingerprint) {
+            eprintln!("Warning: could not update {}: {}", known_hosts::path().display(), e);
+        }
+        return Ok(());
+    }
+    // Pairing proves who the server is, whatever certificate it has.
+    if options.code.is_some() {
+        return Ok(());
+    }
+
+    match known_hosts::check(address, fingerprint) {
+        known_hosts::Verdict::Known => Ok(()),
+        known_hosts::Verdict::New => {
+            eprintln!("Warning: first connection to {}. Its certificate fingerprint is", address);
+            eprintln!("  {}", fingerprint);
+            eprintln!("Check it against the one the server printed.");
+            match known_hosts::remember(address, fingerprint) {
+                Ok(()) => eprintln!("It has been added to {} and will be expected from now on.", known_hosts::path().display()),
+                Err(e) => eprintln!("Warning: could not update {}: {}", known_hosts::path().display(), e),
+            }
+            Ok(())
+        }
+        known_hosts::Verdict::Changed { previous, line } => {
+            known_hosts::warn_changed(address, fingerprint, previous, line);
+            Err(std::io::Error::new(
+                std::io::ErrorKind::PermissionDenied,
+                format!("the certificate of {} has changed; refusing to connect", address),
+            ))
+        }
+    }
+}
+
+/// Sends a batch of files and directories to the server at `address`, all over
+/// one connection. `resumed` is the journal of the interrupted batch they are
+/// what is left of, if any.
+pub async fn send_files(
+    address: &str,
+    connect_options: &ConnectOptions,
+    entries: Vec<Entry>,
+    options: SendOptions,
+    resumed: Option<BatchJournal>,
+) -> tokio::io::Result<()> {
+    // Journal the batch so `client --resume` can finish it if we are interrupted.
+    let journal = match resumed {
+        Some(journal) => Some(journal),
+        None => BatchJournal::create(address, entries.clone())
+            .await
+            .inspect_err(|e| eprintln!("Warning: could not save batch journal: {}", e))
+            .ok(),
+    };
+    let journal = journal.map(Arc::new);
+
+    let session = open(address, connect_options, Message::Push).await?;
+    send::announce_batch(&session, &send::manifest(&entries)).await?;
+    send::send_batch(&session, entries, &options, address, journal).await
+}
+
+/// Sends `entries` to the server at `address` as one archive, built as it is
+/// sent. There is nothing to journal: an interrupted archive is sent again from the start.
+pub async fn send_archive(address: &str, connect_options: &ConnectOptions, entries: Vec<Entry>, format: ArchiveFormat, options: SendOptions) -> tokio::io::Result<()> {
+    let Some(top) = entries.first().and_then(|entry| entry.name.split('/').next()) else {
+        return Err(std::io::Error::new(std::io::ErrorKind::InvalidInput, "nothing to archive"));
+    };
+    let name = format!("{}.{}", top, format.extension());
+    // The archive is only built as it is sent, so it is announced at the most it can come to.
+    let size = archive::max_len(format, &entries);
+
+    let session = open(address, connect_options, Message::Push).await?;
+    send::announce_batch(&session, &[(name.clone(), Some(size))]).await?;
+    send::send_archive(&session, &name, format, entries, &options, address).await
+}
+
+/// Downloads `remote`, a file or directory in the server's export, into
+/// `local_dir`, as a single archive if `archive` is given.
+pub async fn fetch_files(
+    address: &str,
+    connect_options: &ConnectOptions,
+    remote: &str,
+    local_dir: PathBuf,
+    on_conflict: ConflictPolicy,
+    archive: Option<ArchiveFormat>,
+    preserve: Preserve,
+) -> tokio::io::Result<()> {
+    let session = open(address, connect_options, Message::Pull { path: remote.to_string(), archive }).await?;
+
+    let destination = Arc::new(Destination { root: local_dir, on_conflict, max_size: None, approved: None, extract: false, limits: Extraction::default(), preserve, dedup: None });
+    let summary = receive::receive_batch(&session, destination, address).await
+        .map_err(|e| std::io::Error::new(e.kind(), format!("'{}': {}", remote, e)))?;
+    session.close().await;
+    println!(
+        "Download from {} complete: {} received ({} bytes), {} skipped, {} failed",
+        address, summary.received, summary.bytes, summary.skipped, summary.failed
+    );
+
+    if summary.failed > 0 {
+        let total = summary.received + summary.skipped + summary.failed;
+        return Err(std::io::Error::other(format!("{} of {} files failed", summary.failed, total)));
+    }
+    Ok(())
+}
+
+/// Prints the contents of `path` in the server's output directory, or its root if empty.
+pub async fn list_files(address: &str, connect_options: &ConnectOptions, path: &str, hashes: bool, json: bool) -> tokio::io::Result<()> {
+    let session = open(address, connect_options, Message::List { path: path.to_string(), hashes }).await?;
+
+    let mut entries = Vec::new();
+    loop {
+        match session.recv_control().await? {
+            Message::ListEntry(entry) => entries.push(entry),
+            Message::Status { code: StatusCode::Ok, .. } => break,
+            Message::Status { message, .. } => return Err(std::io::Error::other(message)),
+            other => return Err(protocol::unexpected(&other)),
+        }
+    }
+
+    if json {
+        list::print_json(&entries);
+    } else {
+        list::print_table(&entries);
+    }
+    Ok(())
+}
+
+/// Deletes `path`, a file or directory in the server's output directory.
+pub async fn delete_files(address: &str, connect_options: &ConnectOptions, path: &str) -> tokio::io::Result<()> {
+    open(address, connect_options, Message::Delete { path: path.to_string() }).await?;
+    println!("Deleted '{}' on {}", path, address);
+    Ok(())
+}
@@ -0,0 +1,158 @@
+use std::io::{Error, ErrorKind};
+use indicatif::HumanBytes;
+
+use crate::protocol::{Message, CAP_LZ4, CAP_ZSTD};
+
+/// zstd level used unless `--level` says otherwise: fast, and most of the gain.
+const DEFAULT_LEVEL: i32 = 3;
+
+/// Auto mode only compresses files whose first chunk shrinks to at most this
+/// share of its size; anything else is most likely compressed already.
+const AUTO_THRESHOLD: f64 = 0.9;
+
+/// How much of the first chunk auto mode tries to compress to decide.
+const SAMPLE_SIZE: usize = 128 * 1024;
+
+/// How the chunks of one file travel, as chosen by the sender.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Codec {
+    None,
+    Zstd,
+    Lz4,
+}
+
+impl Codec {
+    pub fn to_u8(self) -> u8 {
+        match self {
+            Codec::None => 0,
+            Codec::Zstd => 1,
+            Codec::Lz4 => 2,
+        }
+    }
+
+    pub fn from_u8(value: u8) -> Option<Self> {
+        match value {
+            0 => Some(Codec::None),
+            1 => Some(Codec::Zstd),
+            2 => Some(Codec::Lz4),
+            _ => None,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Codec::None => "none",
+            Codec::Zstd => "zstd",
+            Codec::Lz4 => "lz4",
+        }
+    }
+
+    /// The hello capability a peer needs to read this codec.
+    fn capability(self) -> u32 {
+        match self {
+            Codec::None => 0,
+            Codec::Zstd => CAP_ZSTD,
+            Codec::Lz4 => CAP_LZ4,
+        }
+    }
+}
+
+/// What `--compress` asked for.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Mode {
+    Off,
+    Always(Codec),
+    /// zstd, but only for files that turn out to be compressible.
+    Auto,
+}
+
+/// The sender's compression settings, applied file by file.
+#[derive(Clone, Copy)]
+pub struct Compression {
+    pub mode: Mode,
+    /// zstd level; lz4 has none.
+    pub level: i32,
+}
+
+impl Compression {
+    /// Parses `--compress` and `--level`.
+    pub fn parse(mode: Option<&str>, level: Option<&str>) -> Result<Self, String> {
+        let mode = match mode {
+            None | Some("none") => Mode::Off,
+            Some("zstd") => Mode::Always(Codec::Zstd),
+            Some("lz4") => Mode::Always(Codec::Lz4),
+            Some("auto") => Mode::Auto,
+            Some(other) => return Err(format!("unknown compression '{}' (expected zstd, lz4, auto or none)", other)),
+        };
+        let level = match level {
+            None => DEFAULT_LEVEL,
+            Some(_) if !matches!(mode, Mode::Always(Codec::Zstd) | Mode::Auto) => {
+                return Err("--level only applies to --compress zstd or auto".to_string());
+            }
+            Some(level) => level
+                .parse()
+                .ok()
+                .filter(|level| zstd::compression_level_range().contains(level))
+                .ok_or_else(|| format!("--level must be a zstd level from 1 to {}", zstd::compression_level_range().end()))?,
+        };
+        Ok(Compression { mode, level })
+    }
+
+    /// Picks the codec for one file from those the peer supports, looking at
+    /// its first chunk in auto mode.
+    pub fn choose(&self, capabilities: u32, first_chunk: &[u8]) -> Codec {
+        let codec = match self.mode {
+            Mode::Off => return Codec::None,
+            Mode::Always(codec) => codec,
+            Mode::Auto => {
+                let sample = &first_chunk[..first_chunk.len().min(SAMPLE_SIZE)];
+                let compressed = zstd::bulk::compress(sample, 1).map_or(usize::MAX, |compressed| compressed.len());
+                if sample.is_empty() || compressed as f64 > sample.len() as f64 * AUTO_THRESHOLD {
+                    return Codec::None;
+                }
+                Codec::Zstd
+            }
+        };
+        if capabilities & codec.capability() == 0 {
+            return Codec::None;
+        }
+        codec
+    }
+}
+
+/// Compresses one chunk for the wire, or leaves it as plain data if that
+/// would not make it any smaller.
+pub fn pack(codec: Codec, level: i32, chunk: Vec<u8>) -> std::io::Result<Message> {
+    let compressed = match codec {
+        Codec::None => return Ok(Message::Data(chunk)),
+        Codec::Zstd => zstd::bulk::compress(&chunk, level)?,
+        Codec::Lz4 => lz4_flex::block::compress(&chunk),
+    };
+    if compressed.len() >= chunk.len() {
+        return Ok(Message::Data(chunk));
+    }
+    Ok(Message::CompressedData { raw_len: chunk.len() as u32, data: compressed })
+}
+
+/// Restores a chunk that was `raw_len` bytes before compression, refusing to
+/// produce any more than that.
+pub fn unpack(codec: Codec, data: &[u8], raw_len: usize) -> std::io::Result<Vec<u8>> {
+    let chunk = match codec {
+        Codec::None => return Err(Error::new(ErrorKind::InvalidData, "compressed chunk in an uncompressed transfer")),
+        Codec::Zstd => zstd::bulk::decompress(data, raw_len)?,
+        Codec::Lz4 => lz4_flex::block::decompress(data, raw_len).map_err(|e| Error::new(ErrorKind::InvalidData, e))?,
+    };
+    if chunk.len() != raw_len {
+        return Err(Error::new(
+            ErrorKind::InvalidData,
+            format!("chunk decompressed to {} bytes instead of {}", chunk.len(), raw_len),
+        ));
+    }
+    Ok(chunk)
+}
+
+/// Describes what compression did to a file's body, e.g. `zstd, 12.0 MiB as 1.5 MiB (13%)`.
+pub fn describe(codec: Codec, raw: u64, wire: u64) -> String {
+    let percent = if raw == 0 { 100.0 } else { wire as f64 * 100.0 / raw as f64 };
+    format!("{}, {} as {} ({:.0}%)", codec.name(), HumanBytes(raw), HumanBytes(wire), percent)
+}
@@ -0,0 +1,118 @@
+use std::io::{Error, ErrorKind};
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+
+/// What the receiver does when a file it is sent already exists.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ConflictPolicy {
+    Overwrite,
+    Skip,
+    Rename,
+    Fail,
+    /// Overwrite only if the sender's copy was modified more recently, otherwise skip.
+    Newer,
+}
+
+impl ConflictPolicy {
+    pub fn parse(value: &str) -> Result<Self, String> {
+        match value {
+            "overwrite" => Ok(ConflictPolicy::Overwrite),
+            "skip" => Ok(ConflictPolicy::Skip),
+            "rename" => Ok(ConflictPolicy::Rename),
+            "fail" => Ok(ConflictPolicy::Fail),
+            "newer" => Ok(ConflictPolicy::Newer),
+            other => Err(format!("unknown conflict policy '{}' (expected overwrite, skip, rename, fail or newer)", other)),
+        }
+    }
+
+    pub fn to_u8(self) -> u8 {
+        match self {
+            ConflictPolicy::Overwrite => 1,
+            ConflictPolicy::Skip => 2,
+            ConflictPolicy::Rename => 3,
+            ConflictPolicy::Fail => 4,
+            ConflictPolicy::Newer => 5,
+        }
+    }
+
+    pub fn from_u8(value: u8) -> Option<Self> {
+        match value {
+            1 => Some(ConflictPolicy::Overwrite),
+            2 => Some(ConflictPolicy::Skip),
+            3 => Some(ConflictPolicy::Rename),
+            4 => Some(ConflictPolicy::Fail),
+            5 => Some(ConflictPolicy::Newer),
+            _ => None,
+        }
+    }
+}
+
+/// The first free name of the form `name (1).ext`, `name (2).ext`, ... next to `path`.
+pub fn renamed_path(path: &Path) -> PathBuf {
+    let stem = path.file_stem().unwrap_or_default().to_string_lossy().into_owned();
+    let extension = path.extension().map(|ext| format!(".{}", ext.to_string_lossy())).unwrap_or_default();
+    (1..)
+        .map(|n| path.with_file_name(format!("{} ({}){}", stem, n, extension)))
+        .find(|candidate| candidate.symlink_metadata().is_err())
+        .unwrap()
+}
+
+/// Where an incoming entry goes when something is already at `path`, or `None`
+/// to skip it. `mtime` is the incoming entry's, for `newer`.
+///
+/// What is there is looked at, never what it links to: a link is replaced
+/// like a file. Errors name only the file, as they are passed on to the sender.
+pub fn resolve(path: PathBuf, policy: ConflictPolicy, mtime: Option<SystemTime>) -> std::io::Result<Option<PathBuf>> {
+    let Ok(existing) = std::fs::symlink_metadata(&path) else {
+        return Ok(Some(path));
+    };
+    let name = path.file_name().unwrap_or_default();
+    if existing.is_dir() {
+        return Err(Error::new(ErrorKind::AlreadyExists, format!("{:?} is a directory", name)));
+    }
+    match policy {
+        ConflictPolicy::Overwrite => Ok(Some(path)),
+        ConflictPolicy::Rename => Ok(Some(renamed_path(&path))),
+        ConflictPolicy::Fail => Err(Error::new(ErrorKind::AlreadyExists, format!("{:?} already exists", name))),
+        ConflictPolicy::Skip => Ok(None),
+        ConflictPolicy::Newer => match (mtime, existing.modified()) {
+            (Some(incoming), Ok(current)) if incoming <= current => Ok(None),
+            _ => Ok(Some(path)),
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[cfg(unix)]
+    #[test]
+    fn links_are_treated_as_files_of_their_own() {
+        let dir = std::env::temp_dir().join(format!("streamline-conflict-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(dir.join("elsewhere")).unwrap();
+        std::os::unix::fs::symlink(dir.join("elsewhere"), dir.join("link")).unwrap();
+
+        // A link to a directory is not a directory in the way.
+        let link = dir.join("link");
+        assert_eq!(resolve(link.clone(), ConflictPolicy::Overwrite, None).unwrap(), Some(link.clone()));
+        assert_eq!(resolve(link.clone(), ConflictPolicy::Skip, None).unwrap(), None);
+        assert_eq!(resolve(link.clone(), ConflictPolicy::Rename, None).unwrap(), Some(dir.join("link (1)")));
+        let error = resolve(link, ConflictPolicy::Fail, None).unwrap_err();
+        assert_eq!(error.to_string(), "\"link\" already exists");
+
+        let error = resolve(dir.join("elsewhere"), ConflictPolicy::Overwrite, None).unwrap_err();
+        assert_eq!(error.to_string(), "\"elsewhere\" is a directory");
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn every_policy_survives_the_wire() {
+        for policy in [ConflictPolicy::Overwrite, ConflictPolicy::Skip, ConflictPolicy::Rename, ConflictPolicy::Fail, ConflictPolicy::Newer] {
+            assert_eq!(ConflictPolicy::from_u8(policy.to_u8()), Some(policy));
+        }
+        assert_eq!(ConflictPolicy::from_u8(0), None);
+        assert_eq!(ConflictPolicy::from_u8(6), None);
+    }
+}
@@ -0,0 +1,145 @@
+use std::collections::HashMap;
+use std::fs::File;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use sha2::{Digest, Sha256};
+
+use crate::resume;
+
+/// How a server started with `--dedup` puts a file it already has in place.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum DedupMode {
+    /// A copy of its own, which can change without the other changing too.
+    Copy,
+    /// Another name for the same file, which takes no more space but shares
+    /// its times and permissions with the first.
+    Link,
+}
+
+impl DedupMode {
+    pub fn parse(value: &str) -> Result<Self, String> {
+        match value {
+            "copy" => Ok(DedupMode::Copy),
+            "link" => Ok(DedupMode::Link),
+            other => Err(format!("unknown --dedup mode '{}' (expected copy or link)", other)),
+        }
+    }
+}
+
+/// The files under the output directory by the SHA-256 of their content, so
+/// that a file sent again can be taken from the copy already there.
+pub struct ContentIndex {
+    pub mode: DedupMode,
+    files: Mutex<HashMap<[u8; 32], Vec<PathBuf>>>,
+}
+
+impl ContentIndex {
+    /// Starts hashing every file under `root` on a blocking thread. Each file
+    /// can be found as soon as it has been hashed.
+    pub fn build(root: PathBuf, mode: DedupMode) -> Arc<Self> {
+        let index = Arc::new(ContentIndex { mode, files: Mutex::default() });
+        let scanning = index.clone();
+        tokio::task::spawn_blocking(move || {
+            let count = scanning.scan(&root);
+            say!("Indexed {} files in {:?} by content", count, root);
+        });
+        index
+    }
+
+    /// Hashes the files under `dir`, leaving out links and transfers in
+    /// progress, and returns how many there were.
+    fn scan(&self, dir: &Path) -> usize {
+        // The current directory is "" as a root, which paths are joined onto as they are received.
+        let Ok(entries) = std::fs::read_dir(if dir.as_os_str().is_empty() { Path::new(".") } else { dir }) else {
+            return 0;
+        };
+        let mut count = 0;
+        for entry in entries.flatten() {
+            let Ok(file_type) = entry.file_type() else {
+                continue;
+            };
+            let path = dir.join(entry.file_name());
+            if file_type.is_dir() {
+                count += self.scan(&path);
+            } else if file_type.is_file() && !resume::is_partial_name(&entry.file_name().to_string_lossy()) {
+                match hash_file(&path) {
+                    Ok(sha256) => {
+                        self.insert(sha256, path);
+                        count += 1;
+                    }
+                    Err(e) => say_err!("Warning: could not index {:?}: {}", path, e),
+                }
+            }
+        }
+        count
+    }
+
+    /// Records that the file at `path` has the content `sha256`.
+    pub fn insert(&self, sha256: [u8; 32], path: PathBuf) {
+        let mut files = self.files.lock().unwrap();
+        let paths = files.entry(sha256).or_default();
+        if !paths.contains(&path) {
+            paths.push(path);
+        }
+    }
+
+    /// Puts a file with the content `sha256` and `size` bytes long, from among
+    /// those under `root`, at `partial`, and returns the file it came from.
+    ///
+    /// A file may have changed since it was indexed, so what ends up at
+    /// `partial` is hashed again. Files that no longer match are forgotten.
+    pub async fn place(&self, sha256: [u8; 32], size: u64, root: &Path, partial: &Path) -> Option<PathBuf> {
+        let candidates: Vec<PathBuf> = self.files.lock().unwrap()
+            .get(&sha256)
+            .map(|paths| paths.iter().filter(|path| path.starts_with(root)).cloned().collect())
+            .unwrap_or_default();
+        for candidate in candidates {
+            let (source, target, mode) = (candidate.clone(), partial.to_path_buf(), self.mode);
+            let placed = tokio::task::spawn_blocking(move || place_file(&source, size, &target, mode))
+                .await
+                .unwrap_or_else(|e| Err(std::io::Error::other(e)));
+            match placed {
+                Ok(placed) if placed == sha256 => return Some(candidate),
+                Ok(_) => self.forget(&sha256, &candidate),
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => self.forget(&sha256, &candidate),
+                Err(e) => say_err!("Warning: could not reuse {:?}: {}", candidate, e),
+            }
+            let _ = tokio::fs::remove_file(partial).await;
+        }
+        None
+    }
+
+    fn forget(&self, sha256: &[u8; 32], path: &Path) {
+        let mut files = self.files.lock().unwrap();
+        if let Some(paths) = files.get_mut(sha256) {
+            paths.retain(|other| other != path);
+            if paths.is_empty() {
+                files.remove(sha256);
+            }
+        }
+    }
+}
+
+/// Copies or links `source` to `partial` if it is still a regular file of
+/// `size` bytes, and returns the hash of what is there now.
+fn place_file(source: &Path, size: u64, partial: &Path, mode: DedupMode) -> std::io::Result<[u8; 32]> {
+    let metadata = std::fs::symlink_metadata(source)?;
+    if !metadata.is_file() || metadata.len() != size {
+        return Err(std::io::Error::new(std::io::ErrorKind::NotFound, format!("{:?} has changed", source)));
+    }
+    match std::fs::remove_file(partial) {
+        Err(e) if e.kind() != std::io::ErrorKind::NotFound => return Err(e),
+        _ => {}
+    }
+    match mode {
+        DedupMode::Copy => std::fs::copy(source, partial).map(|_| ())?,
+        DedupMode::Link => std::fs::hard_link(source, partial)?,
+    }
+    hash_file(partial)
+}
+
+fn hash_file(path: &Path) -> std::io::Result<[u8; 32]> {
+    let mut hasher = Sha256::new();
+    std::io::copy(&mut File::open(path)?, &mut hasher)?;
+    Ok(hasher.finalize().into())
+}
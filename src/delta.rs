@@ -0,0 +1,419 @@
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{Read, SeekFrom};
+use std::path::Path;
+use sha2::{Digest, Sha256};
+use tokio::io::{AsyncReadExt, AsyncSeekExt};
+use tokio::sync::mpsc;
+
+use crate::CHUNK_SIZE;
+
+/// Copies smaller than this are replaced by sending the file in full, which
+/// costs little more than describing them would.
+const MIN_BASIS_SIZE: u64 = 64 * 1024;
+pub const MIN_BLOCK_SIZE: u32 = 4 * 1024;
+pub const MAX_BLOCK_SIZE: u32 = CHUNK_SIZE as u32;
+/// Most blocks a copy is described in, which bounds the memory the sums take
+/// on both ends, at 80 MB. Copies that would need more are replaced in full.
+pub const MAX_BLOCKS: usize = 4 * 1024 * 1024;
+/// Block sums sent in one frame.
+pub const SUMS_PER_FRAME: usize = CHUNK_SIZE / BlockSum::ENCODED_LEN;
+
+/// The sums of one block of the receiver's copy of a file. The weak one is
+/// cheap to update as a window slides over the sender's copy, and only where
+/// it matches is the strong one worked out and compared.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub struct BlockSum {
+    pub weak: u32,
+    /// The start of the block's SHA-256.
+    pub strong: [u8; 16],
+}
+
+impl BlockSum {
+    pub const ENCODED_LEN: usize = 20;
+
+    fn of(block: &[u8]) -> Self {
+        BlockSum { weak: Rolling::new(block).digest(), strong: strong_sum(block) }
+    }
+}
+
+fn strong_sum(block: &[u8]) -> [u8; 16] {
+    Sha256::digest(block)[..16].try_into().unwrap()
+}
+
+/// The block size a copy of `len` bytes is described in, about its square
+/// root, or `None` if it is too small or too large to be worth describing.
+fn block_size(len: u64) -> Option<u32> {
+    if len < MIN_BASIS_SIZE {
+        return None;
+    }
+    let size = len.isqrt().next_multiple_of(1024).clamp(MIN_BLOCK_SIZE as u64, MAX_BLOCK_SIZE as u64);
+    (len.div_ceil(size) <= MAX_BLOCKS as u64).then_some(size as u32)
+}
+
+/// The weak checksum rsync uses: two running sums of a window's bytes, which
+/// can be moved along by a byte without going over the window again.
+struct Rolling {
+    a: u32,
+    b: u32,
+    len: u32,
+}
+
+impl Rolling {
+    fn new(window: &[u8]) -> Self {
+        let len = window.len() as u32;
+        let (mut a, mut b) = (0u32, 0u32);
+        for (i, &byte) in window.iter().enumerate() {
+            a = a.wrapping_add(byte as u32);
+            b = b.wrapping_add((len - i as u32).wrapping_mul(byte as u32));
+        }
+        Rolling { a, b, len }
+    }
+
+    /// Moves the window on by one byte, dropping `out` and taking in `next`.
+    fn roll(&mut self, out: u8, next: u8) {
+        self.a = self.a.wrapping_sub(out as u32).wrapping_add(next as u32);
+        self.b = self.b.wrapping_sub(self.len.wrapping_mul(out as u32)).wrapping_add(self.a);
+    }
+
+    fn digest(&self) -> u32 {
+        (self.a & 0xffff) | (self.b << 16)
+    }
+}
+
+/// The receiver's copy of a file that is about to be replaced, which the
+/// sender can refer to blocks of instead of sending them again.
+pub struct Basis {
+    file: tokio::fs::File,
+    len: u64,
+    block_size: u32,
+    blocks: u64,
+}
+
+impl Basis {
+    /// Opens the file at `path` and sums each of its blocks, or returns `None`
+    /// if there is no regular file there worth describing.
+    pub async fn open(path: &Path) -> std::io::Result<Option<(Self, u32, Vec<BlockSum>)>> {
+        let Ok(metadata) = tokio::fs::symlink_metadata(path).await else {
+            return Ok(None);
+        };
+        let Some(block_size) = block_size(metadata.len()).filter(|_| metadata.is_file()) else {
+            return Ok(None);
+        };
+        let file = File::open(path)?;
+        let reader = file.try_clone()?;
+        let sums = tokio::task::spawn_blocking(move || sum_blocks(reader, block_size))
+            .await
+            .map_err(std::io::Error::other)??;
+        let basis = Basis { file: tokio::fs::File::from_std(file), len: metadata.len(), block_size, blocks: sums.len() as u64 };
+        Ok(Some((basis, block_size, sums)))
+    }
+
+    /// Reads `count` blocks from `index` on, which the sender found in its own copy.
+    pub async fn read(&mut self, index: u64, count: u32) -> std::io::Result<Vec<u8>> {
+        let block_size = self.block_size as u64;
+        let len = count as u64 * block_size;
+        if index.checked_add(count as u64).is_none_or(|end| end > self.blocks) || len > block_size.max(CHUNK_SIZE as u64) {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                format!("sender referred to blocks {} to {} of a copy with {}", index, index.saturating_add(count as u64), self.blocks),
+            ));
+        }
+        let start = index * block_size;
+        let mut buffer = vec![0; len.min(self.len - start) as usize];
+        self.file.seek(SeekFrom::Start(start)).await?;
+        self.file.read_exact(&mut buffer).await?;
+        Ok(buffer)
+    }
+}
+
+fn sum_blocks(mut file: File, block_size: u32) -> std::io::Result<Vec<BlockSum>> {
+    let mut sums = Vec::new();
+    let mut block = Vec::with_capacity(block_size as usize);
+    loop {
+        block.clear();
+        (&mut file).take(block_size as u64).read_to_end(&mut block)?;
+        if block.is_empty() {
+            return Ok(sums);
+        }
+        sums.push(BlockSum::of(&block));
+        if block.len() < block_size as usize {
+            return Ok(sums);
+        }
+    }
+}
+
+/// A piece of a file as the sender found it against the receiver's copy.
+pub enum Op {
+    /// Bytes the receiver doesn't have.
+    Literal(Vec<u8>),
+    /// Bytes the receiver has already, as `count` blocks of its copy from
+    /// `index` on. They come along only for the sender to hash.
+    Blocks { index: u64, count: u32, data: Vec<u8> },
+}
+
+/// Reads `file` and finds in it the blocks of the receiver's copy summed up in
+/// `sums`, wherever they moved to, on a blocking thread. What it finds comes
+/// through the returned channel, in order, and ends when the channel closes.
+pub fn diff(file: File, block_size: u32, sums: Vec<BlockSum>) -> mpsc::Receiver<std::io::Result<Op>> {
+    let (ops, receiver) = mpsc::channel(2);
+    tokio::task::spawn_blocking(move || {
+        let mut matcher = Matcher::new(block_size as usize, &sums, ops.clone());
+        if let Err(e) = matcher.run(file) {
+            let _ = ops.blocking_send(Err(e));
+        }
+    });
+    receiver
+}
+
+struct Matcher<'a> {
+    block_size: usize,
+    sums: &'a [BlockSum],
+    /// The first block with each distinct pair of sums, by its weak sum.
+    by_weak: HashMap<u32, Vec<u32>>,
+    ops: mpsc::Sender<std::io::Result<Op>>,
+    /// Blocks found but not yet sent, which grows for as long as each block
+    /// found follows on from the one before it.
+    run: Option<(u64, u32, Vec<u8>)>,
+}
+
+impl<'a> Matcher<'a> {
+    fn new(block_size: usize, sums: &'a [BlockSum], ops: mpsc::Sender<std::io::Result<Op>>) -> Self {
+        let mut by_weak: HashMap<u32, Vec<u32>> = HashMap::new();
+        for (index, sum) in sums.iter().enumerate() {
+            let same_weak = by_weak.entry(sum.weak).or_default();
+            if !same_weak.iter().any(|&other| sums[other as usize] == *sum) {
+                same_weak.push(index as u32);
+            }
+        }
+        Matcher { block_size, sums, by_weak, ops, run: None }
+    }
+
+    fn run(&mut self, mut file: File) -> std::io::Result<()> {
+        let block_size = self.block_size;
+        let mut buffer = Vec::with_capacity(CHUNK_SIZE + 2 * block_size);
+        // Bytes before `literal` have been dealt with, and those from there up
+        // to the window at `start` matched nothing.
+        let mut literal = 0;
+        let mut start = 0;
+        let mut rolling: Option<Rolling> = None;
+        let mut eof = false;
+        loop {
+            // Keep the window and the byte after it in the buffer.
+            if !eof && buffer.len() - start <= block_size {
+                buffer.drain(..literal);
+                start -= literal;
+                literal = 0;
+                eof = (&mut file).take(CHUNK_SIZE as u64).read_to_end(&mut buffer)? == 0;
+                continue;
+            }
+            let end = (start + block_size).min(buffer.len());
+            let window = &buffer[start..end];
+            if window.len() < block_size {
+                // The end of the file can only be the copy's last, shorter
+                // block, and is only looked for where it is.
+                let found = (!window.is_empty()).then(|| self.find(Rolling::new(window).digest(), window)).flatten();
+                match found {
+                    Some(index) => {
+                        self.send_literal(&buffer[literal..start])?;
+                        self.add_block(index, &buffer[start..end])?;
+                    }
+                    None => self.send_literal(&buffer[literal..])?,
+                }
+                return self.send_run();
+            }
+
+            let weak = rolling.get_or_insert_with(|| Rolling::new(window)).digest();
+            if let Some(index) = self.find(weak, window) {
+                self.send_literal(&buffer[literal..start])?;
+                self.add_block(index, &buffer[start..end])?;
+                start = end;
+                literal = start;
+                rolling = None;
+                continue;
+            }
+            match (rolling.as_mut(), buffer.get(end)) {
+                (Some(rolling), Some(&next)) => rolling.roll(buffer[start], next),
+                _ => rolling = None,
+            }
+            start += 1;
+            if start - literal >= CHUNK_SIZE {
+                self.send_literal(&buffer[literal..start])?;
+                literal = start;
+            }
+        }
+    }
+
+    /// The block of the receiver's copy that `window` is, if any, preferring
+    /// the one after the last block found so that runs stay unbroken.
+    fn find(&self, weak: u32, window: &[u8]) -> Option<u64> {
+        let next = self.run.as_ref().map(|(index, count, _)| index + *count as u64);
+        let next_matches = next.is_some_and(|next| self.sums.get(next as usize).is_some_and(|sum| sum.weak == weak));
+        if !next_matches && !self.by_weak.contains_key(&weak) {
+            return None;
+        }
+        let strong = strong_sum(window);
+        if let Some(next) = next.filter(|&next| next_matches && self.sums[next as usize].strong == strong) {
+            return Some(next);
+        }
+        let candidates = self.by_weak.get(&weak)?;
+        candidates.iter().find(|&&index| self.sums[index as usize].strong == strong).map(|&index| index as u64)
+    }
+
+    fn add_block(&mut self, index: u64, block: &[u8]) -> std::io::Result<()> {
+        let limit = self.block_size.max(CHUNK_SIZE);
+        match &mut self.run {
+            Some((first, count, data)) if *first + *count as u64 == index && data.len() + block.len() <= limit => {
+                *count += 1;
+                data.extend_from_slice(block);
+                Ok(())
+            }
+            _ => {
+                self.send_run()?;
+                self.run = Some((index, 1, block.to_vec()));
+                Ok(())
+            }
+        }
+    }
+
+    fn send_run(&mut self) -> std::io::Result<()> {
+        match self.run.take() {
+            Some((index, count, data)) => self.send(Op::Blocks { index, count, data }),
+            None => Ok(()),
+        }
+    }
+
+    fn send_literal(&mut self, bytes: &[u8]) -> std::io::Result<()> {
+        if bytes.is_empty() {
+            return Ok(());
+        }
+        self.send_run()?;
+        // The rest of a file after its last block can be longer than a chunk.
+        bytes.chunks(CHUNK_SIZE).try_for_each(|chunk| self.send(Op::Literal(chunk.to_vec())))
+    }
+
+    fn send(&self, op: Op) -> std::io::Result<()> {
+        // The receiving end only goes away if the transfer is over already.
+        self.ops.blocking_send(Ok(op)).map_err(|_| std::io::Error::other("transfer ended"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+    use super::*;
+
+    /// Bytes that don't repeat, so that blocks only match where they were copied.
+    fn noise(len: usize, seed: u64) -> Vec<u8> {
+        let mut state = seed | 1;
+        (0..len)
+            .map(|_| {
+                state ^= state << 13;
+                state ^= state >> 7;
+                state ^= state << 17;
+                state as u8
+            })
+            .collect()
+    }
+
+    fn write_scratch(name: &str, content: &[u8]) -> PathBuf {
+        let path = std::env::temp_dir().join(format!("streamline-delta-{}-{}", name, std::process::id()));
+        std::fs::write(&path, content).unwrap();
+        path
+    }
+
+    /// Diffs `new` against `old` as the sender would, rebuilds it as the
+    /// receiver would, and returns the rebuilt file and how much of it was reused.
+    async fn round_trip(name: &str, old: &[u8], new: &[u8]) -> (Vec<u8>, usize) {
+        let old_path = write_scratch(&format!("{}-old", name), old);
+        let new_path = write_scratch(&format!("{}-new", name), new);
+        let (mut basis, block_size, sums) = Basis::open(&old_path).await.unwrap().expect("copy is large enough to describe");
+        let mut ops = diff(File::open(&new_path).unwrap(), block_size, sums);
+        let (mut rebuilt, mut reused) = (Vec::new(), 0);
+        while let Some(op) = ops.recv().await {
+            match op.unwrap() {
+                Op::Literal(bytes) => rebuilt.extend_from_slice(&bytes),
+                Op::Blocks { index, count, data } => {
+                    let blocks = basis.read(index, count).await.unwrap();
+                    assert_eq!(blocks, data);
+                    reused += blocks.len();
+                    rebuilt.extend_from_slice(&blocks);
+                }
+            }
+        }
+        std::fs::remove_file(old_path).unwrap();
+        std::fs::remove_file(new_path).unwrap();
+        (rebuilt, reused)
+    }
+
+    #[test]
+    fn rolling_matches_summing_afresh() {
+        let bytes = noise(4096 + 100, 1);
+        let mut rolling = Rolling::new(&bytes[..4096]);
+        for start in 1..=100 {
+            rolling.roll(bytes[start - 1], bytes[start + 4095]);
+            assert_eq!(rolling.digest(), Rolling::new(&bytes[start..start + 4096]).digest());
+        }
+    }
+
+    #[test]
+    fn small_copies_are_not_described() {
+        assert_eq!(block_size(MIN_BASIS_SIZE - 1), None);
+        assert_eq!(block_size(MIN_BASIS_SIZE), Some(MIN_BLOCK_SIZE));
+        assert_eq!(block_size(1 << 40), Some(MAX_BLOCK_SIZE));
+    }
+
+    #[tokio::test]
+    async fn unchanged_file_is_all_blocks() {
+        let old = noise(1024 * 1024 + 123, 2);
+        let (rebuilt, reused) = round_trip("unchanged", &old, &old).await;
+        assert!(rebuilt == old);
+        assert_eq!(reused, old.len());
+    }
+
+    #[tokio::test]
+    async fn inserted_bytes_are_sent_and_the_rest_reused() {
+        let old = noise(1024 * 1024, 3);
+        let mut new = old.clone();
+        new.splice(300_000..300_000, noise(777, 4));
+        new.splice(10..10, noise(5, 5));
+        let (rebuilt, reused) = round_trip("inserted", &old, &new).await;
+        assert!(rebuilt == new);
+        assert!(reused >= old.len() - 3 * 4096, "only {} bytes reused", reused);
+    }
+
+    #[tokio::test]
+    async fn deleted_bytes_leave_the_rest_reused() {
+        let old = noise(1024 * 1024, 6);
+        let mut new = old.clone();
+        new.drain(500_000..510_001);
+        new.drain(..3);
+        let (rebuilt, reused) = round_trip("deleted", &old, &new).await;
+        assert!(rebuilt == new);
+        assert!(reused >= new.len() - 3 * 4096, "only {} bytes reused", reused);
+    }
+
+    #[tokio::test]
+    async fn moved_and_changed_blocks_are_found() {
+        let old = noise(512 * 1024 + 1000, 7);
+        // The second half first, then a change, then the first half with its end cut off.
+        let mut new = old[256 * 1024..].to_vec();
+        new.extend_from_slice(&noise(10_000, 8));
+        new.extend_from_slice(&old[..200_000]);
+        let (rebuilt, reused) = round_trip("moved", &old, &new).await;
+        assert!(rebuilt == new);
+        assert!(reused >= new.len() - 10_000 - 2 * 4096, "only {} bytes reused", reused);
+    }
+
+    #[tokio::test]
+    async fn reading_past_the_copy_is_refused() {
+        let old = noise(100 * 1024, 9);
+        let path = write_scratch("past", &old);
+        let (mut basis, _, sums) = Basis::open(&path).await.unwrap().unwrap();
+        let blocks = sums.len() as u64;
+        assert_eq!(basis.read(blocks - 1, 1).await.unwrap(), old[(blocks as usize - 1) * 4096..]);
+        assert!(basis.read(blocks - 1, 2).await.is_err());
+        assert!(basis.read(u64::MAX, 1).await.is_err());
+        std::fs::remove_file(path).unwrap();
+    }
+}
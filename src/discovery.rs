@@ -0,0 +1,340 @@
+use std::io::{Error, ErrorKind};
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::time::Duration;
+use serde_json::{json, Value};
+use socket2::{Domain, Protocol, Socket, Type};
+use tokio::net::UdpSocket;
+use tokio::time::Instant;
+
+use crate::limits::Limits;
+use crate::protocol::PROTOCOL_VERSION;
+
+/// Servers listen for discovery queries on this UDP port, both on the multicast
+/// group and for broadcasts.
+const DISCOVERY_PORT: u16 = 47811;
+const DISCOVERY_GROUP: Ipv4Addr = Ipv4Addr::new(239, 255, 83, 76);
+
+/// Where queries are sent and listened for: the group, which a unicast
+/// address can stand in for, and broadcasts if `broadcast`, on `port`.
+#[derive(Clone, Copy)]
+struct Channel {
+    group: Ipv4Addr,
+    port: u16,
+    broadcast: bool,
+}
+
+impl Channel {
+    const LAN: Channel = Channel { group: DISCOVERY_GROUP, port: DISCOVERY_PORT, broadcast: true };
+}
+
+/// How long to collect answers. Queries are sent twice, in case one is lost.
+const DISCOVERY_TIMEOUT: Duration = Duration::from_millis(1000);
+const REPEAT_AFTER: Duration = Duration::from_millis(300);
+
+/// What a server tells clients looking for it.
+pub struct Announcement {
+    pub name: String,
+    /// The address the server listens on, unless it listens on all of them, in
+    /// which case clients use the one the answer came from.
+    pub ip: Option<IpAddr>,
+    pub port: u16,
+    /// The certificate fingerprint, or `None` when running with `--insecure`.
+    pub fingerprint: Option<String>,
+    pub pairing: bool,
+    pub tokens: bool,
+}
+
+/// A server that answered a discovery query.
+pub struct DiscoveredServer {
+    pub name: String,
+    pub address: SocketAddr,
+    pub version: u64,
+    pub fingerprint: Option<String>,
+    pub pairing: bool,
+    pub tokens: bool,
+}
+
+/// The name a server advertises unless given `--name`: the machine's host name.
+pub fn default_name() -> String {
+    let name = std::env::var("COMPUTERNAME")
+        .or_else(|_| std::fs::read_to_string("/proc/sys/kernel/hostname"))
+        .or_else(|_| std::fs::read_to_string("/etc/hostname"))
+        .unwrap_or_default();
+    match name.trim() {
+        "" => "streamline".to_string(),
+        name => name.to_lowercase(),
+    }
+}
+
+/// Answers discovery queries from the addresses `limits` allows, until the server stops.
+pub async fn advertise(announcement: Announcement, limits: Limits) -> std::io::Result<()> {
+    advertise_on(Channel::LAN, announcement, limits).await
+}
+
+async fn advertise_on(channel: Channel, announcement: Announcement, limits: Limits) -> std::io::Result<()> {
+    let socket = listen(channel)?;
+    let reply = json!({
+        "streamline": "here",
+        "version": PROTOCOL_VERSION,
+        "name": announcement.name,
+        "ip": announcement.ip.map(|ip| ip.to_string()),
+        "port": announcement.port,
+        "fingerprint": announcement.fingerprint,
+        "pairing": announcement.pairing,
+        "tokens": announcement.tokens,
+    })
+    .to_string();
+
+    let mut buffer = [0u8; 2048];
+    loop {
+        let (len, from) = socket.recv_from(&mut buffer).await?;
+        let query: Option<Value> = serde_json::from_slice(&buffer[..len]).ok();
+        if query.as_ref().and_then(|query| query["streamline"].as_str()) != Some("discover") {
+            continue;
+        }
+        if limits.address_refusal(from.ip()).is_some() {
+            continue;
+        }
+        // A client that has gone away is no reason to stop answering others.
+        let _ = socket.send_to(reply.as_bytes(), from).await;
+    }
+}
+
+/// Binds the discovery port so that several servers on one machine can share it,
+/// each receiving every query.
+fn listen(channel: Channel) -> std::io::Result<UdpSocket> {
+    let socket = Socket::new(Domain::IPV4, Type::DGRAM, Some(Protocol::UDP))?;
+    socket.set_reuse_address(true)?;
+    #[cfg(unix)]
+    socket.set_reuse_port(true)?;
+    socket.set_nonblocking(true)?;
+    socket.bind(&SocketAddr::from((Ipv4Addr::UNSPECIFIED, channel.port)).into())?;
+    if channel.group.is_multicast() {
+        socket.join_multicast_v4(&channel.group, &Ipv4Addr::UNSPECIFIED)?;
+    }
+    UdpSocket::from_std(socket.into())
+}
+
+/// Asks the local network which servers are there. With `wanted`, stops as soon
+/// as the server of that name answers.
+pub async fn discover(wanted: Option<&str>) -> std::io::Result<Vec<DiscoveredServer>> {
+    discover_on(Channel::LAN, wanted).await
+}
+
+async fn discover_on(channel: Channel, wanted: Option<&str>) -> std::io::Result<Vec<DiscoveredServer>> {
+    let socket = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0)).await?;
+    socket.set_broadcast(true)?;
+    socket.set_multicast_loop_v4(true)?;
+    let query = json!({ "streamline": "discover", "version": PROTOCOL_VERSION }).to_string();
+
+    let started = Instant::now();
+    let mut queries_sent = 0;
+    let mut found: Vec<DiscoveredServer> = Vec::new();
+    let mut buffer = [0u8; 2048];
+    loop {
+        let next_query = started + REPEAT_AFTER * queries_sent;
+        if queries_sent < 2 && Instant::now() >= next_query {
+            send_query(&socket, channel, query.as_bytes()).await?;
+            queries_sent += 1;
+            continue;
+        }
+        let wake = if queries_sent < 2 { next_query } else { started + DISCOVERY_TIMEOUT };
+        let received = match tokio::time::timeout_at(wake, socket.recv_from(&mut buffer)).await {
+            Ok(received) => received?,
+            Err(_) if queries_sent < 2 => continue,
+            Err(_) => break,
+        };
+        let Some(server) = parse_reply(&buffer[..received.0], received.1) else {
+            continue;
+        };
+        // Queries go out by multicast and broadcast alike, so servers usually answer twice.
+        if found.iter().any(|known| known.address == server.address) {
+            continue;
+        }
+        let done = wanted.is_some_and(|wanted| server.name.eq_ignore_ascii_case(wanted));
+        found.push(server);
+        if done {
+            break;
+        }
+    }
+    found.sort_by(|a, b| a.name.cmp(&b.name).then(a.address.cmp(&b.address)));
+    Ok(found)
+}
+
+/// Sends the query to the multicast group and as a broadcast. Either may be
+/// unavailable on a given network, but not both.
+async fn send_query(socket: &UdpSocket, channel: Channel, query: &[u8]) -> std::io::Result<()> {
+    let multicast = socket.send_to(query, (channel.group, channel.port)).await;
+    let broadcast = if channel.broadcast {
+        socket.send_to(query, (Ipv4Addr::BROADCAST, channel.port)).await
+    } else {
+        Err(Error::new(ErrorKind::Unsupported, "not broadcasting"))
+    };
+    match (multicast, broadcast) {
+        (Err(e), Err(_)) => Err(Error::new(e.kind(), format!("could not search the local network: {}", e))),
+        _ => Ok(()),
+    }
+}
+
+fn parse_reply(datagram: &[u8], from: SocketAddr) -> Option<DiscoveredServer> {
+    let reply: Value = serde_json::from_slice(datagram).ok()?;
+    if reply["streamline"].as_str()? != "here" {
+        return None;
+    }
+    let name = reply["name"].as_str()?;
+    if name.is_empty() || name.chars().any(|c| c.is_whitespace() || c.is_control()) {
+        return None;
+    }
+    let ip = match reply["ip"].as_str() {
+        Some(ip) => ip.parse().ok()?,
+        None => from.ip(),
+    };
+    Some(DiscoveredServer {
+        name: name.to_string(),
+        address: SocketAddr::new(ip, u16::try_from(reply["port"].as_u64()?).ok()?),
+        version: reply["version"].as_u64()?,
+        fingerprint: reply["fingerprint"].as_str().map(str::to_string),
+        pairing: reply["pairing"].as_bool().unwrap_or(false),
+        tokens: reply["tokens"].as_bool().unwrap_or(false),
+    })
+}
+
+/// Turns an address given as `@name` into the address of the server advertising
+/// that name. Other addresses are returned unchanged.
+pub async fn resolve(address: &str) -> std::io::Result<String> {
+    let Some(name) = address.strip_prefix('@') else {
+        return Ok(address.to_string());
+    };
+    let found = discover(Some(name)).await?;
+    match found.into_iter().find(|server| server.name.eq_ignore_ascii_case(name)) {
+        Some(server) => {
+            eprintln!("Found {} at {}", address, server.address);
+            Ok(server.address.to_string())
+        }
+        None => Err(Error::new(
+            ErrorKind::NotFound,
+            format!("no server named '{}' answered on the local network (see 'streamline discover')", name),
+        )),
+    }
+}
+
+/// Prints discovered servers as a table: name, address, security, fingerprint.
+pub fn print_table(servers: &[DiscoveredServer]) {
+    let name_width = servers.iter().map(|server| server.name.len()).max().unwrap_or(0);
+    let address_width = servers.iter().map(|server| server.address.to_string().len()).max().unwrap_or(0);
+    for server in servers {
+        let security = describe_security(server);
+        println!(
+            "{:name_width$}  {:address_width$}  {:24}  {}",
+            server.name,
+            server.address.to_string(),
+            security,
+            server.fingerprint.as_deref().unwrap_or("-"),
+            name_width = name_width,
+            address_width = address_width
+        );
+    }
+}
+
+fn describe_security(server: &DiscoveredServer) -> String {
+    let mut security = vec![if server.fingerprint.is_some() { "TLS" } else { "insecure" }];
+    if server.pairing {
+        security.push("pairing code");
+    }
+    if server.tokens {
+        security.push("tokens");
+    }
+    let mut security = security.join(", ");
+    if server.version != PROTOCOL_VERSION as u64 {
+        security.push_str(&format!(" (protocol v{}, incompatible)", server.version));
+    }
+    security
+}
+
+/// Prints discovered servers as a JSON array, one object per server.
+pub fn print_json(servers: &[DiscoveredServer]) {
+    let servers: Vec<_> = servers
+        .iter()
+        .map(|server| {
+            json!({
+                "name": server.name,
+                "address": server.address.to_string(),
+                "version": server.version,
+                "fingerprint": server.fingerprint,
+                "pairing": server.pairing,
+                "tokens": server.tokens,
+            })
+        })
+        .collect();
+    println!("{}", serde_json::to_string_pretty(&servers).unwrap());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn announcement(name: &str, port: u16) -> Announcement {
+        Announcement { name: name.to_string(), ip: None, port, fingerprint: Some("ab:cd".to_string()), pairing: true, tokens: false }
+    }
+
+    fn limits(deny: &[&str]) -> Limits {
+        let deny = deny.iter().map(|cidr| crate::limits::Cidr::parse(cidr).unwrap()).collect();
+        Limits { allow: Vec::new(), deny, max_connections: 0, max_per_address: 0, per_minute: 0 }
+    }
+
+    /// Queries to loopback only, on a port nothing else on the machine is
+    /// using, so that tests don't answer each other.
+    fn loopback() -> Channel {
+        let port = std::net::UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).unwrap().local_addr().unwrap().port();
+        Channel { group: Ipv4Addr::LOCALHOST, port, broadcast: false }
+    }
+
+    #[tokio::test]
+    async fn servers_are_found_over_loopback() {
+        // Only one server can be on a unicast channel: each query reaches just one of them.
+        let channel = loopback();
+        let server = tokio::spawn(advertise_on(channel, announcement("office-pc", 9000), limits(&[])));
+
+        let found = discover_on(channel, None).await.unwrap();
+        assert_eq!(found.len(), 1, "servers answering twice are listed once");
+        let office_pc = &found[0];
+        assert_eq!(office_pc.name, "office-pc");
+        assert_eq!(office_pc.address, SocketAddr::from((Ipv4Addr::LOCALHOST, 9000)));
+        assert_eq!(office_pc.version, PROTOCOL_VERSION as u64);
+        assert_eq!(office_pc.fingerprint.as_deref(), Some("ab:cd"));
+        assert!(office_pc.pairing && !office_pc.tokens);
+        server.abort();
+    }
+
+    #[tokio::test]
+    async fn a_wanted_name_ends_the_search_early() {
+        let channel = loopback();
+        let server = tokio::spawn(advertise_on(channel, announcement("Office-PC", 9000), limits(&[])));
+
+        let started = Instant::now();
+        let found = discover_on(channel, Some("office-pc")).await.unwrap();
+        assert!(started.elapsed() < DISCOVERY_TIMEOUT);
+        assert_eq!(found.len(), 1);
+        server.abort();
+    }
+
+    #[tokio::test]
+    async fn denied_addresses_get_no_answer() {
+        let channel = loopback();
+        let server = tokio::spawn(advertise_on(channel, announcement("office-pc", 9000), limits(&["127.0.0.0/8"])));
+
+        assert!(discover_on(channel, None).await.unwrap().is_empty());
+        server.abort();
+    }
+
+    #[test]
+    fn replies_with_unusable_names_are_ignored() {
+        let from = SocketAddr::from((Ipv4Addr::LOCALHOST, 5000));
+        let reply = |name: &str| json!({ "streamline": "here", "version": 1, "name": name, "port": 80 }).to_string();
+        assert!(parse_reply(reply("office-pc").as_bytes(), from).is_some());
+        for name in ["", "office pc", "office\u{1b}[2J"] {
+            assert!(parse_reply(reply(name).as_bytes(), from).is_none(), "{:?} was accepted", name);
+        }
+        assert!(parse_reply(b"not json", from).is_none());
+    }
+}
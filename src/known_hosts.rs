@@ -0,0 +1,72 @@
+use std::path::PathBuf;
+
+use crate::streamline_dir;
+use crate::tls::Fingerprint;
+
+/// How a server's certificate compares with the one remembered for it.
+pub enum Verdict {
+    Known,
+    New,
+    /// The server presented a different certificate than before; `line` is where
+    /// the old one is recorded.
+    Changed { previous: Fingerprint, line: usize },
+}
+
+pub fn path() -> PathBuf {
+    streamline_dir().join("known_hosts")
+}
+
+/// Looks `host` up in the known hosts file. Each line holds a host, as typed on
+/// the command line, and its certificate fingerprint; `#` starts a comment.
+pub fn check(host: &str, fingerprint: Fingerprint) -> Verdict {
+    let contents = std::fs::read_to_string(path()).unwrap_or_default();
+    for (index, line) in contents.lines().enumerate() {
+        let Some((known_host, known)) = parse_line(line) else {
+            continue;
+        };
+        if !known_host.eq_ignore_ascii_case(host) {
+            continue;
+        }
+        return if known == fingerprint {
+            Verdict::Known
+        } else {
+            Verdict::Changed { previous: known, line: index + 1 }
+        };
+    }
+    Verdict::New
+}
+
+/// Records `fingerprint` as the certificate of `host`, replacing any previous entry.
+pub fn remember(host: &str, fingerprint: Fingerprint) -> std::io::Result<()> {
+    let contents = std::fs::read_to_string(path()).unwrap_or_default();
+    let mut updated: String = contents
+        .lines()
+        .filter(|line| !parse_line(line).is_some_and(|(known_host, _)| known_host.eq_ignore_ascii_case(host)))
+        .map(|line| format!("{}\n", line))
+        .collect();
+    updated.push_str(&format!("{} {}\n", host, fingerprint));
+    std::fs::create_dir_all(streamline_dir())?;
+    std::fs::write(path(), updated)
+}
+
+fn parse_line(line: &str) -> Option<(&str, Fingerprint)> {
+    let line = line.split('#').next().unwrap_or_default();
+    let (host, fingerprint) = line.trim().split_once(char::is_whitespace)?;
+    Some((host, Fingerprint::parse(fingerprint.trim()).ok()?))
+}
+
+/// Prints an unmissable warning about a changed certificate, in the spirit of SSH's.
+pub fn warn_changed(host: &str, fingerprint: Fingerprint, previous: Fingerprint, line: usize) {
+    eprintln!("@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@");
+    eprintln!("@    WARNING: SERVER CERTIFICATE HAS CHANGED!             @");
+    eprintln!("@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@");
+    eprintln!("IT IS POSSIBLE THAT SOMEONE IS DOING SOMETHING NASTY!");
+    eprintln!("Someone could be intercepting your connection to {} (a man-in-the-middle attack),", host);
+    eprintln!("or the server's certificate has been replaced.");
+    eprintln!("The certificate fingerprint sent by the server is");
+    eprintln!("  {}", fingerprint);
+    eprintln!("but it used to be");
+    eprintln!("  {}", previous);
+    eprintln!("as recorded on line {} of {}.", line, path().display());
+    eprintln!("If you know why it changed, remove that line, or connect once with --pin {}", fingerprint);
+}
@@ -0,0 +1,183 @@
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// How often refusals of the same address are logged; the rest are counted.
+const REPORT_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Addresses not heard from for this long are forgotten, unless still connected.
+const FORGET_AFTER: Duration = Duration::from_secs(60);
+
+/// A block of addresses such as `192.168.0.0/24`, or a single address.
+#[derive(Clone, Copy)]
+pub struct Cidr {
+    network: IpAddr,
+    prefix: u8,
+}
+
+impl Cidr {
+    pub fn parse(value: &str) -> Result<Self, String> {
+        let invalid = || format!("'{}' is not an address or CIDR block such as 192.168.0.0/24", value);
+        let (address, prefix) = match value.split_once('/') {
+            Some((address, prefix)) => (address, Some(prefix)),
+            None => (value, None),
+        };
+        let network: IpAddr = address.parse().map_err(|_| invalid())?;
+        let max_prefix = if network.is_ipv4() { 32 } else { 128 };
+        let prefix = match prefix {
+            Some(prefix) => prefix.parse().ok().filter(|prefix| *prefix <= max_prefix).ok_or_else(invalid)?,
+            None => max_prefix,
+        };
+        Ok(Cidr { network, prefix })
+    }
+
+    fn contains(&self, ip: IpAddr) -> bool {
+        // IPv4 peers of a dual-stack listener show up as ::ffff:a.b.c.d.
+        match (self.network, ip.to_canonical()) {
+            (IpAddr::V4(network), IpAddr::V4(ip)) => {
+                let mask = u32::MAX.checked_shl(32 - self.prefix as u32).unwrap_or(0);
+                u32::from(network) & mask == u32::from(ip) & mask
+            }
+            (IpAddr::V6(network), IpAddr::V6(ip)) => {
+                let mask = u128::MAX.checked_shl(128 - self.prefix as u32).unwrap_or(0);
+                u128::from(network) & mask == u128::from(ip) & mask
+            }
+            _ => false,
+        }
+    }
+}
+
+impl std::fmt::Display for Cidr {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{}/{}", self.network, self.prefix)
+    }
+}
+
+/// Who may connect to the server, and how much. A limit of 0 means none.
+#[derive(Clone)]
+pub struct Limits {
+    /// If not empty, only these addresses may connect.
+    pub allow: Vec<Cidr>,
+    /// Addresses that may never connect, even if allowed.
+    pub deny: Vec<Cidr>,
+    pub max_connections: usize,
+    pub max_per_address: usize,
+    /// New connections accepted from one address per minute, in bursts of up to as many.
+    pub per_minute: usize,
+}
+
+impl Limits {
+    /// Why `ip` may not connect at all, going by the allow and deny lists.
+    pub fn address_refusal(&self, ip: IpAddr) -> Option<&'static str> {
+        if self.deny.iter().any(|cidr| cidr.contains(ip)) {
+            Some("address is denied")
+        } else if !self.allow.is_empty() && !self.allow.iter().any(|cidr| cidr.contains(ip)) {
+            Some("address is not allowed")
+        } else {
+            None
+        }
+    }
+}
+
+/// Applies `Limits` to incoming connections as they are accepted.
+pub struct Admission {
+    limits: Limits,
+    state: Mutex<State>,
+}
+
+#[derive(Default)]
+struct State {
+    active: usize,
+    peers: HashMap<IpAddr, Peer>,
+}
+
+struct Peer {
+    active: usize,
+    /// Connections this address may still open right now, replenished over time.
+    allowance: f64,
+    last_seen: Instant,
+    last_report: Option<Instant>,
+    unreported: u32,
+}
+
+/// A refused connection, with whether it is worth logging.
+pub struct Refused {
+    pub reason: String,
+    pub report: bool,
+}
+
+/// An admitted connection, which holds its place until dropped.
+pub struct Permit {
+    admission: Arc<Admission>,
+    ip: IpAddr,
+}
+
+impl Admission {
+    pub fn new(limits: Limits) -> Arc<Self> {
+        Arc::new(Admission { limits, state: Mutex::default() })
+    }
+
+    pub fn admit(self: &Arc<Self>, ip: IpAddr) -> Result<Permit, Refused> {
+        let ip = ip.to_canonical();
+        let limits = &self.limits;
+        let now = Instant::now();
+        let mut state = self.state.lock().unwrap();
+        if state.peers.len() > 1024 {
+            state.peers.retain(|_, peer| peer.active > 0 || now - peer.last_seen < FORGET_AFTER);
+        }
+
+        let active = state.active;
+        let peer = state.peers.entry(ip).or_insert(Peer {
+            active: 0,
+            allowance: limits.per_minute as f64,
+            last_seen: now,
+            last_report: None,
+            unreported: 0,
+        });
+        let rate = limits.per_minute as f64 / 60.0;
+        peer.allowance = (peer.allowance + (now - peer.last_seen).as_secs_f64() * rate).min(limits.per_minute as f64);
+        peer.last_seen = now;
+
+        let refusal = if let Some(reason) = limits.address_refusal(ip) {
+            Some(reason.to_string())
+        } else if limits.per_minute > 0 && peer.allowance < 1.0 {
+            Some(format!("more than {} connections a minute", limits.per_minute))
+        } else if limits.max_per_address > 0 && peer.active >= limits.max_per_address {
+            Some(format!("already {} connections from this address", peer.active))
+        } else if limits.max_connections > 0 && active >= limits.max_connections {
+            Some(format!("already {} connections in total", active))
+        } else {
+            None
+        };
+
+        if let Some(mut reason) = refusal {
+            let report = peer.last_report.is_none_or(|last| now - last >= REPORT_INTERVAL);
+            if report {
+                if peer.unreported > 0 {
+                    reason.push_str(&format!(" ({} more refused since the last report)", peer.unreported));
+                }
+                peer.last_report = Some(now);
+                peer.unreported = 0;
+            } else {
+                peer.unreported += 1;
+            }
+            return Err(Refused { reason, report });
+        }
+
+        peer.allowance -= 1.0;
+        peer.active += 1;
+        state.active += 1;
+        Ok(Permit { admission: self.clone(), ip })
+    }
+}
+
+impl Drop for Permit {
+    fn drop(&mut self) {
+        let mut state = self.admission.state.lock().unwrap();
+        state.active -= 1;
+        if let Some(peer) = state.peers.get_mut(&self.ip) {
+            peer.active -= 1;
+        }
+    }
+}
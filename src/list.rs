@@ -0,0 +1,111 @@
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
+use sha2::Digest;
+
+use crate::{date, paths};
+use crate::protocol::RemoteEntry;
+use crate::resume;
+
+/// Lists `path` below `root`: the entries of a directory, or just the file itself.
+/// An empty path lists the root. Symbolic links are left out, as they can't be
+/// written through or downloaded either.
+pub async fn list(root: &Path, path: &str, hashes: bool) -> Result<Vec<RemoteEntry>, String> {
+    let path = path.trim_end_matches('/');
+    let local = if path.is_empty() {
+        root.to_path_buf()
+    } else {
+        paths::sanitize(path).and_then(|relative| paths::resolve(root, &relative))?
+    };
+    let not_found = || format!("'{}' does not exist", path);
+    let metadata = tokio::fs::symlink_metadata(&local).await.map_err(|_| not_found())?;
+    if !metadata.is_dir() {
+        let name = path.rsplit('/').next().unwrap_or_default().to_string();
+        return Ok(vec![describe(&local, name, &metadata, hashes).await?]);
+    }
+
+    let mut entries = Vec::new();
+    let mut children = tokio::fs::read_dir(&local).await.map_err(|e| format!("'{}': {}", path, e))?;
+    while let Some(child) = children.next_entry().await.map_err(|e| format!("'{}': {}", path, e))? {
+        let Ok(name) = child.file_name().into_string() else {
+            continue;
+        };
+        let Ok(metadata) = child.metadata().await else {
+            continue;
+        };
+        if metadata.file_type().is_symlink() || name.chars().any(char::is_control) {
+            continue;
+        }
+        // Hidden partial files belong to transfers in progress, not to the listing.
+        if resume::is_partial_name(&name) {
+            continue;
+        }
+        entries.push(describe(&child.path(), name, &metadata, hashes).await?);
+    }
+    entries.sort_by(|a, b| a.name.cmp(&b.name));
+    Ok(entries)
+}
+
+async fn describe(path: &Path, name: String, metadata: &std::fs::Metadata, hashes: bool) -> Result<RemoteEntry, String> {
+    let is_dir = metadata.is_dir();
+    let sha256 = if hashes && !is_dir {
+        let hasher = resume::hash_prefix(path, metadata.len()).await.map_err(|e| format!("'{}': {}", name, e))?;
+        Some(hasher.finalize().into())
+    } else {
+        None
+    };
+    Ok(RemoteEntry {
+        name,
+        is_dir,
+        size: if is_dir { 0 } else { metadata.len() },
+        mtime: metadata.modified().ok(),
+        sha256,
+    })
+}
+
+/// Prints a listing as an aligned table: size, modification time, hash if any, name.
+pub fn print_table(entries: &[RemoteEntry]) {
+    let width = entries.iter().map(|entry| entry.size.to_string().len()).max().unwrap_or(0);
+    for entry in entries {
+        let size = if entry.is_dir { "-".to_string() } else { entry.size.to_string() };
+        let modified = entry.mtime.map(format_time).unwrap_or_else(|| "-".repeat(20));
+        let hash = match (&entry.sha256, entry.is_dir) {
+            (Some(sha256), _) => format!("{}  ", hex(sha256)),
+            (None, true) if entries.iter().any(|entry| entry.sha256.is_some()) => format!("{:64}  ", "-"),
+            (None, _) => String::new(),
+        };
+        let suffix = if entry.is_dir { "/" } else { "" };
+        println!("{:>width$}  {}  {}{}{}", size, modified, hash, entry.name, suffix, width = width);
+    }
+}
+
+/// Prints a listing as a JSON array, one object per entry.
+pub fn print_json(entries: &[RemoteEntry]) {
+    let entries: Vec<_> = entries
+        .iter()
+        .map(|entry| {
+            serde_json::json!({
+                "name": entry.name,
+                "type": if entry.is_dir { "directory" } else { "file" },
+                "size": entry.size,
+                "modified": entry.mtime.map(format_time),
+                "sha256": entry.sha256.as_ref().map(|sha256| hex(sha256)),
+            })
+        })
+        .collect();
+    println!("{}", serde_json::to_string_pretty(&entries).unwrap());
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+/// Formats a time as UTC in ISO 8601, e.g. `2024-05-01T12:30:00Z`.
+fn format_time(time: SystemTime) -> String {
+    let secs = time.duration_since(UNIX_EPOCH).map(|since| since.as_secs()).unwrap_or(0);
+    let (days, secs_of_day) = ((secs / 86_400) as i64, secs % 86_400);
+    let (year, month, day) = date::civil_from_days(days);
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}Z",
+        year, month, day, secs_of_day / 3600, secs_of_day / 60 % 60, secs_of_day % 60
+    )
+}
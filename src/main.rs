@@ -1,172 +1,119 @@
-use std::fs::File;
-use std::path::{Path, PathBuf};
+use std::path::PathBuf;
 use std::env;
-use std::time::Instant;
-use std::io::{BufReader, Read};
-use tokio::net::{TcpListener, TcpStream};
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
-use tokio::fs::OpenOptions;
-use tokio::sync::Semaphore;
-use sha2::{Sha256, Digest};
-use indicatif::{ProgressBar, ProgressStyle};
-use futures::future::join_all;
+
+#[macro_use]
+mod progress;
+mod archive;
+mod auth;
+mod cli;
+mod client;
+mod compress;
+mod conflict;
+mod date;
+mod dedup;
+mod delta;
+mod discovery;
+mod known_hosts;
+mod limits;
+mod list;
+mod metadata;
+mod mux;
+mod pairing;
+mod paths;
+mod prompt;
+mod protocol;
+mod receive;
+mod resume;
+mod send;
+mod server;
+mod sparse;
+mod tls;
+mod walk;
+
+use archive::{ArchiveFormat, Extraction};
+use auth::Tokens;
+use client::ConnectOptions;
+use compress::Compression;
+use conflict::ConflictPolicy;
+use dedup::DedupMode;
+use limits::{Cidr, Limits};
+use metadata::Preserve;
+use resume::BatchJournal;
+use send::SendOptions;
+use pairing::PairingCode;
+use prompt::Prompt;
+use server::ServerConfig;
+use tls::Fingerprint;
+use walk::Links;
 
 const CHUNK_SIZE: usize = 1024 * 1024; // 1 MB
 const MAX_PARALLEL_TRANSFERS: usize = 5;
-
-async fn start_server(address: &str, output_path: Option<String>) -> tokio::io::Result<()> {
-    let listener = TcpListener::bind(address).await?;
-    println!("Server listening on {}", address);
-
-    loop {
-        let (socket, _) = listener.accept().await?;
-        let output_path = output_path.clone();
-        tokio::spawn(async move {
-            if let Err(e) = receive_file(socket, output_path).await {
-                eprintln!("Error receiving file: {}", e);
-            }
-        });
+const CHECKPOINT_INTERVAL: u64 = 64 * 1024 * 1024; // 64 MB
+
+// Default connection limits for the server, generous for a LAN but enough to
+// stop one runaway script from using up its file descriptors.
+const MAX_CONNECTIONS: usize = 64;
+const MAX_CONNECTIONS_PER_ADDRESS: usize = 8;
+const CONNECTIONS_PER_MINUTE: usize = 60;
+
+/// Where Streamline keeps its own state, such as the journals of batches being sent.
+fn streamline_dir() -> PathBuf {
+    if let Some(dir) = env::var_os("STREAMLINE_HOME") {
+        return PathBuf::from(dir);
     }
+    let home = env::var_os("HOME").or_else(|| env::var_os("USERPROFILE"));
+    home.map(PathBuf::from).unwrap_or_default().join(".streamline")
 }
 
-async fn receive_file(mut socket: TcpStream, output_path: Option<String>) -> tokio::io::Result<()> {
-    let mut file_name_buffer = vec![0; 256];
-    let file_name_size = socket.read(&mut file_name_buffer).await?;
-    let file_name = String::from_utf8_lossy(&file_name_buffer[..file_name_size]);
-    let file_name = file_name.trim();
-
-    let output_file_path = if let Some(path) = output_path {
-        let mut path_buf = PathBuf::from(path);
-        path_buf.push(file_name);
-        path_buf
-    } else {
-        PathBuf::from(file_name)
-    };
-
-    let mut file = OpenOptions::new()
-        .create(true)
-        .write(true)
-        .truncate(true)
-        .open(&output_file_path)
-        .await?;
-
-    let mut size_buffer = [0u8; 8];
-    socket.read_exact(&mut size_buffer).await?;
-    let file_size = u64::from_be_bytes(size_buffer);
-
-    let pb = ProgressBar::new(file_size);
-    pb.set_style(ProgressStyle::default_bar()
-        .template("[{elapsed_precise}] [{bar:40.cyan/blue}] {bytes}/{total_bytes} ({eta})")
-        .unwrap()
-        .progress_chars("#>-"));
-
-    let start_time = Instant::now();
-    let mut total_bytes = 0u64;
-    let mut hasher = Sha256::new();
-
-    while total_bytes < file_size {
-        let mut buffer = vec![0; CHUNK_SIZE.min((file_size - total_bytes) as usize)];
-        let n = socket.read(&mut buffer).await?;
-        if n == 0 {
-            break;
-        }
-        file.write_all(&buffer[..n]).await?;
-        total_bytes += n as u64;
-        pb.set_position(total_bytes);
-        hasher.update(&buffer[..n]);
-    }
-
-    pb.finish_with_message("Transfer complete");
-
-    let duration = start_time.elapsed();
-    let speed = total_bytes as f64 / duration.as_secs_f64() / 1024.0 / 1024.0; // MB/s
-    println!("Transfer complete in {:.2?}", duration);
-    println!("Average speed: {:.2} MB/s", speed);
-
-    let calculated_hash = hasher.finalize();
-    let mut received_hash = [0u8; 32];
-    socket.read_exact(&mut received_hash).await?;
-
-    if calculated_hash[..] == received_hash {
-        println!("File integrity verified");
-    } else {
-        println!("Warning: File integrity check failed");
+/// The `--insecure`, `--pin`, `--code` and `--token` options shared by every
+/// client command. The token can also come from `STREAMLINE_TOKEN`, which keeps
+/// it out of the process list.
+fn connect_options(options: &cli::Options) -> Result<ConnectOptions, String> {
+    let pin = options.value("--pin").map(Fingerprint::parse).transpose()?;
+    if pin.is_some() && options.has("--insecure") {
+        return Err("--pin has no effect with --insecure".to_string());
     }
-
-    println!("File received and saved to {:?}", output_file_path);
-    Ok(())
+    let code = options.value("--code").map(str::to_string);
+    let token = options.value("--token").map(str::to_string).or_else(|| env::var("STREAMLINE_TOKEN").ok());
+    Ok(ConnectOptions { insecure: options.has("--insecure"), pin, code, token })
 }
 
-async fn send_files(address: &str, file_paths: Vec<String>) -> tokio::io::Result<()> {
-    let semaphore = std::sync::Arc::new(Semaphore::new(MAX_PARALLEL_TRANSFERS));
-
-    let transfers = file_paths.into_iter().map(|file_path| {
-        let semaphore = semaphore.clone();
-        let address = address.to_string();
-        async move {
-            let _permit = semaphore.acquire_owned().await.unwrap();
-            send_file(&address, &file_path).await
-        }
-    });
-
-    let results = join_all(transfers).await;
-    
-    for result in results {
-        if let Err(e) = result {
-            eprintln!("Error sending file: {}", e);
-        }
+/// The limits on what the server unpacks from one archive.
+fn extraction(options: &cli::Options) -> Result<Extraction, String> {
+    let mut extraction = Extraction::default();
+    if let Some(value) = options.value("--max-extract-size") {
+        extraction.max_bytes = auth::parse_size(value)?;
     }
-
-    Ok(())
+    if let Some(value) = options.value("--max-extract-ratio") {
+        extraction.max_ratio = value.parse::<u64>().ok().filter(|ratio| *ratio > 0)
+            .ok_or_else(|| format!("--max-extract-ratio expects a positive number, not '{}'", value))?;
+    }
+    Ok(extraction)
 }
 
-async fn send_file(address: &str, file_path: &str) -> tokio::io::Result<()> {
-    let mut stream = TcpStream::connect(address).await?;
-    let path = Path::new(file_path);
-
-    let file_name = path.file_name().unwrap().to_str().unwrap();
-    stream.write_all(file_name.as_bytes()).await?;
-
-    let file = File::open(file_path)?;
-    let file_size = file.metadata()?.len();
-    stream.write_all(&file_size.to_be_bytes()).await?;
-
-    let mut reader = BufReader::new(file);
-
-    let pb = ProgressBar::new(file_size);
-    pb.set_style(ProgressStyle::default_bar()
-        .template("[{elapsed_precise}] [{bar:40.cyan/blue}] {bytes}/{total_bytes} ({eta})")
-        .unwrap()
-        .progress_chars("#>-"));
-
-    let start_time = Instant::now();
-    let mut total_bytes = 0u64;
-    let mut hasher = Sha256::new();
-
-    loop {
-        let mut buffer = vec![0; CHUNK_SIZE];
-        let n = reader.read(&mut buffer)?;
-        if n == 0 {
-            break;
-        }
-        stream.write_all(&buffer[..n]).await?;
-        total_bytes += n as u64;
-        pb.set_position(total_bytes);
-        hasher.update(&buffer[..n]);
+/// The `--no-times`, `--no-perms` and `--no-xattrs` switches of the receiving side.
+fn preserve(options: &cli::Options) -> Preserve {
+    Preserve {
+        times: !options.has("--no-times"),
+        permissions: !options.has("--no-perms"),
+        xattrs: !options.has("--no-xattrs"),
     }
+}
 
-    pb.finish_with_message("Transfer complete");
-
-    let duration = start_time.elapsed();
-    let speed = total_bytes as f64 / duration.as_secs_f64() / 1024.0 / 1024.0; // MB/s
-    println!("Transfer complete in {:.2?}", duration);
-    println!("Average speed: {:.2} MB/s", speed);
-
-    let hash = hasher.finalize();
-    stream.write_all(&hash).await?;
-
-    println!("File integrity verified: '{}' sent to {}", file_name, address);
-    Ok(())
+/// The server's `--allow`, `--deny` and connection limit options.
+fn limits(options: &cli::Options) -> Result<Limits, String> {
+    let cidrs = |name| options.values(name).iter().map(|value| Cidr::parse(value)).collect::<Result<Vec<_>, _>>();
+    let number = |name, default| match options.value(name) {
+        Some(value) => value.parse::<usize>().map_err(|_| format!("{} expects a number, not '{}'", name, value)),
+        None => Ok(default),
+    };
+    Ok(Limits {
+        allow: cidrs("--allow")?,
+        deny: cidrs("--deny")?,
+        max_connections: number("--max-conns", MAX_CONNECTIONS)?,
+        max_per_address: number("--max-conns-per-ip", MAX_CONNECTIONS_PER_ADDRESS)?,
+        per_minute: number("--rate-limit", CONNECTIONS_PER_MINUTE)?,
+    })
 }
 
 #[tokio::main]
@@ -174,31 +121,276 @@ async fn main() {
     let args: Vec<String> = env::args().collect();
 
     if args.len() < 2 {
-        eprintln!("Usage: {} [server|client] [options]", args[0]);
+        eprintln!("Usage: {} [server|client|get|ls|rm|discover] [options]", args[0]);
         return;
     }
 
     match args[1].as_str() {
         "server" => {
-            let address = args.get(2).cloned().unwrap_or_else(|| "0.0.0.0:8080".to_string());
-            let output_path = args.get(3).cloned();
-            if let Err(e) = start_server(&address, output_path).await {
+            let config = cli::Options::parse(&args[2..], &[
+                "--insecure", "--code", "--no-discovery", "--ask", "--extract", "--no-times", "--no-perms", "--no-xattrs",
+            ], &[
+                "--on-conflict", "--export", "--cert", "--key", "--tokens", "--name",
+                "--allow", "--deny", "--max-conns", "--max-conns-per-ip", "--rate-limit", "--compress", "--level",
+                "--max-extract-size", "--max-extract-ratio", "--dedup",
+            ]).and_then(|options| {
+                let on_conflict = match options.value("--on-conflict") {
+                    Some(policy) => ConflictPolicy::parse(policy)?,
+                    None => ConflictPolicy::Overwrite,
+                };
+                let address = options.positional.first().cloned().unwrap_or_else(|| "0.0.0.0:8080".to_string());
+                let output_path = options.positional.get(1).cloned();
+                let export = options.value("--export").map(PathBuf::from);
+                let tls = if options.has("--insecure") {
+                    None
+                } else {
+                    let cert = options.value("--cert").map(PathBuf::from);
+                    let key = options.value("--key").map(PathBuf::from);
+                    Some(tls::server(cert.as_deref(), key.as_deref()).map_err(|e| e.to_string())?)
+                };
+                let pairing = options.has("--code").then(PairingCode::generate);
+                let tokens = options.value("--tokens").map(|path| Tokens::load(path.as_ref())).transpose()?;
+                let limits = limits(&options)?;
+                let name = match options.value("--name") {
+                    Some(name) if name.is_empty() || name.starts_with('@') || name.chars().any(|c| c.is_whitespace() || c.is_control()) => {
+                        return Err(format!("'{}' can't be used as a name; leave out spaces and the leading @", name));
+                    }
+                    Some(name) => name.to_string(),
+                    None => discovery::default_name(),
+                };
+                let name = (!options.has("--no-discovery")).then_some(name);
+                let ask = if options.has("--ask") { Some(Prompt::new()?) } else { None };
+                let compression = Compression::parse(options.value("--compress"), options.value("--level"))?;
+                let extract = options.has("--extract");
+                let extraction = extraction(&options)?;
+                let preserve = preserve(&options);
+                let dedup = options.value("--dedup").map(DedupMode::parse).transpose()?;
+                Ok((address, ServerConfig { output_path, on_conflict, export, tls, pairing, tokens, limits, name, ask, compression, extract, extraction, preserve, dedup }))
+            });
+            let (address, config) = match config {
+                Ok(config) => config,
+                Err(e) => {
+                    eprintln!("Server error: {}", e);
+                    std::process::exit(1);
+                }
+            };
+            if let Err(e) = server::start_server(&address, config).await {
                 eprintln!("Server error: {}", e);
+                std::process::exit(1);
             }
         }
         "client" => {
-            if args.len() < 4 {
-                eprintln!("Usage: {} client <address> <file_path1> [file_path2 ...]", args[0]);
+            let parsed = cli::Options::parse(&args[2..], &["--resume", "--insecure", "--zip", "--tar", "--xattrs"], &["--include", "--exclude", "--on-conflict", "--pin", "--code", "--token", "--compress", "--level", "--links"])
+                .and_then(|options| {
+                    let archive = match (options.has("--zip"), options.has("--tar")) {
+                        (true, true) => return Err("--zip and --tar can't be used together".to_string()),
+                        (true, false) => Some(ArchiveFormat::Zip),
+                        (false, true) => Some(ArchiveFormat::Tar),
+                        (false, false) => None,
+                    };
+                    let links = options.value("--links").map(Links::parse).transpose()?;
+                    if let Some(format) = archive {
+                        if links.is_some() {
+                            return Err("--links applies to files sent one by one; --tar always keeps links and --zip follows them".to_string());
+                        }
+                        if options.has("--resume") {
+                            return Err(format!("--resume can't continue an archive; send it again with --{}", format.extension()));
+                        }
+                        if options.positional.len() > 2 {
+                            return Err(format!("--{} sends one directory at a time", format.extension()));
+                        }
+                    }
+                    let on_conflict = options.value("--on-conflict").map(ConflictPolicy::parse).transpose()?;
+                    let compression = Compression::parse(options.value("--compress"), options.value("--level"))?;
+                    let xattrs = options.has("--xattrs");
+                    let links = links.unwrap_or(Links::Follow);
+                    let hard_links = links == Links::Preserve;
+                    let connect_options = connect_options(&options)?;
+                    Ok((options, connect_options, archive, links, SendOptions { on_conflict, compression, xattrs, hard_links }))
+                });
+            let (options, connect_options, archive, links, config) = match parsed {
+                Ok(parsed) => parsed,
+                Err(e) => {
+                    eprintln!("Client error: {}", e);
+                    std::process::exit(1);
+                }
+            };
+
+            let (address, entries, resumed) = if options.has("--resume") && options.positional.len() <= 1 {
+                match BatchJournal::load(options.positional.first().map(String::as_str)).await {
+                    Ok(mut journal) => {
+                        println!(
+                            "Resuming batch to {}: {} of {} files remaining",
+                            journal.address,
+                            journal.pending.len(),
+                            journal.pending.len() + journal.done
+                        );
+                        (journal.address.clone(), std::mem::take(&mut journal.pending), Some(journal))
+                    }
+                    Err(e) => {
+                        eprintln!("Client error: {}", e);
+                        std::process::exit(1);
+                    }
+                }
+            } else if options.positional.len() < 2 {
+                eprintln!("Usage: {} client [options] <address> <path1> [path2 ...]", args[0]);
+                eprintln!("       {} client --resume [address]", args[0]);
+                eprintln!("Options:");
+                eprintln!("  --include <glob>   only send files matching the pattern (repeatable)");
+                eprintln!("  --exclude <glob>   skip files and directories matching the pattern (repeatable)");
+                eprintln!("  --on-conflict <p>  ask the server to overwrite, skip, rename, fail or keep the newer file");
+                eprintln!("  --compress <c>     compress file data with zstd, lz4, or auto (zstd unless already compressed)");
+                eprintln!("  --level <n>        zstd compression level, 1 to 22 (default 3)");
+                eprintln!("  --zip              send one directory as a zip archive, built as it is sent");
+                eprintln!("  --tar              send one directory as a tar archive, unpacked as it arrives with permissions, times and links");
+                eprintln!("  --links <mode>     preserve symbolic and hard links, follow them (default) or skip them");
+                eprintln!("  --xattrs           send the extended attributes (user.*) of each file as well");
+                eprintln!("  --resume           continue the last interrupted batch, to the address if one is given");
+                eprintln!("  --code <code>      pair with a server started with --code");
+                eprintln!("  --token <token>    access token for a server started with --tokens (or set STREAMLINE_TOKEN)");
+                eprintln!("  --pin <fp>         only connect if the server's certificate has this fingerprint");
+                eprintln!("  --insecure         connect without TLS, to a server started with --insecure");
+                return;
+            } else {
+                let collected = walk::Filters::new(options.values("--include"), options.values("--exclude"))
+                    .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidInput, e))
+                    .and_then(|filters| match archive {
+                        Some(ArchiveFormat::Tar) => walk::collect_tree(&options.positional[1..], &filters),
+                        _ => walk::collect_entries(&options.positional[1..], &filters, links),
+                    });
+                match collected {
+                    Ok(entries) => (options.positional[0].clone(), entries, None),
+                    Err(e) => {
+                        eprintln!("Client error: {}", e);
+                        std::process::exit(1);
+                    }
+                }
+            };
+            let sent = match archive {
+                Some(format) => client::send_archive(&address, &connect_options, entries, format, config).await,
+                None => client::send_files(&address, &connect_options, entries, config, resumed).await,
+            };
+            if let Err(e) = sent {
+                eprintln!("Client error: {}", e);
+                std::process::exit(1);
+            }
+        }
+        "get" => {
+            let parsed = cli::Options::parse(&args[2..], &["--insecure", "--tar", "--no-times", "--no-perms"], &["--on-conflict", "--pin", "--code", "--token"]).and_then(|options| {
+                let on_conflict = match options.value("--on-conflict") {
+                    Some(policy) => ConflictPolicy::parse(policy)?,
+                    None => ConflictPolicy::Overwrite,
+                };
+                let connect_options = connect_options(&options)?;
+                Ok((options, connect_options, on_conflict))
+            });
+            let (options, connect_options, on_conflict) = match parsed {
+                Ok(parsed) => parsed,
+                Err(e) => {
+                    eprintln!("Client error: {}", e);
+                    std::process::exit(1);
+                }
+            };
+            if options.positional.len() < 2 || options.positional.len() > 3 {
+                eprintln!("Usage: {} get [options] <address> <remote path> [local directory]", args[0]);
+                eprintln!("Options:");
+                eprintln!("  --on-conflict <p>  overwrite, skip, rename, fail or keep the newer of existing local files");
+                eprintln!("  --tar              download as a tar archive, unpacked as it arrives with permissions, times and links");
+                eprintln!("  --no-times         don't keep the modification and access times of downloaded files");
+                eprintln!("  --no-perms         don't keep the permissions of downloaded files");
+                eprintln!("  --code <code>      pair with a server started with --code");
+                eprintln!("  --token <token>    access token for a server started with --tokens (or set STREAMLINE_TOKEN)");
+                eprintln!("  --pin <fp>         only connect if the server's certificate has this fingerprint");
+                eprintln!("  --insecure         connect without TLS, to a server started with --insecure");
                 return;
             }
-            let address = args[2].clone();
-            let file_paths: Vec<String> = args[3..].to_vec();
-            if let Err(e) = send_files(&address, file_paths).await {
+            let local_dir = options.positional.get(2).map(PathBuf::from).unwrap_or_default();
+            let archive = options.has("--tar").then_some(ArchiveFormat::Tar);
+            if let Err(e) = client::fetch_files(&options.positional[0], &connect_options, &options.positional[1], local_dir, on_conflict, archive, preserve(&options)).await {
                 eprintln!("Client error: {}", e);
+                std::process::exit(1);
+            }
+        }
+        "ls" => {
+            let parsed = cli::Options::parse(&args[2..], &["--json", "--hash", "--insecure"], &["--pin", "--code", "--token"]).and_then(|options| {
+                let connect_options = connect_options(&options)?;
+                Ok((options, connect_options))
+            });
+            let (options, connect_options) = match parsed {
+                Ok(parsed) => parsed,
+                Err(e) => {
+                    eprintln!("Client error: {}", e);
+                    std::process::exit(1);
+                }
+            };
+            if options.positional.is_empty() || options.positional.len() > 2 {
+                eprintln!("Usage: {} ls [options] <address> [remote path]", args[0]);
+                eprintln!("Options:");
+                eprintln!("  --hash        include the SHA-256 of every file");
+                eprintln!("  --json        print the listing as JSON");
+                eprintln!("  --code <code> pair with a server started with --code");
+                eprintln!("  --token <t>   access token for a server started with --tokens (or set STREAMLINE_TOKEN)");
+                eprintln!("  --pin <fp>    only connect if the server's certificate has this fingerprint");
+                eprintln!("  --insecure    connect without TLS, to a server started with --insecure");
+                return;
+            }
+            let path = options.positional.get(1).map(String::as_str).unwrap_or_default();
+            if let Err(e) = client::list_files(&options.positional[0], &connect_options, path, options.has("--hash"), options.has("--json")).await {
+                eprintln!("Client error: {}", e);
+                std::process::exit(1);
+            }
+        }
+        "rm" => {
+            let parsed = cli::Options::parse(&args[2..], &["--insecure"], &["--pin", "--code", "--token"]).and_then(|options| {
+                let connect_options = connect_options(&options)?;
+                Ok((options, connect_options))
+            });
+            let (options, connect_options) = match parsed {
+                Ok(parsed) => parsed,
+                Err(e) => {
+                    eprintln!("Client error: {}", e);
+                    std::process::exit(1);
+                }
+            };
+            if options.positional.len() != 2 {
+                eprintln!("Usage: {} rm [options] <address> <remote path>", args[0]);
+                eprintln!("Deletes a file, or a directory and everything in it, from the server's output directory.");
+                eprintln!("Options:");
+                eprintln!("  --token <t>   access token allowing delete (or set STREAMLINE_TOKEN)");
+                eprintln!("  --code <code> pair with a server started with --code");
+                eprintln!("  --pin <fp>    only connect if the server's certificate has this fingerprint");
+                eprintln!("  --insecure    connect without TLS, to a server started with --insecure");
+                return;
+            }
+            if let Err(e) = client::delete_files(&options.positional[0], &connect_options, &options.positional[1]).await {
+                eprintln!("Client error: {}", e);
+                std::process::exit(1);
+            }
+        }
+        "discover" => {
+            let options = match cli::Options::parse(&args[2..], &["--json"], &[]) {
+                Ok(options) if options.positional.is_empty() => options,
+                Ok(_) => {
+                    eprintln!("Usage: {} discover [--json]", args[0]);
+                    return;
+                }
+                Err(e) => {
+                    eprintln!("Client error: {}", e);
+                    std::process::exit(1);
+                }
+            };
+            match discovery::discover(None).await {
+                Ok(servers) if options.has("--json") => discovery::print_json(&servers),
+                Ok(servers) if servers.is_empty() => eprintln!("No servers answered on the local network."),
+                Ok(servers) => discovery::print_table(&servers),
+                Err(e) => {
+                    eprintln!("Client error: {}", e);
+                    std::process::exit(1);
+                }
             }
         }
         _ => {
-            eprintln!("Invalid mode. Use 'server' or 'client'.");
+            eprintln!("Invalid mode. Use 'server', 'client', 'get', 'ls', 'rm' or 'discover'.");
         }
     }
 }
\ No newline at end of file
@@ -0,0 +1,134 @@
+use std::fs::{File, FileTimes};
+use std::path::Path;
+use std::time::SystemTime;
+
+use crate::protocol::FileHeader;
+
+/// Most bytes of extended attributes sent with one file, which keeps its
+/// header well inside a frame.
+const MAX_XATTR_BYTES: usize = 256 * 1024;
+
+/// Only attributes in this namespace are sent or applied. The others hold
+/// security labels, capabilities and ACLs, which are not for a peer to set.
+const XATTR_NAMESPACE: &str = "user.";
+
+/// Which of the attributes that come with a file the receiver keeps, all of
+/// them unless turned off with `--no-times`, `--no-perms` or `--no-xattrs`.
+#[derive(Clone, Copy)]
+pub struct Preserve {
+    pub times: bool,
+    pub permissions: bool,
+    pub xattrs: bool,
+}
+
+/// The permission bits of a file, for the receiver to apply. Only Unix has them.
+pub fn mode(metadata: &std::fs::Metadata) -> Option<u32> {
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        Some(metadata.permissions().mode() & 0o7777)
+    }
+    #[cfg(not(unix))]
+    {
+        let _ = metadata;
+        None
+    }
+}
+
+/// Reads the extended attributes of `path` in the user namespace. A file
+/// system without them simply has none.
+pub fn read_xattrs(path: &Path) -> Vec<(String, Vec<u8>)> {
+    let mut xattrs = Vec::new();
+    #[cfg(unix)]
+    {
+        let Ok(names) = xattr::list(path) else {
+            return xattrs;
+        };
+        let mut total = 0;
+        for name in names {
+            let Some(name) = name.to_str().filter(|name| name.starts_with(XATTR_NAMESPACE)) else {
+                continue;
+            };
+            let Ok(Some(value)) = xattr::get(path, name) else {
+                continue;
+            };
+            total += name.len() + value.len();
+            if total > MAX_XATTR_BYTES {
+                say_err!("Warning: {:?} has more than {} bytes of extended attributes; sending only some", path, MAX_XATTR_BYTES);
+                break;
+            }
+            xattrs.push((name.to_string(), value));
+        }
+    }
+    #[cfg(not(unix))]
+    let _ = path;
+    xattrs
+}
+
+/// Gives the file at `path` the times, permissions and extended attributes in
+/// `header`, as far as `preserve` allows. The data has arrived intact by now,
+/// so anything the file system can't store is only warned about.
+pub fn apply(path: &Path, header: &FileHeader, preserve: Preserve) {
+    let name = &header.name;
+    if preserve.xattrs {
+        for (key, value) in header.xattrs.iter().filter(|(key, _)| key.starts_with(XATTR_NAMESPACE)) {
+            if let Err(e) = set_xattr(path, key, value) {
+                say_err!("Warning: could not keep extended attribute {} of {:?}: {}", key, name, e);
+                break;
+            }
+        }
+    }
+    if preserve.times {
+        if let Err(e) = set_times(path, header.mtime, header.atime) {
+            say_err!("Warning: could not keep the times of {:?}: {}", name, e);
+        }
+    }
+    // Last, since it may take away the permission to change the rest.
+    if let Some(mode) = header.mode.filter(|_| preserve.permissions) {
+        if let Err(e) = set_mode(path, mode) {
+            say_err!("Warning: could not keep the permissions of {:?}: {}", name, e);
+        }
+    }
+}
+
+/// Sets whichever of the times are known. Where the access time can't be
+/// stored, the modification time alone is.
+fn set_times(path: &Path, mtime: Option<SystemTime>, atime: Option<SystemTime>) -> std::io::Result<()> {
+    let Some(mtime) = mtime else {
+        return Ok(());
+    };
+    let file = File::options().write(true).open(path)?;
+    let times = FileTimes::new().set_modified(mtime);
+    match atime {
+        Some(atime) => file.set_times(times.set_accessed(atime)).or_else(|_| file.set_times(times)),
+        None => file.set_times(times),
+    }
+}
+
+/// Applies permission bits other than setuid, setgid and sticky. Elsewhere the
+/// closest there is, read-only for a file nobody may write, is applied.
+pub fn set_mode(path: &Path, mode: u32) -> std::io::Result<()> {
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        std::fs::set_permissions(path, std::fs::Permissions::from_mode(mode & 0o777))
+    }
+    #[cfg(not(unix))]
+    {
+        let mut permissions = std::fs::metadata(path)?.permissions();
+        permissions.set_readonly(mode & 0o222 == 0);
+        std::fs::set_permissions(path, permissions)
+    }
+}
+
+fn set_xattr(path: &Path, key: &str, value: &[u8]) -> std::io::Result<()> {
+    #[cfg(unix)]
+    {
+        xattr::set(path, key, value)
+    }
+    #[cfg(not(unix))]
+    {
+        let _ = (path, key, value);
+        Err(std::io::Error::new(std::io::ErrorKind::Unsupported, "extended attributes are only kept on Unix"))
+    }
+}
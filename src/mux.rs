@@ -0,0 +1,408 @@
+use std::collections::HashMap;
+use std::io::{Error, ErrorKind};
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicU32, Ordering};
+use tokio::io::{AsyncRead, AsyncWrite, AsyncWriteExt};
+use tokio::runtime::Handle;
+use tokio::sync::mpsc::error::TrySendError;
+use tokio::sync::{mpsc, Mutex as AsyncMutex, Semaphore};
+use tokio::task::JoinHandle;
+
+use crate::pairing::SessionKeys;
+use crate::protocol::{self, Message, StatusCode};
+
+/// Frames queued for the connection, across all streams.
+const OUTGOING_QUEUE: usize = 16;
+/// Frames one end may send on a stream before the other end has taken them
+/// from it. A peer that sends past that has only its stream reset, so that a
+/// stream nobody is reading can never hold up the rest of the session.
+const STREAM_WINDOW: usize = 8;
+/// The control stream carries whole manifests and listings, a frame per
+/// name, so it is allowed much further ahead.
+const CONTROL_WINDOW: usize = 1024;
+/// Opened streams waiting to be accepted, beyond which new ones are reset.
+const ACCEPT_QUEUE: usize = 16;
+
+/// Stream id reserved for messages about the session as a whole.
+const CONTROL_STREAM: u32 = 0;
+
+/// Where frames for a stream go, and what this end may still send on it.
+struct Route {
+    frames: mpsc::Sender<Message>,
+    credit: Arc<Semaphore>,
+}
+
+type Routes = Arc<Mutex<HashMap<u32, Route>>>;
+
+/// Frames are encoded before they are queued, so that a message that can't be
+/// sent is refused to whoever sent it rather than ending the session.
+type Outgoing = mpsc::Sender<Vec<u8>>;
+
+/// One end of a session: many files, each on its own stream id, sharing one
+/// connection. Either side can open streams; the other side accepts them.
+pub struct Session {
+    outgoing: Outgoing,
+    routes: Routes,
+    next_id: AtomicU32,
+    accepted: AsyncMutex<mpsc::Receiver<(Stream, Message)>>,
+    control: AsyncMutex<Window>,
+    control_credit: Arc<Semaphore>,
+    /// The optional protocol features both peers support, and whether the
+    /// peer keeps an index of its files by content.
+    capabilities: u32,
+    reader_task: JoinHandle<()>,
+    writer_task: JoinHandle<()>,
+}
+
+/// A single file's conversation within a session.
+pub struct Stream {
+    id: u32,
+    outgoing: Outgoing,
+    incoming: Window,
+    credit: Arc<Semaphore>,
+    routes: Routes,
+}
+
+/// The receiving end of a stream, which hands credit back to the peer as the
+/// frames it sent are taken.
+struct Window {
+    id: u32,
+    frames: mpsc::Receiver<Message>,
+    /// Frames taken that the peer hasn't been given credit for yet.
+    taken: usize,
+    size: usize,
+    outgoing: Outgoing,
+}
+
+impl Window {
+    async fn recv(&mut self) -> tokio::io::Result<Message> {
+        let message = self.frames.recv().await.ok_or_else(closed)?;
+        self.took().await?;
+        Ok(message)
+    }
+
+    /// Counts a frame as taken, giving the peer credit for half a window at a time.
+    async fn took(&mut self) -> tokio::io::Result<()> {
+        self.taken += 1;
+        if self.taken >= self.size / 2 {
+            let frame = protocol::encode_message(self.id, &Message::Credit { frames: self.taken as u32 })?;
+            self.outgoing.send(frame).await.map_err(|_| closed())?;
+            self.taken = 0;
+        }
+        Ok(())
+    }
+}
+
+fn closed() -> Error {
+    Error::new(ErrorKind::BrokenPipe, "connection closed")
+}
+
+/// Queues `frame` once the peer has room for it.
+async fn send_with_credit(outgoing: &Outgoing, credit: &Semaphore, frame: Vec<u8>) -> tokio::io::Result<()> {
+    let permit = credit.acquire().await.map_err(|_| closed())?;
+    outgoing.send(frame).await.map_err(|_| closed())?;
+    permit.forget();
+    Ok(())
+}
+
+/// Queues `frame` without waiting for room, for the reader and for `Drop`,
+/// which must never wait on the connection.
+fn queue(outgoing: &Outgoing, frame: Vec<u8>) {
+    if let Err(TrySendError::Full(frame)) = outgoing.try_send(frame) {
+        if let Ok(runtime) = Handle::try_current() {
+            let outgoing = outgoing.clone();
+            runtime.spawn(async move {
+                let _ = outgoing.send(frame).await;
+            });
+        }
+    }
+}
+
+/// Gives up on a stream the peer has broken the rules on, telling it why; the
+/// rest of the session carries on.
+fn reset(routes: &Routes, outgoing: &Outgoing, id: u32, reason: &str) {
+    if let Some(route) = routes.lock().unwrap().remove(&id) {
+        route.credit.close();
+    }
+    let status = Message::Status { code: StatusCode::Rejected, message: reason.to_string() };
+    if let Ok(frame) = protocol::encode_message(id, &status) {
+        queue(outgoing, frame);
+    }
+}
+
+/// Starts the tasks that write queued frames to `writer` and route frames read
+/// from `reader` to their streams. With `keys`, every frame is encrypted.
+pub fn start<R, W>(mut reader: R, mut writer: W, keys: Option<SessionKeys>, capabilities: u32) -> Session
+where
+    R: AsyncRead + Unpin + Send + 'static,
+    W: AsyncWrite + Unpin + Send + 'static,
+{
+    let (mut sealer, mut opener) = match keys {
+        Some(keys) => (Some(keys.sealer), Some(keys.opener)),
+        None => (None, None),
+    };
+
+    let (outgoing, mut queue) = mpsc::channel::<Vec<u8>>(OUTGOING_QUEUE);
+    let writer_task = tokio::spawn(async move {
+        while let Some(frame) = queue.recv().await {
+            let bytes = match &mut sealer {
+                Some(sealer) => sealer.seal(frame),
+                None => frame,
+            };
+            if writer.write_all(&bytes).await.is_err() {
+                return;
+            }
+        }
+        let _ = writer.shutdown().await;
+    });
+
+    let routes: Routes = Arc::default();
+    let (accept_tx, accepted) = mpsc::channel(ACCEPT_QUEUE);
+    let (control_tx, control) = mpsc::channel(CONTROL_WINDOW);
+    let control_credit = Arc::new(Semaphore::new(CONTROL_WINDOW));
+    let reader_routes = routes.clone();
+    let reader_outgoing = outgoing.clone();
+    let reader_control_credit = control_credit.clone();
+    // Never waits on a stream, only on the connection, so one stream falling
+    // behind leaves every other one, the control stream included, flowing.
+    let reader_task = tokio::spawn(async move {
+        loop {
+            let next = match &mut opener {
+                Some(opener) => match opener.read(&mut reader).await {
+                    Ok(record) => protocol::read_message(&mut record.as_slice()).await,
+                    Err(e) => Err(e),
+                },
+                None => protocol::read_message(&mut reader).await,
+            };
+            let Ok((id, message)) = next else {
+                break;
+            };
+            if let Message::Credit { frames } = message {
+                let credit = match id {
+                    CONTROL_STREAM => Some((reader_control_credit.clone(), CONTROL_WINDOW)),
+                    _ => reader_routes.lock().unwrap().get(&id).map(|route| (route.credit.clone(), STREAM_WINDOW)),
+                };
+                // Never more than a whole window, however much the peer claims.
+                if let Some((credit, window)) = credit {
+                    credit.add_permits((frames as usize).min(window.saturating_sub(credit.available_permits())));
+                }
+                continue;
+            }
+            if id == CONTROL_STREAM {
+                // The session can't go on without its control stream.
+                if control_tx.try_send(message).is_err() {
+                    break;
+                }
+                continue;
+            }
+
+            let route = reader_routes.lock().unwrap().get(&id).map(|route| route.frames.clone());
+            if let Some(route) = route {
+                match route.try_send(message) {
+                    Ok(()) => {}
+                    // A stream that has been dropped no longer wants its frames, for
+                    // example the rest of a body it has already rejected.
+                    Err(TrySendError::Closed(_)) => {
+                        reader_routes.lock().unwrap().remove(&id);
+                    }
+                    Err(TrySendError::Full(_)) => reset(&reader_routes, &reader_outgoing, id, "sent more than the stream's window"),
+                }
+            } else if matches!(message, Message::Header(_) | Message::Directory { .. } | Message::Symlink { .. } | Message::HardLink { .. }) {
+                let (frames, incoming) = mpsc::channel(STREAM_WINDOW);
+                let credit = Arc::new(Semaphore::new(STREAM_WINDOW));
+                reader_routes.lock().unwrap().insert(id, Route { frames, credit: credit.clone() });
+                // The opening message is taken along with the stream.
+                let incoming = Window { id, frames: incoming, taken: 1, size: STREAM_WINDOW, outgoing: reader_outgoing.clone() };
+                let stream = Stream { id, outgoing: reader_outgoing.clone(), incoming, credit, routes: reader_routes.clone() };
+                match accept_tx.try_send((stream, message)) {
+                    Ok(()) => {}
+                    Err(TrySendError::Closed(_)) => break,
+                    Err(TrySendError::Full(_)) => reset(&reader_routes, &reader_outgoing, id, "too many streams opened at once"),
+                }
+            }
+        }
+        // Closing the routes wakes every stream still waiting with "connection closed".
+        reader_control_credit.close();
+        for (_, route) in reader_routes.lock().unwrap().drain() {
+            route.credit.close();
+        }
+    });
+
+    Session {
+        outgoing: outgoing.clone(),
+        routes,
+        next_id: AtomicU32::new(1),
+        accepted: AsyncMutex::new(accepted),
+        control: AsyncMutex::new(Window { id: CONTROL_STREAM, frames: control, taken: 0, size: CONTROL_WINDOW, outgoing }),
+        control_credit,
+        capabilities,
+        reader_task,
+        writer_task,
+    }
+}
+
+impl Session {
+    /// Opens a new stream. Nothing is sent until its first message.
+    pub fn open(&self) -> Stream {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let (frames, incoming) = mpsc::channel(STREAM_WINDOW);
+        let credit = Arc::new(Semaphore::new(STREAM_WINDOW));
+        self.routes.lock().unwrap().insert(id, Route { frames, credit: credit.clone() });
+        let incoming = Window { id, frames: incoming, taken: 0, size: STREAM_WINDOW, outgoing: self.outgoing.clone() };
+        Stream { id, outgoing: self.outgoing.clone(), incoming, credit, routes: self.routes.clone() }
+    }
+
+    pub fn capabilities(&self) -> u32 {
+        self.capabilities
+    }
+
+    /// Waits for the peer to open a stream, returning it with its opening message.
+    pub async fn accept(&self) -> Option<(Stream, Message)> {
+        self.accepted.lock().await.recv().await
+    }
+
+    pub async fn send_control(&self, message: Message) -> tokio::io::Result<()> {
+        let frame = protocol::encode_message(CONTROL_STREAM, &message)?;
+        send_with_credit(&self.outgoing, &self.control_credit, frame).await
+    }
+
+    pub async fn recv_control(&self) -> tokio::io::Result<Message> {
+        self.control.lock().await.recv().await
+    }
+
+    /// Waits until everything queued has been written, then closes the connection.
+    /// Without this, a process that exits right after its last message may never send it.
+    pub async fn close(self) {
+        let Session { outgoing, accepted, control, reader_task, writer_task, .. } = self;
+        // The writer stops once every sender is gone, including the reader's and
+        // those of streams it opened that were never accepted.
+        reader_task.abort();
+        let _ = reader_task.await;
+        drop(accepted);
+        drop(control);
+        drop(outgoing);
+        let _ = writer_task.await;
+    }
+}
+
+impl Stream {
+    pub async fn send(&self, message: Message) -> tokio::io::Result<()> {
+        let frame = protocol::encode_message(self.id, &message)?;
+        send_with_credit(&self.outgoing, &self.credit, frame).await
+    }
+
+    /// Sends `message` unless the peer says something on this stream first, in
+    /// which case that is returned instead and `message` is dropped.
+    pub async fn send_unless_interrupted(&mut self, message: Message) -> tokio::io::Result<Option<Message>> {
+        let frame = protocol::encode_message(self.id, &message)?;
+        tokio::select! {
+            sent = send_with_credit(&self.outgoing, &self.credit, frame) => sent.map(|_| None),
+            reply = self.incoming.recv() => reply.map(Some),
+        }
+    }
+
+    pub async fn recv(&mut self) -> tokio::io::Result<Message> {
+        self.incoming.recv().await
+    }
+
+    pub async fn send_status(&self, code: StatusCode, message: impl Into<String>) -> tokio::io::Result<()> {
+        self.send(Message::Status { code, message: message.into() }).await
+    }
+
+    /// Reads the receiver's verdict on a transfer and turns anything but `Ok` into
+    /// an error. An `Ok` status may carry a note, such as the name a file was saved under.
+    pub async fn recv_status(&mut self) -> tokio::io::Result<String> {
+        match self.recv().await? {
+            Message::Status { code: StatusCode::Ok, message } => Ok(message),
+            Message::Status { code, message } => Err(protocol::status_error(code, &message)),
+            other => Err(protocol::unexpected(&other)),
+        }
+    }
+}
+
+impl Drop for Stream {
+    fn drop(&mut self) {
+        // Whatever the peer still sends here is thrown away, so it gets a whole
+        // window to finish with rather than waiting on credit that never comes.
+        if self.routes.lock().unwrap().remove(&self.id).is_some() {
+            if let Ok(frame) = protocol::encode_message(self.id, &Message::Credit { frames: STREAM_WINDOW as u32 }) {
+                queue(&self.outgoing, frame);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocol::FileHeader;
+    use std::time::Duration;
+    use tokio::io::{duplex, split};
+    use tokio::time::timeout;
+
+    fn header(name: &str) -> Message {
+        Message::Header(FileHeader {
+            name: name.to_string(),
+            size: None,
+            mtime: None,
+            on_conflict: None,
+            archive: None,
+            atime: None,
+            mode: None,
+            xattrs: Vec::new(),
+            sha256: None,
+        })
+    }
+
+    #[tokio::test]
+    async fn a_stalled_stream_holds_up_only_itself() {
+        let (near, far) = duplex(64 * 1024);
+        let (reader, writer) = split(near);
+        let sender = Arc::new(start(reader, writer, None, 0));
+        let (reader, writer) = split(far);
+        let receiver = start(reader, writer, None, 0);
+
+        let stalled = sender.open();
+        stalled.send(header("stalled")).await.unwrap();
+        let (_unread, _) = receiver.accept().await.unwrap();
+        let flooding = tokio::spawn(async move {
+            for _ in 0..STREAM_WINDOW * 4 {
+                stalled.send(Message::Data(vec![0; 1024])).await.unwrap();
+            }
+        });
+
+        let mut flowing = sender.open();
+        flowing.send(header("flowing")).await.unwrap();
+        let (mut accepted, _) = timeout(Duration::from_secs(5), receiver.accept()).await.unwrap().unwrap();
+        for _ in 0..STREAM_WINDOW * 4 {
+            flowing.send(Message::Data(b"data".to_vec())).await.unwrap();
+            assert!(matches!(timeout(Duration::from_secs(5), accepted.recv()).await.unwrap().unwrap(), Message::Data(_)));
+        }
+        accepted.send_status(StatusCode::Ok, "").await.unwrap();
+        assert_eq!(flowing.recv_status().await.unwrap(), "");
+
+        sender.send_control(Message::ManifestEnd).await.unwrap();
+        assert!(matches!(timeout(Duration::from_secs(5), receiver.recv_control()).await.unwrap().unwrap(), Message::ManifestEnd));
+        assert!(!flooding.is_finished());
+        flooding.abort();
+    }
+
+    #[tokio::test]
+    async fn a_peer_sending_past_the_window_has_only_that_stream_reset() {
+        let (near, far) = duplex(64 * 1024);
+        let (mut peer_reader, mut peer_writer) = split(near);
+        let (reader, writer) = split(far);
+        let session = start(reader, writer, None, 0);
+
+        peer_writer.write_all(&protocol::encode_message(1, &header("greedy")).unwrap()).await.unwrap();
+        for _ in 0..STREAM_WINDOW + 2 {
+            peer_writer.write_all(&protocol::encode_message(1, &Message::Data(b"data".to_vec())).unwrap()).await.unwrap();
+        }
+        peer_writer.write_all(&protocol::encode_message(CONTROL_STREAM, &Message::ManifestEnd).unwrap()).await.unwrap();
+
+        let (id, message) = timeout(Duration::from_secs(5), protocol::read_message(&mut peer_reader)).await.unwrap().unwrap();
+        assert_eq!(id, 1);
+        assert!(matches!(message, Message::Status { code: StatusCode::Rejected, .. }));
+        assert!(matches!(timeout(Duration::from_secs(5), session.recv_control()).await.unwrap().unwrap(), Message::ManifestEnd));
+    }
+}
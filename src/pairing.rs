@@ -0,0 +1,291 @@
+use std::collections::HashMap;
+use std::io::{Error, ErrorKind};
+use std::net::{IpAddr, Ipv6Addr};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use ring::aead::{Aad, LessSafeKey, Nonce, UnboundKey, CHACHA20_POLY1305};
+use ring::hkdf::{Salt, HKDF_SHA256};
+use ring::rand::{SecureRandom, SystemRandom};
+use spake2::{Ed25519Group, Identity, Password, Spake2};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+use crate::protocol::{HANDSHAKE_TIMEOUT, MAX_FRAME_SIZE};
+
+/// 256 short, distinct words, so each word in a code carries one byte.
+const WORDS: &str = include_str!("wordlist.txt");
+
+/// Failed attempts tolerated from one address before it has to wait out
+/// `LOCKOUT`, which keeps online guessing hopeless without letting anyone
+/// lock everybody else out.
+const MAX_FAILED_ATTEMPTS: u32 = 3;
+const LOCKOUT: Duration = Duration::from_secs(10 * 60);
+/// Failed attempts tolerated from everyone together before the code is
+/// replaced, so that guessing from many addresses gets nowhere either.
+const MAX_FAILED_IN_TOTAL: u32 = 20;
+
+const CONTEXT: &[u8] = b"streamline pairing v1";
+const CONFIRMATION: &[u8] = b"streamline pairing confirmation";
+const TAG_LEN: usize = 16;
+
+#[derive(Clone, Copy)]
+pub enum Side {
+    Client,
+    Server,
+}
+
+/// The code a server accepts clients with, such as `7-crossword-banana`.
+pub struct PairingCode {
+    state: Mutex<State>,
+}
+
+struct State {
+    code: String,
+    /// Failed attempts by network, and when the last of them was.
+    failures: HashMap<IpAddr, (u32, Instant)>,
+    /// Failed attempts at this code from anywhere.
+    failed_in_total: u32,
+}
+
+/// What a failed attempt at the code leads to.
+#[derive(Debug, PartialEq, Eq)]
+pub enum Failed {
+    Counted,
+    /// The address has to wait out `LOCKOUT`.
+    LockedOut,
+    /// Too many attempts failed altogether, so this new code replaces the old one.
+    Replaced(String),
+}
+
+impl PairingCode {
+    pub fn generate() -> Self {
+        PairingCode { state: Mutex::new(State { code: random_code(), failures: HashMap::new(), failed_in_total: 0 }) }
+    }
+
+    pub fn current(&self) -> String {
+        self.state.lock().unwrap().code.clone()
+    }
+
+    /// How long `ip` has to wait before it may try again, if it failed too often.
+    pub fn locked_out(&self, ip: IpAddr) -> Option<Duration> {
+        let state = self.state.lock().unwrap();
+        let (failed, last) = state.failures.get(&network(ip))?;
+        (*failed >= MAX_FAILED_ATTEMPTS).then(|| LOCKOUT.saturating_sub(last.elapsed())).filter(|wait| !wait.is_zero())
+    }
+
+    /// Records a failed attempt from `ip`.
+    pub fn failed(&self, ip: IpAddr) -> Failed {
+        let now = Instant::now();
+        let mut state = self.state.lock().unwrap();
+        state.failed_in_total += 1;
+        if state.failed_in_total >= MAX_FAILED_IN_TOTAL {
+            state.code = random_code();
+            state.failed_in_total = 0;
+            return Failed::Replaced(state.code.clone());
+        }
+        // Attempts further apart than the lockout are not counted together.
+        state.failures.retain(|_, (_, last)| now - *last < LOCKOUT);
+        let (failed, last) = state.failures.entry(network(ip)).or_insert((0, now));
+        *failed += 1;
+        *last = now;
+        if *failed >= MAX_FAILED_ATTEMPTS { Failed::LockedOut } else { Failed::Counted }
+    }
+
+    /// Forgets the failed attempts from `ip`, once it has got the code right.
+    pub fn succeeded(&self, ip: IpAddr) {
+        self.state.lock().unwrap().failures.remove(&network(ip));
+    }
+}
+
+/// What attempts from `ip` are counted under: the address itself, or for
+/// IPv6 its /64, since a host is usually free to pick any address in that.
+fn network(ip: IpAddr) -> IpAddr {
+    match ip.to_canonical() {
+        IpAddr::V6(ip) => IpAddr::V6(Ipv6Addr::from(u128::from(ip) & !(u64::MAX as u128))),
+        ip => ip,
+    }
+}
+
+fn random_code() -> String {
+    let words: Vec<&str> = WORDS.lines().collect();
+    let mut random = [0u8; 4];
+    SystemRandom::new().fill(&mut random).expect("no system random number generator");
+    let number = u16::from_be_bytes([random[0], random[1]]) % 99 + 1;
+    format!("{}-{}-{}", number, words[random[2] as usize], words[random[3] as usize])
+}
+
+/// Keys for the encrypted frames of a paired session, one per direction.
+pub struct SessionKeys {
+    pub sealer: Sealer,
+    pub opener: Opener,
+}
+
+/// Runs SPAKE2 with the peer over `conn` and derives the session keys from it.
+/// Both sides then prove they hold the same keys, so a wrong code fails here
+/// rather than as a garbled first frame.
+pub async fn pair<S>(conn: &mut S, code: &str, side: Side) -> std::io::Result<SessionKeys>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let code = code.trim().to_lowercase();
+    let (spake, outbound) = Spake2::<Ed25519Group>::start_symmetric(&Password::new(code.as_bytes()), &Identity::new(CONTEXT));
+    let mut message = vec![outbound.len() as u8];
+    message.extend_from_slice(&outbound);
+    conn.write_all(&message).await?;
+
+    let inbound = tokio::time::timeout(HANDSHAKE_TIMEOUT, async {
+        let len = conn.read_u8().await?;
+        let mut inbound = vec![0u8; len as usize];
+        conn.read_exact(&mut inbound).await?;
+        Ok::<_, Error>(inbound)
+    });
+    let inbound = match inbound.await.map_err(|_| Error::new(ErrorKind::TimedOut, "peer did not answer the pairing request"))? {
+        Ok(inbound) => inbound,
+        // A server refusing to pair just hangs up, having nothing it could say unencrypted.
+        Err(e) if e.kind() == ErrorKind::UnexpectedEof && matches!(side, Side::Client) => {
+            return Err(Error::new(
+                ErrorKind::PermissionDenied,
+                "the server hung up instead of pairing; after too many wrong codes it refuses an address for a while",
+            ));
+        }
+        Err(e) => return Err(e),
+    };
+    let key = spake.finish(&inbound).map_err(|e| Error::new(ErrorKind::InvalidData, format!("pairing failed: {}", e)))?;
+
+    let secret = Salt::new(HKDF_SHA256, CONTEXT).extract(&key);
+    let derive = |label: &[u8]| {
+        let info = [label];
+        let okm = secret.expand(&info, &CHACHA20_POLY1305).expect("valid key length");
+        LessSafeKey::new(UnboundKey::from(okm))
+    };
+    let (sending, receiving): (&[u8], &[u8]) = match side {
+        Side::Client => (b"client to server", b"server to client"),
+        Side::Server => (b"server to client", b"client to server"),
+    };
+    let mut keys = SessionKeys {
+        sealer: Sealer { key: derive(sending), counter: 0 },
+        opener: Opener { key: derive(receiving), counter: 0 },
+    };
+
+    conn.write_all(&keys.sealer.seal(CONFIRMATION.to_vec())).await?;
+    let confirmation = tokio::time::timeout(HANDSHAKE_TIMEOUT, keys.opener.read(conn)).await;
+    match confirmation {
+        Ok(Ok(confirmation)) if confirmation == CONFIRMATION => Ok(keys),
+        Ok(Err(e)) if e.kind() != ErrorKind::InvalidData => Err(e),
+        _ => Err(Error::new(ErrorKind::PermissionDenied, "the pairing code does not match")),
+    }
+}
+
+/// Encrypts outgoing records. Each is numbered, so none can be dropped,
+/// replayed or reordered without the peer noticing.
+pub struct Sealer {
+    key: LessSafeKey,
+    counter: u64,
+}
+
+impl Sealer {
+    /// Returns `plaintext` sealed into a record: a big-endian u32 length, then
+    /// the ciphertext and its tag.
+    pub fn seal(&mut self, mut plaintext: Vec<u8>) -> Vec<u8> {
+        let nonce = nonce(self.counter);
+        self.counter += 1;
+        self.key
+            .seal_in_place_append_tag(nonce, Aad::empty(), &mut plaintext)
+            .expect("record within the size limit");
+        let mut record = Vec::with_capacity(4 + plaintext.len());
+        record.extend_from_slice(&(plaintext.len() as u32).to_be_bytes());
+        record.extend_from_slice(&plaintext);
+        record
+    }
+}
+
+pub struct Opener {
+    key: LessSafeKey,
+    counter: u64,
+}
+
+impl Opener {
+    /// Reads and decrypts the next record.
+    pub async fn read<R>(&mut self, reader: &mut R) -> std::io::Result<Vec<u8>>
+    where
+        R: AsyncRead + Unpin,
+    {
+        let len = reader.read_u32().await? as usize;
+        // Room for the largest frame, its header and the tag.
+        if len > MAX_FRAME_SIZE + 9 + TAG_LEN {
+            return Err(Error::new(ErrorKind::InvalidData, format!("record of {} bytes is too large", len)));
+        }
+        let mut record = vec![0u8; len];
+        reader.read_exact(&mut record).await?;
+
+        let nonce = nonce(self.counter);
+        self.counter += 1;
+        let plaintext_len = self
+            .key
+            .open_in_place(nonce, Aad::empty(), &mut record)
+            .map_err(|_| Error::new(ErrorKind::InvalidData, "record failed authentication"))?
+            .len();
+        record.truncate(plaintext_len);
+        Ok(record)
+    }
+}
+
+fn nonce(counter: u64) -> Nonce {
+    let mut nonce = [0u8; 12];
+    nonce[4..].copy_from_slice(&counter.to_be_bytes());
+    Nonce::assume_unique_for_key(nonce)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::Ipv4Addr;
+    use super::*;
+
+    #[test]
+    fn failures_lock_out_only_the_address_they_came_from() {
+        let code = PairingCode::generate();
+        let (guesser, other) = (IpAddr::from(Ipv4Addr::new(10, 0, 0, 9)), IpAddr::from(Ipv4Addr::new(10, 0, 0, 2)));
+        assert_eq!(code.failed(guesser), Failed::Counted);
+        assert_eq!(code.failed(guesser), Failed::Counted);
+        assert!(code.locked_out(guesser).is_none());
+        assert_eq!(code.failed(guesser), Failed::LockedOut);
+        assert!(code.locked_out(guesser).is_some_and(|wait| wait <= LOCKOUT));
+        assert!(code.locked_out(other).is_none());
+        // The same address written as IPv4-mapped IPv6 is the same address.
+        assert!(code.locked_out("::ffff:10.0.0.9".parse().unwrap()).is_some());
+    }
+
+    #[test]
+    fn ipv6_addresses_are_locked_out_by_their_64() {
+        let code = PairingCode::generate();
+        for host in 1..=MAX_FAILED_ATTEMPTS {
+            code.failed(format!("2001:db8:1:2::{}", host).parse().unwrap());
+        }
+        assert!(code.locked_out("2001:db8:1:2:ffff::1".parse().unwrap()).is_some());
+        assert!(code.locked_out("2001:db8:1:3::1".parse().unwrap()).is_none());
+    }
+
+    #[test]
+    fn too_many_failures_from_everywhere_replace_the_code() {
+        let code = PairingCode::generate();
+        let first = code.current();
+        for host in 1..MAX_FAILED_IN_TOTAL {
+            assert!(!matches!(code.failed(IpAddr::from(Ipv4Addr::new(10, 0, 1, host as u8))), Failed::Replaced(_)));
+        }
+        let Failed::Replaced(replacement) = code.failed(IpAddr::from(Ipv4Addr::new(10, 0, 2, 1))) else {
+            panic!("the code was not replaced");
+        };
+        assert_eq!(replacement, code.current());
+        assert_ne!(replacement, first);
+    }
+
+    #[test]
+    fn getting_the_code_right_forgets_earlier_failures() {
+        let code = PairingCode::generate();
+        let peer = IpAddr::from(Ipv4Addr::LOCALHOST);
+        code.failed(peer);
+        code.failed(peer);
+        code.succeeded(peer);
+        assert_eq!(code.failed(peer), Failed::Counted);
+        assert!(code.locked_out(peer).is_none());
+    }
+}
@@ -0,0 +1,195 @@
+use std::path::{Path, PathBuf};
+
+/// Device names Windows reserves in every directory, with or without an extension.
+const RESERVED_NAMES: [&str; 22] = [
+    "CON", "PRN", "AUX", "NUL",
+    "COM1", "COM2", "COM3", "COM4", "COM5", "COM6", "COM7", "COM8", "COM9",
+    "LPT1", "LPT2", "LPT3", "LPT4", "LPT5", "LPT6", "LPT7", "LPT8", "LPT9",
+];
+
+/// Turns a `/`-separated name received from a peer into a relative path that
+/// cannot leave the directory it is joined onto, on any platform.
+///
+/// Rather than trying to clean up a suspicious name, anything that could be
+/// interpreted as more than a plain relative path is refused.
+pub fn sanitize(name: &str) -> Result<PathBuf, String> {
+    if name.is_empty() {
+        return Err("empty path".to_string());
+    }
+    if name.contains('\0') {
+        return Err("path contains a NUL byte".to_string());
+    }
+    if name.chars().any(char::is_control) {
+        return Err("path contains control characters".to_string());
+    }
+    if name.starts_with('/') {
+        return Err("absolute paths are not allowed".to_string());
+    }
+    if name.contains('\\') {
+        return Err("backslashes are not allowed in paths".to_string());
+    }
+    if name.contains(':') {
+        return Err("drive letters and ':' are not allowed in paths".to_string());
+    }
+
+    let mut path = PathBuf::new();
+    for component in name.split('/') {
+        match component {
+            "" => return Err("empty path component".to_string()),
+            "." | ".." => return Err(format!("'{}' components are not allowed", component)),
+            _ => {}
+        }
+        // Windows silently drops trailing dots and spaces, which would let
+        // "a." alias "a" or "CON ." reach a device.
+        if component.ends_with('.') || component.ends_with(' ') {
+            return Err(format!("'{}' ends with a dot or space", component));
+        }
+        let stem = component.split('.').next().unwrap_or_default().trim_end();
+        if RESERVED_NAMES.iter().any(|reserved| reserved.eq_ignore_ascii_case(stem)) {
+            return Err(format!("'{}' is a reserved device name", component));
+        }
+        path.push(component);
+    }
+    Ok(path)
+}
+
+/// Joins a sanitized relative path onto `root`, refusing to go through any
+/// symbolic link that already exists below the root, since a link would let
+/// the write land outside it.
+pub fn resolve(root: &Path, relative: &Path) -> Result<PathBuf, String> {
+    let mut path = root.to_path_buf();
+    for component in relative.components() {
+        path.push(component);
+        if path.symlink_metadata().is_ok_and(|metadata| metadata.file_type().is_symlink()) {
+            return Err(format!("{:?} is a symbolic link", path));
+        }
+    }
+    Ok(path)
+}
+
+/// Whether a symbolic link at `link`, relative to the destination, pointing
+/// at `target` resolves to somewhere inside the destination.
+///
+/// `..` is only allowed ahead of any other name in the target: the directories
+/// above a link are real ones, but a name after it may itself be a link, and
+/// `..` from there would go up from wherever that leads.
+pub fn link_stays_within(link: &Path, target: &str) -> bool {
+    if target.is_empty() || target.starts_with('/') || target.contains('\\') || target.contains(':') {
+        return false;
+    }
+    let mut depth = link.components().count() - 1;
+    let mut descended = false;
+    for component in target.split('/') {
+        match component {
+            "" | "." => {}
+            ".." if descended => return false,
+            ".." => match depth.checked_sub(1) {
+                Some(up) => depth = up,
+                None => return false,
+            },
+            _ => descended = true,
+        }
+    }
+    true
+}
+
+/// Creates a symbolic link at `path` pointing at `target`. Only possible on Unix.
+#[cfg(unix)]
+pub fn symlink(target: &str, path: &Path) -> std::io::Result<()> {
+    std::os::unix::fs::symlink(target, path)
+}
+
+#[cfg(not(unix))]
+pub fn symlink(_target: &str, _path: &Path) -> std::io::Result<()> {
+    Err(std::io::Error::new(std::io::ErrorKind::Unsupported, "symbolic links can only be created on Unix"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// An empty directory of its own for a test to write in.
+    fn scratch(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("streamline-paths-{}-{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn sanitize_accepts_plain_relative_names() {
+        assert_eq!(sanitize("a.txt").unwrap(), PathBuf::from("a.txt"));
+        assert_eq!(sanitize("photos/2024/beach.jpg").unwrap(), Path::new("photos").join("2024").join("beach.jpg"));
+        assert_eq!(sanitize("..hidden").unwrap(), PathBuf::from("..hidden"));
+    }
+
+    #[test]
+    fn sanitize_refuses_parent_and_current_components() {
+        for name in ["..", "../a", "a/../b", "a/..", ".", "./a", "a/./b"] {
+            assert!(sanitize(name).is_err(), "{:?} was accepted", name);
+        }
+    }
+
+    #[test]
+    fn sanitize_refuses_absolute_and_rooted_paths() {
+        for name in ["/etc/passwd", "/", "C:/Windows", "C:file", "\\\\server\\share", "\\a", "a\\..\\b"] {
+            assert!(sanitize(name).is_err(), "{:?} was accepted", name);
+        }
+    }
+
+    #[test]
+    fn sanitize_refuses_names_that_are_not_plain() {
+        for name in ["", "a//b", "a/", "a\0b", "a\x1b[2Jb", "a\nb", "a.", "a ", "CON", "nul.txt", "dir/com1", "lpt9 .log"] {
+            assert!(sanitize(name).is_err(), "{:?} was accepted", name);
+        }
+    }
+
+    #[test]
+    fn resolve_joins_onto_the_root() {
+        let root = scratch("join");
+        assert_eq!(resolve(&root, Path::new("a/b.txt")).unwrap(), root.join("a").join("b.txt"));
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn resolve_refuses_symlinked_parents() {
+        let root = scratch("parents");
+        let outside = scratch("parents-outside");
+        symlink(outside.to_str().unwrap(), &root.join("escape")).unwrap();
+        std::fs::create_dir(root.join("real")).unwrap();
+        symlink("../escape", &root.join("real").join("nested")).unwrap();
+
+        assert!(resolve(&root, Path::new("escape/file")).is_err());
+        assert!(resolve(&root, Path::new("real/nested/file")).is_err());
+        assert!(resolve(&root, Path::new("real/file")).is_ok());
+        std::fs::remove_dir_all(&root).unwrap();
+        std::fs::remove_dir_all(&outside).unwrap();
+    }
+
+    #[test]
+    fn links_within_the_destination_are_allowed() {
+        assert!(link_stays_within(Path::new("link"), "file"));
+        assert!(link_stays_within(Path::new("link"), "dir/file"));
+        assert!(link_stays_within(Path::new("a/b/link"), "../../file"));
+        assert!(link_stays_within(Path::new("a/link"), "../b/./file"));
+        assert!(link_stays_within(Path::new("a/link"), "./file"));
+    }
+
+    #[test]
+    fn links_leaving_the_destination_are_refused() {
+        assert!(!link_stays_within(Path::new("link"), ""));
+        assert!(!link_stays_within(Path::new("link"), "/etc/passwd"));
+        assert!(!link_stays_within(Path::new("link"), ".."));
+        assert!(!link_stays_within(Path::new("a/link"), "../../file"));
+        assert!(!link_stays_within(Path::new("link"), "C:/Windows"));
+        assert!(!link_stays_within(Path::new("link"), "..\\file"));
+    }
+
+    #[test]
+    fn links_may_not_go_up_after_going_down() {
+        // "dir" may itself be a link by the time this one is followed.
+        assert!(!link_stays_within(Path::new("a/link"), "dir/../file"));
+        assert!(!link_stays_within(Path::new("a/b/link"), "../dir/../file"));
+    }
+}
@@ -0,0 +1,43 @@
+use std::sync::LazyLock;
+use indicatif::{MultiProgress, ProgressBar, ProgressStyle};
+
+/// Every progress bar shown by this process, so that concurrent transfers each
+/// get their own line instead of overwriting one another.
+static BARS: LazyLock<MultiProgress> = LazyLock::new(MultiProgress::new);
+
+pub fn bar(len: u64) -> ProgressBar {
+    let pb = BARS.add(ProgressBar::new(len));
+    pb.set_style(ProgressStyle::default_bar()
+        .template("[{elapsed_precise}] [{bar:40.cyan/blue}] {bytes}/{total_bytes} ({eta})")
+        .unwrap()
+        .progress_chars("#>-"));
+    pb
+}
+
+/// A progress display for a transfer of unknown length, such as an archive being built.
+pub fn counter() -> ProgressBar {
+    let pb = BARS.add(ProgressBar::new_spinner());
+    pb.set_style(ProgressStyle::default_spinner()
+        .template("[{elapsed_precise}] {spinner} {bytes} ({bytes_per_sec})")
+        .unwrap());
+    pb
+}
+
+/// Runs `f` with the progress bars cleared, so printed lines don't get mixed into them.
+pub fn suspend<R>(f: impl FnOnce() -> R) -> R {
+    BARS.suspend(f)
+}
+
+/// `println!` that keeps clear of the progress bars.
+macro_rules! say {
+    ($($arg:tt)*) => {
+        $crate::progress::suspend(|| println!($($arg)*))
+    };
+}
+
+/// `eprintln!` that keeps clear of the progress bars.
+macro_rules! say_err {
+    ($($arg:tt)*) => {
+        $crate::progress::suspend(|| eprintln!($($arg)*))
+    };
+}
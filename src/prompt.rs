@@ -0,0 +1,89 @@
+use std::io::{IsTerminal, Write};
+use std::time::Duration;
+use indicatif::HumanBytes;
+use tokio::sync::{mpsc, Mutex as AsyncMutex};
+
+use crate::receive::Manifest;
+
+/// How long a batch waits for an answer before it is declined.
+const ANSWER_TIMEOUT: Duration = Duration::from_secs(300);
+
+/// Entries shown by name when asking about a batch; the rest are only counted.
+const MAX_LISTED: usize = 20;
+
+/// Asks whoever runs the server about each incoming batch, on its terminal.
+pub struct Prompt {
+    /// Lines typed on stdin. Locked for the whole of a question, so batches
+    /// arriving together are asked about one at a time.
+    answers: AsyncMutex<mpsc::Receiver<String>>,
+}
+
+impl Prompt {
+    pub fn new() -> Result<Self, String> {
+        if !std::io::stdin().is_terminal() {
+            return Err("--ask needs a terminal to answer on".to_string());
+        }
+        let (lines, answers) = mpsc::channel(16);
+        std::thread::spawn(move || {
+            for line in std::io::stdin().lines() {
+                let Ok(line) = line else {
+                    break;
+                };
+                if lines.blocking_send(line).is_err() {
+                    break;
+                }
+            }
+        });
+        Ok(Prompt { answers: AsyncMutex::new(answers) })
+    }
+
+    /// Describes the batch `peer` wants to send and waits for a yes or no.
+    /// Returns why the batch is declined, if it is.
+    pub async fn confirm_batch(&self, peer: &str, identity: &str, manifest: &Manifest) -> Result<(), String> {
+        let mut answers = self.answers.lock().await;
+        // Anything typed while no question was showing was not an answer to this one.
+        while answers.try_recv().is_ok() {}
+
+        crate::progress::suspend(|| {
+            println!("Incoming batch from {} ({}):", peer, identity);
+            // Names are quoted and escaped, so that none can pass for anything else on the terminal.
+            for (name, size) in manifest.entries.iter().take(MAX_LISTED) {
+                match size {
+                    Some(size) => println!("  {:?} ({})", name, HumanBytes(*size)),
+                    None => println!("  {:?}", format!("{}/", name)),
+                }
+            }
+            if manifest.entries.len() > MAX_LISTED {
+                println!("  ... and {} more", manifest.entries.len() - MAX_LISTED);
+            }
+            let files = manifest.entries.iter().filter(|(_, size)| size.is_some()).count();
+            let directories = manifest.entries.len() - files;
+            let mut counts = format!("{} file{}", files, if files == 1 { "" } else { "s" });
+            if directories > 0 {
+                counts.push_str(&format!(" and {} empty director{}", directories, if directories == 1 { "y" } else { "ies" }));
+            }
+            println!("{}, {} in total.", counts, HumanBytes(manifest.bytes()));
+            print!("Accept? [y/N] ");
+            let _ = std::io::stdout().flush();
+        });
+
+        loop {
+            let answer = match tokio::time::timeout(ANSWER_TIMEOUT, answers.recv()).await {
+                Ok(Some(answer)) => answer,
+                Ok(None) => return Err("nobody is there to answer".to_string()),
+                Err(_) => {
+                    say!();
+                    return Err(format!("no answer within {} minutes", ANSWER_TIMEOUT.as_secs() / 60));
+                }
+            };
+            match answer.trim().to_lowercase().as_str() {
+                "y" | "yes" => return Ok(()),
+                "" | "n" | "no" => return Err(String::new()),
+                _ => crate::progress::suspend(|| {
+                    print!("Please answer y or n: ");
+                    let _ = std::io::stdout().flush();
+                }),
+            }
+        }
+    }
+}
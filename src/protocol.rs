@@ -0,0 +1,911 @@
+use std::io::{Error, ErrorKind};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+use crate::CHUNK_SIZE;
+use crate::archive::ArchiveFormat;
+use crate::compress::Codec;
+use crate::conflict::ConflictPolicy;
+use crate::delta::BlockSum;
+
+/// Sent first by both peers. The leading NUL makes older Streamline servers,
+/// which treat the first bytes as a file name, fail instead of creating a file.
+pub const MAGIC: [u8; 8] = *b"\0STRMLN\n";
+/// Raised with every change to what goes over the wire: frames and their
+/// fields, the hello, capabilities, or what a receiver accepts in an archive.
+/// Peers built from any two commits either agree on everything or refuse each
+/// other here, rather than misreading a frame halfway through a transfer.
+pub const PROTOCOL_VERSION: u16 = 17;
+
+/// Optional protocol features supported by this build, as a bitmask. Both
+/// peers advertise theirs in the hello and only the intersection is used.
+pub const CAPABILITIES: u32 = CAP_ZSTD | CAP_LZ4;
+
+/// Can decompress chunks compressed with zstd.
+pub const CAP_ZSTD: u32 = 1 << 0;
+/// Can decompress chunks compressed with lz4.
+pub const CAP_LZ4: u32 = 1 << 1;
+/// Keeps an index of its files by content, and wants the SHA-256 of each file
+/// in its header. Unlike the others it only says something about the side that
+/// sets it, a server started with `--dedup`, so it is kept from the peer's hello alone.
+pub const CAP_CONTENT_INDEX: u32 = 1 << 2;
+
+/// Sent alongside the capabilities, but a requirement rather than an option: set
+/// by a server that only accepts paired clients and by a client with a pairing code.
+const PAIRING_FLAG: u32 = 1 << 31;
+
+pub const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+pub const MAX_FRAME_SIZE: usize = CHUNK_SIZE + 64 * 1024;
+const MAX_NAME_LEN: usize = 4096;
+
+const FRAME_HEADER: u8 = 1;
+const FRAME_DATA: u8 = 2;
+const FRAME_END: u8 = 3;
+const FRAME_STATUS: u8 = 4;
+const FRAME_OFFER: u8 = 5;
+const FRAME_START: u8 = 6;
+const FRAME_DIRECTORY: u8 = 7;
+const FRAME_EXISTS: u8 = 8;
+const FRAME_BATCH_END: u8 = 9;
+const FRAME_BATCH_SUMMARY: u8 = 10;
+const FRAME_PUSH: u8 = 11;
+const FRAME_PULL: u8 = 12;
+const FRAME_LIST: u8 = 13;
+const FRAME_LIST_ENTRY: u8 = 14;
+const FRAME_AUTH: u8 = 15;
+const FRAME_DELETE: u8 = 16;
+const FRAME_MANIFEST_ENTRY: u8 = 17;
+const FRAME_MANIFEST_END: u8 = 18;
+const FRAME_COMPRESSED_DATA: u8 = 19;
+const FRAME_SYMLINK: u8 = 20;
+const FRAME_HARD_LINK: u8 = 21;
+const FRAME_EXTENT: u8 = 22;
+const FRAME_SIGNATURES: u8 = 23;
+const FRAME_BLOCK_REF: u8 = 24;
+const FRAME_ALREADY_HAVE: u8 = 25;
+const FRAME_CREDIT: u8 = 26;
+
+/// Outcome of a transfer, reported by the receiver once it has finished with a file,
+/// or by the sender if it could not finish sending it.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum StatusCode {
+    Ok = 0,
+    HashMismatch = 1,
+    WriteError = 2,
+    Rejected = 3,
+    /// The person running the receiver said no.
+    Declined = 4,
+    /// Sent by the sender instead of the rest of a body it could not read.
+    ReadError = 5,
+}
+
+impl StatusCode {
+    fn from_u8(value: u8) -> tokio::io::Result<Self> {
+        match value {
+            0 => Ok(StatusCode::Ok),
+            1 => Ok(StatusCode::HashMismatch),
+            2 => Ok(StatusCode::WriteError),
+            3 => Ok(StatusCode::Rejected),
+            4 => Ok(StatusCode::Declined),
+            5 => Ok(StatusCode::ReadError),
+            other => Err(invalid(format!("unknown status code {}", other))),
+        }
+    }
+
+    pub fn describe(self) -> &'static str {
+        match self {
+            StatusCode::Ok => "ok",
+            StatusCode::HashMismatch => "integrity check failed",
+            StatusCode::WriteError => "write error on receiver",
+            StatusCode::Rejected => "rejected by receiver",
+            StatusCode::Declined => "declined by receiver",
+            StatusCode::ReadError => "read error on sender",
+        }
+    }
+}
+
+/// Describes a file before its body is sent.
+#[derive(Clone)]
+pub struct FileHeader {
+    pub name: String,
+    /// `None` when the body is built as it is sent, as archives are.
+    pub size: Option<u64>,
+    pub mtime: Option<SystemTime>,
+    /// The sender's choice of conflict policy, overriding the receiver's default.
+    pub on_conflict: Option<ConflictPolicy>,
+    /// Set when the file is an archive of the sender's files, which a server
+    /// started with `--extract` unpacks instead of storing.
+    pub archive: Option<ArchiveFormat>,
+    pub atime: Option<SystemTime>,
+    /// Unix permission bits.
+    pub mode: Option<u32>,
+    /// Extended attributes in the user namespace, sent with `--xattrs`.
+    pub xattrs: Vec<(String, Vec<u8>)>,
+    /// The SHA-256 of the whole file, sent ahead of it to a receiver that
+    /// indexes its files by content.
+    pub sha256: Option<[u8; 32]>,
+}
+
+pub enum Message {
+    Header(FileHeader),
+    Data(Vec<u8>),
+    /// A chunk compressed with the codec named in `Start`, and its size before that.
+    CompressedData { raw_len: u32, data: Vec<u8> },
+    /// Where the body resumes after a hole in the sender's file, which is not
+    /// sent: everything between the data before it and this offset is zeros.
+    Extent { offset: u64 },
+    /// Stands in for bytes the receiver has already: `count` blocks of its
+    /// copy of the file, from block `index` on.
+    BlockRef { index: u64, count: u32 },
+    End { sha256: [u8; 32] },
+    Status { code: StatusCode, message: String },
+    /// Receiver's answer to a header: how much of the file it already holds and the
+    /// SHA-256 of that prefix. An offset of zero means a fresh transfer.
+    Offer { offset: u64, sha256: [u8; 32] },
+    /// Sent ahead of the offer by a receiver about to replace a copy of the
+    /// file it has, with the sums of its blocks, in order, over as many frames
+    /// as it takes. The sender then refers to the blocks it has too.
+    Signatures { block_size: u32, sums: Vec<BlockSum> },
+    /// Sender's choice of where the body starts: the offered offset, or zero if its
+    /// own prefix did not hash the same, and the codec the body is compressed with.
+    Start { offset: u64, codec: Codec },
+    /// Asks the receiver to create a directory, for directories with no files in them.
+    Directory { name: String },
+    /// Asks the receiver to create a symbolic link, with `--links preserve`.
+    Symlink { name: String, target: String, on_conflict: Option<ConflictPolicy> },
+    /// Asks the receiver to give a file received earlier in the batch, `target`,
+    /// another name, for a file the sender has under several names.
+    HardLink { name: String, target: String, on_conflict: Option<ConflictPolicy> },
+    /// Receiver's answer to a header when it skips the file because it already
+    /// has one by that name: the size and SHA-256 of its existing copy.
+    Exists { size: u64, sha256: [u8; 32] },
+    /// Receiver's answer to a header whose hash matches a file it has: it has
+    /// put that file's content in place, so the body is not needed. Carries the
+    /// name it was saved as, if not the one it was sent under.
+    AlreadyHave { saved_as: String },
+    /// Sender has finished the batch and wants the receiver's summary of it.
+    BatchEnd,
+    BatchSummary(BatchSummary),
+    /// Opens a session in which the client sends a batch to the server. Like
+    /// every request, the server answers it with a status: `Ok` to go ahead, or
+    /// why it refuses. The client then describes the batch with manifest entries,
+    /// and the server answers with a second status once it has decided to take it.
+    Push,
+    /// Opens a session in which the server sends the client the named file or
+    /// directory from its export, as a single archive if one is asked for.
+    Pull { path: String, archive: Option<ArchiveFormat> },
+    /// Asks for the contents of a directory on the server, or the details of a
+    /// file. Once accepted, the server sends one entry frame each, then a status.
+    List { path: String, hashes: bool },
+    ListEntry(RemoteEntry),
+    /// Presents an access token, just before the request it applies to.
+    Auth { token: String },
+    /// Asks the server to delete a file or directory from its output directory.
+    Delete { path: String },
+    /// One file or directory of a batch about to be pushed.
+    ManifestEntry { name: String, size: u64, is_dir: bool },
+    ManifestEnd,
+    /// Says that the other end has taken this many more of the frames sent on
+    /// a stream, so that many more may be sent. Only the session itself sees it.
+    Credit { frames: u32 },
+}
+
+/// The receiver's account of a whole batch, sent once every stream has finished.
+#[derive(Clone, Default)]
+pub struct BatchSummary {
+    pub received: u32,
+    pub skipped: u32,
+    pub failed: u32,
+    pub bytes: u64,
+}
+
+/// A file or directory as seen in a listing of the server.
+pub struct RemoteEntry {
+    pub name: String,
+    pub is_dir: bool,
+    pub size: u64,
+    pub mtime: Option<SystemTime>,
+    /// Only sent for files, and only when asked for.
+    pub sha256: Option<[u8; 32]>,
+}
+
+impl Message {
+    fn name(&self) -> &'static str {
+        match self {
+            Message::Header(_) => "header",
+            Message::Data(_) => "data",
+            Message::CompressedData { .. } => "compressed data",
+            Message::Extent { .. } => "extent",
+            Message::BlockRef { .. } => "block reference",
+            Message::End { .. } => "end",
+            Message::Status { .. } => "status",
+            Message::Offer { .. } => "offer",
+            Message::Signatures { .. } => "signatures",
+            Message::Start { .. } => "start",
+            Message::Directory { .. } => "directory",
+            Message::Symlink { .. } => "symlink",
+            Message::HardLink { .. } => "hard link",
+            Message::Exists { .. } => "exists",
+            Message::AlreadyHave { .. } => "already have",
+            Message::BatchEnd => "batch end",
+            Message::BatchSummary(_) => "batch summary",
+            Message::Push => "push",
+            Message::Pull { .. } => "pull",
+            Message::List { .. } => "list",
+            Message::ListEntry(_) => "list entry",
+            Message::Auth { .. } => "auth",
+            Message::Delete { .. } => "delete",
+            Message::ManifestEntry { .. } => "manifest entry",
+            Message::ManifestEnd => "manifest end",
+            Message::Credit { .. } => "credit",
+        }
+    }
+}
+
+fn invalid(message: impl Into<String>) -> Error {
+    Error::new(ErrorKind::InvalidData, message.into())
+}
+
+/// Error for a frame that is valid on its own but not expected at this point.
+pub fn unexpected(message: &Message) -> Error {
+    invalid(format!("unexpected {} frame from peer", message.name()))
+}
+
+/// What was learned from the peer's hello.
+pub struct Hello {
+    /// Whether the peer wants to pair with a code before anything else.
+    pub pairing: bool,
+    /// The optional features both sides support, and whether the peer keeps
+    /// an index of its files by content.
+    pub capabilities: u32,
+}
+
+/// Exchanges hellos with the peer, saying whether this side will pair with a
+/// code and whether it keeps an index of its files by content.
+///
+/// Both sides write their hello before reading, so the same function serves
+/// client and server.
+pub async fn handshake<S>(stream: &mut S, pairing: bool, content_index: bool) -> tokio::io::Result<Hello>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let mut flags = if pairing { PAIRING_FLAG } else { 0 };
+    if content_index {
+        flags |= CAP_CONTENT_INDEX;
+    }
+    let mut hello = Vec::with_capacity(14);
+    hello.extend_from_slice(&MAGIC);
+    hello.extend_from_slice(&PROTOCOL_VERSION.to_be_bytes());
+    hello.extend_from_slice(&(CAPABILITIES | flags).to_be_bytes());
+    stream.write_all(&hello).await?;
+
+    let mut peer = [0u8; 14];
+    match tokio::time::timeout(HANDSHAKE_TIMEOUT, stream.read_exact(&mut peer)).await {
+        Err(_) => return Err(Error::new(
+            ErrorKind::TimedOut,
+            "no handshake from peer (is it running an older version of Streamline, or is only one side using --insecure?)",
+        )),
+        Ok(Err(e)) if e.kind() == ErrorKind::UnexpectedEof => return Err(Error::new(
+            ErrorKind::UnexpectedEof,
+            "peer closed the connection during the handshake (did it refuse this address, is it running an older version of Streamline, or is only one side using --insecure?)",
+        )),
+        Ok(result) => result?,
+    };
+
+    if peer[..8] != MAGIC {
+        return Err(invalid("peer is not speaking the Streamline protocol (is it running an older version?)"));
+    }
+    let version = u16::from_be_bytes([peer[8], peer[9]]);
+    if version != PROTOCOL_VERSION {
+        return Err(invalid(format!(
+            "protocol version mismatch: this side speaks v{}, peer speaks v{}; upgrade both to the same release",
+            PROTOCOL_VERSION, version
+        )));
+    }
+    let capabilities = u32::from_be_bytes([peer[10], peer[11], peer[12], peer[13]]);
+    Ok(Hello {
+        pairing: capabilities & PAIRING_FLAG != 0,
+        capabilities: capabilities & (CAPABILITIES | CAP_CONTENT_INDEX),
+    })
+}
+
+/// Error for a non-`Ok` status received from the peer.
+pub fn status_error(code: StatusCode, message: &str) -> Error {
+    if message.is_empty() {
+        Error::other(code.describe())
+    } else {
+        Error::other(format!("{}: {}", code.describe(), message))
+    }
+}
+
+/// Encodes one frame: a type byte, the big-endian u32 id of the stream it belongs
+/// to, a big-endian u32 payload length, then the payload.
+pub fn encode_message(stream: u32, message: &Message) -> tokio::io::Result<Vec<u8>> {
+    let (kind, payload) = match message {
+        Message::Header(header) => {
+            check_name(&header.name)?;
+            let mut payload = Encoder::default();
+            payload.put_str(&header.name)?;
+            match header.size {
+                Some(size) => {
+                    payload.put_u8(1);
+                    payload.put_u64(size);
+                }
+                None => payload.put_u8(0),
+            }
+            payload.put_time(header.mtime);
+            payload.put_u8(header.on_conflict.map_or(0, ConflictPolicy::to_u8));
+            payload.put_u8(header.archive.map_or(0, ArchiveFormat::to_u8));
+            payload.put_time(header.atime);
+            match header.mode {
+                Some(mode) => {
+                    payload.put_u8(1);
+                    payload.put_u32(mode);
+                }
+                None => payload.put_u8(0),
+            }
+            let count = u16::try_from(header.xattrs.len())
+                .map_err(|_| Error::new(ErrorKind::InvalidInput, format!("'{}' has more than {} extended attributes", header.name, u16::MAX)))?;
+            payload.put_u16(count);
+            for (name, value) in &header.xattrs {
+                payload.put_str(name)?;
+                payload.put_u32(value.len() as u32);
+                payload.0.extend_from_slice(value);
+            }
+            match header.sha256 {
+                Some(sha256) => {
+                    payload.put_u8(1);
+                    payload.0.extend_from_slice(&sha256);
+                }
+                None => payload.put_u8(0),
+            }
+            (FRAME_HEADER, payload.0)
+        }
+        Message::Data(bytes) if bytes.len() > MAX_FRAME_SIZE => return Err(too_large(message, bytes.len())),
+        Message::Data(bytes) => return Ok(frame(FRAME_DATA, stream, bytes)),
+        Message::CompressedData { raw_len, data } => {
+            let mut payload = Encoder::default();
+            payload.put_u32(*raw_len);
+            payload.0.extend_from_slice(data);
+            (FRAME_COMPRESSED_DATA, payload.0)
+        }
+        Message::Extent { offset } => (FRAME_EXTENT, offset.to_be_bytes().to_vec()),
+        Message::BlockRef { index, count } => {
+            let mut payload = Encoder::default();
+            payload.put_u64(*index);
+            payload.put_u32(*count);
+            (FRAME_BLOCK_REF, payload.0)
+        }
+        Message::End { sha256 } => (FRAME_END, sha256.to_vec()),
+        Message::Status { code, message } => {
+            // A status is cut short rather than lost: it may be the only word of what went wrong.
+            let mut end = message.len().min(MAX_NAME_LEN);
+            while !message.is_char_boundary(end) {
+                end -= 1;
+            }
+            let mut payload = Encoder::default();
+            payload.put_u8(*code as u8);
+            payload.put_str(&message[..end])?;
+            (FRAME_STATUS, payload.0)
+        }
+        Message::Offer { offset, sha256 } => {
+            let mut payload = Encoder::default();
+            payload.put_u64(*offset);
+            payload.0.extend_from_slice(sha256);
+            (FRAME_OFFER, payload.0)
+        }
+        Message::Signatures { block_size, sums } => {
+            let mut payload = Encoder::default();
+            payload.put_u32(*block_size);
+            for sum in sums {
+                payload.put_u32(sum.weak);
+                payload.0.extend_from_slice(&sum.strong);
+            }
+            (FRAME_SIGNATURES, payload.0)
+        }
+        Message::Start { offset, codec } => {
+            let mut payload = Encoder::default();
+            payload.put_u64(*offset);
+            payload.put_u8(codec.to_u8());
+            (FRAME_START, payload.0)
+        }
+        Message::Directory { name } => {
+            check_name(name)?;
+            let mut payload = Encoder::default();
+            payload.put_str(name)?;
+            (FRAME_DIRECTORY, payload.0)
+        }
+        Message::Symlink { name, target, on_conflict } | Message::HardLink { name, target, on_conflict } => {
+            check_name(name)?;
+            check_name(target)?;
+            let mut payload = Encoder::default();
+            payload.put_str(name)?;
+            payload.put_str(target)?;
+            payload.put_u8(on_conflict.map_or(0, ConflictPolicy::to_u8));
+            let kind = if matches!(message, Message::Symlink { .. }) { FRAME_SYMLINK } else { FRAME_HARD_LINK };
+            (kind, payload.0)
+        }
+        Message::Exists { size, sha256 } => {
+            let mut payload = Encoder::default();
+            payload.put_u64(*size);
+            payload.0.extend_from_slice(sha256);
+            (FRAME_EXISTS, payload.0)
+        }
+        Message::AlreadyHave { saved_as } => {
+            let mut payload = Encoder::default();
+            payload.put_str(saved_as)?;
+            (FRAME_ALREADY_HAVE, payload.0)
+        }
+        Message::BatchEnd => (FRAME_BATCH_END, Vec::new()),
+        Message::BatchSummary(summary) => {
+            let mut payload = Encoder::default();
+            payload.put_u32(summary.received);
+            payload.put_u32(summary.skipped);
+            payload.put_u32(summary.failed);
+            payload.put_u64(summary.bytes);
+            (FRAME_BATCH_SUMMARY, payload.0)
+        }
+        Message::Push => (FRAME_PUSH, Vec::new()),
+        Message::Pull { path, archive } => {
+            check_name(path)?;
+            let mut payload = Encoder::default();
+            payload.put_str(path)?;
+            payload.put_u8(archive.map_or(0, ArchiveFormat::to_u8));
+            (FRAME_PULL, payload.0)
+        }
+        Message::List { path, hashes } => {
+            check_name(path)?;
+            let mut payload = Encoder::default();
+            payload.put_str(path)?;
+            payload.put_u8(*hashes as u8);
+            (FRAME_LIST, payload.0)
+        }
+        Message::ListEntry(entry) => {
+            check_name(&entry.name)?;
+            let mut payload = Encoder::default();
+            payload.put_str(&entry.name)?;
+            payload.put_u8(entry.is_dir as u8);
+            payload.put_u64(entry.size);
+            payload.put_time(entry.mtime);
+            match entry.sha256 {
+                Some(sha256) => {
+                    payload.put_u8(1);
+                    payload.0.extend_from_slice(&sha256);
+                }
+                None => payload.put_u8(0),
+            }
+            (FRAME_LIST_ENTRY, payload.0)
+        }
+        Message::Auth { token } => {
+            if token.len() > MAX_NAME_LEN {
+                return Err(Error::new(ErrorKind::InvalidInput, format!("token is longer than {} bytes", MAX_NAME_LEN)));
+            }
+            let mut payload = Encoder::default();
+            payload.put_str(token)?;
+            (FRAME_AUTH, payload.0)
+        }
+        Message::Delete { path } => {
+            check_name(path)?;
+            let mut payload = Encoder::default();
+            payload.put_str(path)?;
+            (FRAME_DELETE, payload.0)
+        }
+        Message::ManifestEntry { name, size, is_dir } => {
+            check_name(name)?;
+            let mut payload = Encoder::default();
+            payload.put_str(name)?;
+            payload.put_u64(*size);
+            payload.put_u8(*is_dir as u8);
+            (FRAME_MANIFEST_ENTRY, payload.0)
+        }
+        Message::ManifestEnd => (FRAME_MANIFEST_END, Vec::new()),
+        Message::Credit { frames } => (FRAME_CREDIT, frames.to_be_bytes().to_vec()),
+    };
+    if payload.len() > MAX_FRAME_SIZE {
+        return Err(too_large(message, payload.len()));
+    }
+    Ok(frame(kind, stream, &payload))
+}
+
+fn too_large(message: &Message, len: usize) -> Error {
+    Error::new(ErrorKind::InvalidInput, format!("{} frame of {} bytes exceeds the {} byte limit", message.name(), len, MAX_FRAME_SIZE))
+}
+
+fn check_name(name: &str) -> tokio::io::Result<()> {
+    if name.len() > MAX_NAME_LEN {
+        return Err(Error::new(ErrorKind::InvalidInput, format!("file name '{}' is longer than {} bytes", name, MAX_NAME_LEN)));
+    }
+    Ok(())
+}
+
+fn frame(kind: u8, stream: u32, payload: &[u8]) -> Vec<u8> {
+    let mut frame = Vec::with_capacity(9 + payload.len());
+    frame.push(kind);
+    frame.extend_from_slice(&stream.to_be_bytes());
+    frame.extend_from_slice(&(payload.len() as u32).to_be_bytes());
+    frame.extend_from_slice(payload);
+    frame
+}
+
+/// Reads one frame, returning the id of the stream it belongs to along with it.
+pub async fn read_message<R>(reader: &mut R) -> tokio::io::Result<(u32, Message)>
+where
+    R: AsyncRead + Unpin,
+{
+    let mut header = [0u8; 9];
+    reader.read_exact(&mut header).await?;
+    let kind = header[0];
+    let stream = u32::from_be_bytes([header[1], header[2], header[3], header[4]]);
+    let len = u32::from_be_bytes([header[5], header[6], header[7], header[8]]) as usize;
+    if len > MAX_FRAME_SIZE {
+        return Err(invalid(format!("frame of {} bytes exceeds the {} byte limit", len, MAX_FRAME_SIZE)));
+    }
+    let mut payload = vec![0u8; len];
+    reader.read_exact(&mut payload).await?;
+
+    let mut decoder = Decoder::new(&payload);
+    let message = match kind {
+        FRAME_HEADER => Message::Header(FileHeader {
+            name: decoder.get_str()?,
+            size: match decoder.get_u8()? {
+                0 => None,
+                _ => Some(decoder.get_u64()?),
+            },
+            mtime: decoder.get_time()?,
+            on_conflict: decoder.get_conflict_policy()?,
+            archive: decoder.get_archive()?,
+            atime: decoder.get_time()?,
+            mode: match decoder.get_u8()? {
+                0 => None,
+                _ => Some(decoder.get_u32()?),
+            },
+            xattrs: {
+                let count = decoder.get_u16()?;
+                let mut xattrs = Vec::new();
+                for _ in 0..count {
+                    let name = decoder.get_str()?;
+                    let len = decoder.get_u32()? as usize;
+                    xattrs.push((name, decoder.take(len)?.to_vec()));
+                }
+                xattrs
+            },
+            sha256: match decoder.get_u8()? {
+                0 => None,
+                _ => Some(decoder.get_array()?),
+            },
+        }),
+        FRAME_DATA => return Ok((stream, Message::Data(payload))),
+        FRAME_COMPRESSED_DATA => {
+            let raw_len = decoder.get_u32()?;
+            return Ok((stream, Message::CompressedData { raw_len, data: decoder.buf.to_vec() }));
+        }
+        FRAME_EXTENT => Message::Extent { offset: decoder.get_u64()? },
+        FRAME_BLOCK_REF => Message::BlockRef {
+            index: decoder.get_u64()?,
+            count: decoder.get_u32()?,
+        },
+        FRAME_END => Message::End { sha256: decoder.get_array()? },
+        FRAME_STATUS => Message::Status {
+            code: StatusCode::from_u8(decoder.get_u8()?)?,
+            message: decoder.get_str()?,
+        },
+        FRAME_OFFER => Message::Offer {
+            offset: decoder.get_u64()?,
+            sha256: decoder.get_array()?,
+        },
+        FRAME_SIGNATURES => {
+            let block_size = decoder.get_u32()?;
+            let mut sums = Vec::with_capacity(decoder.buf.len() / BlockSum::ENCODED_LEN);
+            while !decoder.buf.is_empty() {
+                sums.push(BlockSum { weak: decoder.get_u32()?, strong: decoder.get_array()? });
+            }
+            Message::Signatures { block_size, sums }
+        }
+        FRAME_START => Message::Start {
+            offset: decoder.get_u64()?,
+            codec: Codec::from_u8(decoder.get_u8()?).ok_or_else(|| invalid("unknown compression codec"))?,
+        },
+        FRAME_DIRECTORY => Message::Directory { name: decoder.get_str()? },
+        FRAME_SYMLINK => Message::Symlink {
+            name: decoder.get_str()?,
+            target: decoder.get_str()?,
+            on_conflict: decoder.get_conflict_policy()?,
+        },
+        FRAME_HARD_LINK => Message::HardLink {
+            name: decoder.get_str()?,
+            target: decoder.get_str()?,
+            on_conflict: decoder.get_conflict_policy()?,
+        },
+        FRAME_EXISTS => Message::Exists {
+            size: decoder.get_u64()?,
+            sha256: decoder.get_array()?,
+        },
+        FRAME_ALREADY_HAVE => Message::AlreadyHave { saved_as: decoder.get_str()? },
+        FRAME_BATCH_END => Message::BatchEnd,
+        FRAME_BATCH_SUMMARY => Message::BatchSummary(BatchSummary {
+            received: decoder.get_u32()?,
+            skipped: decoder.get_u32()?,
+            failed: decoder.get_u32()?,
+            bytes: decoder.get_u64()?,
+        }),
+        FRAME_PUSH => Message::Push,
+        FRAME_PULL => Message::Pull {
+            path: decoder.get_str()?,
+            archive: decoder.get_archive()?,
+        },
+        FRAME_LIST => Message::List {
+            path: decoder.get_str()?,
+            hashes: decoder.get_u8()? != 0,
+        },
+        FRAME_LIST_ENTRY => Message::ListEntry(RemoteEntry {
+            name: decoder.get_str()?,
+            is_dir: decoder.get_u8()? != 0,
+            size: decoder.get_u64()?,
+            mtime: decoder.get_time()?,
+            sha256: match decoder.get_u8()? {
+                0 => None,
+                _ => Some(decoder.get_array()?),
+            },
+        }),
+        FRAME_AUTH => Message::Auth { token: decoder.get_str()? },
+        FRAME_DELETE => Message::Delete { path: decoder.get_str()? },
+        FRAME_MANIFEST_ENTRY => Message::ManifestEntry {
+            name: decoder.get_str()?,
+            size: decoder.get_u64()?,
+            is_dir: decoder.get_u8()? != 0,
+        },
+        FRAME_MANIFEST_END => Message::ManifestEnd,
+        FRAME_CREDIT => Message::Credit { frames: decoder.get_u32()? },
+        other => return Err(invalid(format!("unknown frame type {}", other))),
+    };
+    decoder.finish()?;
+    Ok((stream, message))
+}
+
+#[derive(Default)]
+struct Encoder(Vec<u8>);
+
+impl Encoder {
+    fn put_u8(&mut self, value: u8) {
+        self.0.push(value);
+    }
+
+    fn put_u16(&mut self, value: u16) {
+        self.0.extend_from_slice(&value.to_be_bytes());
+    }
+
+    fn put_u32(&mut self, value: u32) {
+        self.0.extend_from_slice(&value.to_be_bytes());
+    }
+
+    fn put_u64(&mut self, value: u64) {
+        self.0.extend_from_slice(&value.to_be_bytes());
+    }
+
+    /// A timestamp as seconds and nanoseconds since the Unix epoch, behind a flag
+    /// byte. Unknown times, and the rare ones before 1970, are sent as absent.
+    fn put_time(&mut self, value: Option<SystemTime>) {
+        match value.and_then(|time| time.duration_since(UNIX_EPOCH).ok()) {
+            Some(since_epoch) => {
+                self.put_u8(1);
+                self.put_u64(since_epoch.as_secs());
+                self.0.extend_from_slice(&since_epoch.subsec_nanos().to_be_bytes());
+            }
+            None => self.put_u8(0),
+        }
+    }
+
+    /// A string behind its u16 length, held to the same limit as when it is read.
+    fn put_str(&mut self, value: &str) -> tokio::io::Result<()> {
+        if value.len() > MAX_NAME_LEN {
+            return Err(Error::new(ErrorKind::InvalidInput, format!("string of {} bytes exceeds the {} byte limit", value.len(), MAX_NAME_LEN)));
+        }
+        self.0.extend_from_slice(&(value.len() as u16).to_be_bytes());
+        self.0.extend_from_slice(value.as_bytes());
+        Ok(())
+    }
+}
+
+struct Decoder<'a> {
+    buf: &'a [u8],
+}
+
+impl<'a> Decoder<'a> {
+    fn new(buf: &'a [u8]) -> Self {
+        Decoder { buf }
+    }
+
+    fn take(&mut self, n: usize) -> tokio::io::Result<&'a [u8]> {
+        if self.buf.len() < n {
+            return Err(invalid("truncated frame"));
+        }
+        let (head, tail) = self.buf.split_at(n);
+        self.buf = tail;
+        Ok(head)
+    }
+
+    fn get_array<const N: usize>(&mut self) -> tokio::io::Result<[u8; N]> {
+        Ok(self.take(N)?.try_into().unwrap())
+    }
+
+    fn get_u8(&mut self) -> tokio::io::Result<u8> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn get_u16(&mut self) -> tokio::io::Result<u16> {
+        Ok(u16::from_be_bytes(self.get_array()?))
+    }
+
+    fn get_u32(&mut self) -> tokio::io::Result<u32> {
+        Ok(u32::from_be_bytes(self.get_array()?))
+    }
+
+    fn get_u64(&mut self) -> tokio::io::Result<u64> {
+        Ok(u64::from_be_bytes(self.get_array()?))
+    }
+
+    fn get_time(&mut self) -> tokio::io::Result<Option<SystemTime>> {
+        if self.get_u8()? == 0 {
+            return Ok(None);
+        }
+        let secs = self.get_u64()?;
+        let nanos = u32::from_be_bytes(self.get_array()?);
+        if nanos >= 1_000_000_000 {
+            return Err(invalid("invalid timestamp"));
+        }
+        UNIX_EPOCH
+            .checked_add(Duration::new(secs, nanos))
+            .map(Some)
+            .ok_or_else(|| invalid("timestamp out of range"))
+    }
+
+    /// A conflict policy, with 0 for the receiver's own.
+    fn get_conflict_policy(&mut self) -> tokio::io::Result<Option<ConflictPolicy>> {
+        match self.get_u8()? {
+            0 => Ok(None),
+            policy => ConflictPolicy::from_u8(policy).map(Some).ok_or_else(|| invalid("unknown conflict policy")),
+        }
+    }
+
+    /// An archive format, with 0 for none.
+    fn get_archive(&mut self) -> tokio::io::Result<Option<ArchiveFormat>> {
+        match self.get_u8()? {
+            0 => Ok(None),
+            format => ArchiveFormat::from_u8(format).map(Some).ok_or_else(|| invalid("unknown archive format")),
+        }
+    }
+
+    fn get_str(&mut self) -> tokio::io::Result<String> {
+        let len = self.get_u16()? as usize;
+        if len > MAX_NAME_LEN {
+            return Err(invalid(format!("string of {} bytes exceeds the {} byte limit", len, MAX_NAME_LEN)));
+        }
+        String::from_utf8(self.take(len)?.to_vec()).map_err(|_| invalid("string is not valid UTF-8"))
+    }
+
+    fn finish(self) -> tokio::io::Result<()> {
+        if self.buf.is_empty() {
+            Ok(())
+        } else {
+            Err(invalid("trailing bytes in frame"))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Encodes `message`, decodes it and encodes it again, which has to give
+    /// the same frame if every field made it through.
+    async fn round_trip(message: Message) -> Message {
+        let frame = encode_message(7, &message).unwrap();
+        let (stream, decoded) = read_message(&mut frame.as_slice()).await.unwrap();
+        assert_eq!(stream, 7);
+        assert!(encode_message(7, &decoded).unwrap() == frame, "{} frame changed on the way", message.name());
+        decoded
+    }
+
+    fn header(name: &str) -> FileHeader {
+        FileHeader {
+            name: name.to_string(),
+            size: Some(1 << 40),
+            mtime: Some(UNIX_EPOCH + Duration::new(1_700_000_000, 123_456_789)),
+            on_conflict: Some(ConflictPolicy::Newer),
+            archive: Some(ArchiveFormat::Tar),
+            atime: None,
+            mode: Some(0o755),
+            xattrs: vec![("user.note".to_string(), b"value".to_vec())],
+            sha256: Some([9; 32]),
+        }
+    }
+
+    #[tokio::test]
+    async fn every_message_survives_the_wire() {
+        let messages = vec![
+            Message::Header(header("dir/file.txt")),
+            Message::Header(FileHeader { size: None, mtime: None, on_conflict: None, archive: None, mode: None, xattrs: Vec::new(), sha256: None, ..header("bare") }),
+            Message::Data(b"some bytes".to_vec()),
+            Message::CompressedData { raw_len: 1000, data: vec![1, 2, 3] },
+            Message::Extent { offset: 1 << 33 },
+            Message::BlockRef { index: 12, count: 3 },
+            Message::End { sha256: [1; 32] },
+            Message::Status { code: StatusCode::Rejected, message: "no room".to_string() },
+            Message::Offer { offset: 64, sha256: [2; 32] },
+            Message::Signatures { block_size: 4096, sums: vec![BlockSum { weak: 5, strong: [3; 16] }, BlockSum { weak: 6, strong: [4; 16] }] },
+            Message::Start { offset: 64, codec: Codec::Zstd },
+            Message::Directory { name: "empty".to_string() },
+            Message::Symlink { name: "link".to_string(), target: "../file".to_string(), on_conflict: Some(ConflictPolicy::Skip) },
+            Message::HardLink { name: "other".to_string(), target: "file".to_string(), on_conflict: None },
+            Message::Exists { size: 10, sha256: [5; 32] },
+            Message::AlreadyHave { saved_as: "file (1).txt".to_string() },
+            Message::BatchEnd,
+            Message::BatchSummary(BatchSummary { received: 3, skipped: 2, failed: 1, bytes: 1 << 35 }),
+            Message::Push,
+            Message::Pull { path: "photos".to_string(), archive: Some(ArchiveFormat::Zip) },
+            Message::List { path: "".to_string(), hashes: true },
+            Message::ListEntry(RemoteEntry { name: "file".to_string(), is_dir: false, size: 10, mtime: Some(UNIX_EPOCH), sha256: Some([6; 32]) }),
+            Message::Auth { token: "secret".to_string() },
+            Message::Delete { path: "old".to_string() },
+            Message::ManifestEntry { name: "file".to_string(), size: 10, is_dir: false },
+            Message::ManifestEnd,
+            Message::Credit { frames: 4 },
+        ];
+        for message in messages {
+            round_trip(message).await;
+        }
+    }
+
+    #[tokio::test]
+    async fn strings_are_held_to_the_same_limit_both_ways() {
+        let longest = "n".repeat(MAX_NAME_LEN);
+        let Message::Directory { name } = round_trip(Message::Directory { name: longest.clone() }).await else {
+            panic!("not a directory frame");
+        };
+        assert_eq!(name, longest);
+
+        let too_long = "n".repeat(MAX_NAME_LEN + 1);
+        let refused = [
+            Message::Header(header(&too_long)),
+            Message::Header(FileHeader { xattrs: vec![(too_long.clone(), Vec::new())], ..header("file") }),
+            Message::Directory { name: too_long.clone() },
+            Message::Symlink { name: "link".to_string(), target: too_long.clone(), on_conflict: None },
+            Message::AlreadyHave { saved_as: too_long.clone() },
+            Message::Pull { path: too_long.clone(), archive: None },
+            Message::List { path: too_long.clone(), hashes: false },
+            Message::Auth { token: too_long.clone() },
+            Message::Delete { path: too_long.clone() },
+            Message::ManifestEntry { name: too_long.clone(), size: 0, is_dir: false },
+            Message::Data(vec![0; MAX_FRAME_SIZE + 1]),
+        ];
+        for message in refused {
+            let error = encode_message(7, &message).err().unwrap_or_else(|| panic!("{} frame was encoded", message.name()));
+            assert_eq!(error.kind(), ErrorKind::InvalidInput);
+        }
+
+        // What a peer that skipped the check would send.
+        let mut frame = vec![FRAME_DIRECTORY, 0, 0, 0, 7];
+        frame.extend_from_slice(&(2 + too_long.len() as u32).to_be_bytes());
+        frame.extend_from_slice(&(too_long.len() as u16).to_be_bytes());
+        frame.extend_from_slice(too_long.as_bytes());
+        assert!(read_message(&mut frame.as_slice()).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn long_statuses_are_cut_short_between_characters() {
+        let message = format!("n{}", "é".repeat(MAX_NAME_LEN));
+        let Message::Status { message: received, .. } = round_trip(Message::Status { code: StatusCode::WriteError, message: message.clone() }).await else {
+            panic!("not a status frame");
+        };
+        assert_eq!(received.len(), MAX_NAME_LEN - 1);
+        assert!(message.starts_with(&received));
+    }
+
+    #[tokio::test]
+    async fn unknown_conflict_policies_are_refused() {
+        let mut frame = encode_message(7, &Message::Symlink { name: "link".to_string(), target: "file".to_string(), on_conflict: None }).unwrap();
+        *frame.last_mut().unwrap() = 99;
+        let error = read_message(&mut frame.as_slice()).await.err().unwrap();
+        assert!(error.to_string().contains("unknown conflict policy"), "{}", error);
+    }
+}
@@ -0,0 +1,978 @@
+use std::collections::HashMap;
+use std::io::SeekFrom;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{Instant, SystemTime};
+use indicatif::HumanBytes;
+use sha2::{Sha256, Digest};
+use tokio::fs::OpenOptions;
+use tokio::io::{AsyncSeekExt, AsyncWriteExt};
+use tokio::task::JoinSet;
+
+use crate::archive::{self, ArchiveFormat, Extracted, Extraction};
+use crate::compress::{self, Codec};
+use crate::conflict::{self, ConflictPolicy};
+use crate::dedup::{ContentIndex, DedupMode};
+use crate::delta::{self, Basis};
+use crate::metadata::{self, Preserve};
+use crate::mux::{Session, Stream};
+use crate::protocol::{self, BatchSummary, FileHeader, Message, StatusCode};
+use crate::resume::{self, PartialState};
+use crate::sparse;
+use crate::{paths, progress, CHECKPOINT_INTERVAL, CHUNK_SIZE};
+
+/// Bounds the memory a manifest takes, at over a million typical names.
+const MAX_MANIFEST_NAMES: usize = 64 * 1024 * 1024;
+
+/// Where and how incoming entries are saved.
+pub struct Destination {
+    pub root: PathBuf,
+    pub on_conflict: ConflictPolicy,
+    /// Largest file accepted, in bytes.
+    pub max_size: Option<u64>,
+    /// When the batch had to be approved, the entries that were: files with
+    /// their announced size, empty directories with `None`. Nothing else is accepted.
+    pub approved: Option<HashMap<String, Option<u64>>>,
+    /// With `--extract`, zip archives are unpacked instead of stored.
+    pub extract: bool,
+    /// What one archive may unpack to.
+    pub limits: Extraction,
+    /// Which of the attributes sent with each file are kept.
+    pub preserve: Preserve,
+    /// With `--dedup`, the files already in the output directory by content.
+    pub dedup: Option<Arc<ContentIndex>>,
+}
+
+/// Where each file of a batch was saved, by the name it was sent under, for
+/// other names of it to link to.
+type Saved = Mutex<HashMap<String, PathBuf>>;
+
+/// What a sender says its batch holds, read before anything is written.
+pub struct Manifest {
+    /// Names in the order announced, files with their size and empty directories with `None`.
+    pub entries: Vec<(String, Option<u64>)>,
+}
+
+impl Manifest {
+    /// Reads the manifest that follows an accepted push. Names that could not
+    /// be received are refused here already, before anyone is shown them.
+    pub async fn read(session: &Session) -> tokio::io::Result<Self> {
+        let mut entries = Vec::new();
+        let mut names_len = 0;
+        loop {
+            match session.recv_control().await? {
+                Message::ManifestEntry { name, size, is_dir } => {
+                    names_len += name.len();
+                    if names_len > MAX_MANIFEST_NAMES {
+                        return Err(std::io::Error::new(std::io::ErrorKind::InvalidData, "batch has too many files to describe"));
+                    }
+                    if let Err(reason) = paths::sanitize(&name) {
+                        return Err(std::io::Error::new(std::io::ErrorKind::PermissionDenied, format!("unsafe path {:?} in batch: {}", name, reason)));
+                    }
+                    entries.push((name, (!is_dir).then_some(size)));
+                }
+                Message::ManifestEnd => break,
+                other => return Err(protocol::unexpected(&other)),
+            }
+        }
+        Ok(Manifest { entries })
+    }
+
+    pub fn bytes(&self) -> u64 {
+        self.entries.iter().filter_map(|(_, size)| *size).sum()
+    }
+
+    pub fn into_approved(self) -> HashMap<String, Option<u64>> {
+        self.entries.into_iter().collect()
+    }
+}
+
+/// What became of one entry that was not refused.
+#[derive(Debug)]
+pub enum Outcome {
+    Received { bytes: u64 },
+    Skipped,
+    Created,
+}
+
+/// Receives every entry the peer sends over `session`, several at a time, until
+/// it ends the batch. Answers with the batch summary, which is also returned.
+pub async fn receive_batch(session: &Session, destination: Arc<Destination>, peer: &str) -> tokio::io::Result<BatchSummary> {
+    let mut handlers = JoinSet::new();
+    let saved = Arc::new(Saved::default());
+    let ended = loop {
+        // Streams opened before the end of the batch are always accepted first.
+        tokio::select! {
+            biased;
+            Some((stream, opening)) = session.accept() => {
+                let destination = destination.clone();
+                let saved = saved.clone();
+                let peer = peer.to_string();
+                handlers.spawn(async move { receive_entry(stream, opening, &destination, &saved, &peer).await });
+            }
+            control = session.recv_control() => match control {
+                Ok(Message::BatchEnd) => break Ok(()),
+                Ok(other) => break Err(protocol::unexpected(&other)),
+                Err(e) => break Err(e),
+            },
+        }
+    };
+
+    // Let every transfer finish, or notice the connection is gone and clean up,
+    // before reporting on the batch.
+    let mut summary = BatchSummary::default();
+    while let Some(outcome) = handlers.join_next().await {
+        match outcome.map_err(std::io::Error::other)? {
+            Ok(Outcome::Received { bytes }) => {
+                summary.received += 1;
+                summary.bytes += bytes;
+            }
+            Ok(Outcome::Skipped) => summary.skipped += 1,
+            Ok(Outcome::Created) => {}
+            Err(e) => {
+                say_err!("Error receiving file: {}", e);
+                summary.failed += 1;
+            }
+        }
+    }
+    ended?;
+    session.send_control(Message::BatchSummary(summary.clone())).await?;
+    Ok(summary)
+}
+
+/// Handles one stream opened by the sender, starting from its opening message.
+async fn receive_entry(mut stream: Stream, opening: Message, destination: &Destination, saved: &Saved, peer: &str) -> tokio::io::Result<Outcome> {
+    match opening {
+        Message::Header(header) if header.archive == Some(ArchiveFormat::Tar) => receive_tar(&mut stream, header, destination, peer).await,
+        Message::Header(header) => receive_file(&mut stream, header, destination, saved, peer).await,
+        Message::Directory { name } => receive_directory(&mut stream, &name, destination, peer).await,
+        Message::Symlink { name, target, on_conflict } => receive_symlink(&mut stream, &name, &target, on_conflict, destination, peer).await,
+        Message::HardLink { name, target, on_conflict } => {
+            receive_hard_link(&mut stream, &name, &target, on_conflict, destination, saved, peer).await
+        }
+        other => Err(protocol::unexpected(&other)),
+    }
+}
+
+async fn receive_file(stream: &mut Stream, header: FileHeader, destination: &Destination, saved: &Saved, peer: &str) -> tokio::io::Result<Outcome> {
+    let file_size = header.size;
+    if let Some((size, max_size)) = file_size.zip(destination.max_size).filter(|(size, max_size)| size > max_size) {
+        return Err(reject(stream, StatusCode::Rejected, over_limit(&header.name, size, max_size)).await);
+    }
+    // An archive is only built as it is sent, so it has no size of its own;
+    // it is held to the most it was announced to come to instead.
+    let approved = match header.archive {
+        Some(_) => approved_size(destination, &header.name),
+        None => check_approved(destination, &header.name, Some(file_size.unwrap_or(0))).map(|()| None),
+    };
+    let announced = match approved {
+        Ok(approved) => file_size.or(approved),
+        Err(e) => return Err(reject(stream, StatusCode::Rejected, e).await),
+    };
+    let extract = destination.extract && header.archive == Some(ArchiveFormat::Zip);
+
+    let output_file_path = match resolve_path(destination, &header.name, peer) {
+        Ok(path) => path,
+        Err(e) => return Err(reject(stream, StatusCode::Rejected, e).await),
+    };
+    if let Some(parent) = output_file_path.parent() {
+        if let Err(e) = tokio::fs::create_dir_all(parent).await {
+            return Err(reject(stream, StatusCode::WriteError, e).await);
+        }
+    }
+
+    let requested_path = output_file_path.clone();
+    let policy = header.on_conflict.unwrap_or(destination.on_conflict);
+    // An archive that is to be extracted is never saved under its own name; the
+    // policy applies to the files in it instead.
+    let output_file_path = if extract {
+        output_file_path
+    } else {
+        match resolve_conflict(stream, policy, &header, output_file_path).await? {
+            Some(path) => path,
+            None => return Ok(Outcome::Skipped),
+        }
+    };
+
+    // Data goes to a hidden file next to the destination, which is only replaced
+    // once the whole file has arrived intact. That file doubles as the partial
+    // copy an interrupted transfer resumes from.
+    let partial_path = resume::partial_path(&output_file_path);
+
+    if let Some(outcome) = reuse(stream, &header, destination, saved, &output_file_path, &requested_path).await? {
+        return Ok(outcome);
+    }
+
+    // Offer to continue an interrupted transfer of the same file from its last checkpoint.
+    let mut hasher = Sha256::new();
+    let mut offered = 0;
+    if let Some(state) = resume::load_state(&output_file_path).await {
+        if Some(state.size) == file_size && state.offset > 0 {
+            if let Ok(prefix) = resume::hash_prefix(&partial_path, state.offset).await {
+                hasher = prefix;
+                offered = state.offset;
+            }
+        }
+    }
+    // A copy about to be replaced lets the sender send only what changed in
+    // it. Archives are always sent in full.
+    let mut basis = None;
+    if offered == 0 && header.archive.is_none() {
+        match Basis::open(&output_file_path).await {
+            Ok(Some((found, block_size, sums))) => {
+                for sums in sums.chunks(delta::SUMS_PER_FRAME) {
+                    stream.send(Message::Signatures { block_size, sums: sums.to_vec() }).await?;
+                }
+                basis = Some(found);
+            }
+            Ok(None) => {}
+            Err(e) => say_err!("Warning: could not read {:?} to have only changes to it sent: {}", output_file_path, e),
+        }
+    }
+    stream.send(Message::Offer {
+        offset: offered,
+        sha256: hasher.clone().finalize().into(),
+    }).await?;
+
+    let (offset, codec) = match stream.recv().await? {
+        Message::Start { offset, codec } if offset == offered || offset == 0 => (offset, codec),
+        Message::Start { offset, .. } => {
+            let error = std::io::Error::new(std::io::ErrorKind::InvalidData, format!("sender asked to start at unoffered offset {}", offset));
+            return Err(reject(stream, StatusCode::Rejected, error).await);
+        }
+        other => return Err(protocol::unexpected(&other)),
+    };
+    if offset == 0 {
+        hasher = Sha256::new();
+    } else {
+        say!("Resuming {:?} from byte {}", output_file_path, offset);
+    }
+
+    let opened = async {
+        if offset == 0 {
+            // Start from a fresh file rather than whatever might be sitting at this name.
+            match tokio::fs::remove_file(&partial_path).await {
+                Err(e) if e.kind() != std::io::ErrorKind::NotFound => return Err(e),
+                _ => {}
+            }
+        } else if tokio::fs::symlink_metadata(&partial_path).await?.file_type().is_symlink() {
+            return Err(std::io::Error::new(std::io::ErrorKind::PermissionDenied, format!("{:?} is a symbolic link", partial_path)));
+        }
+        let mut file = OpenOptions::new()
+            .create(true)
+            .write(true)
+            .truncate(false)
+            .open(&partial_path)
+            .await?;
+        file.set_len(offset).await?;
+        file.seek(SeekFrom::Start(offset)).await?;
+        if let Some(size) = file_size {
+            resume::save_state(&output_file_path, &PartialState { size, offset }).await?;
+        }
+        Ok(file)
+    };
+    let mut file = match opened.await {
+        Ok(file) => file,
+        Err(e) => return Err(reject(stream, StatusCode::WriteError, e).await),
+    };
+
+    let pb = match file_size {
+        Some(size) => progress::bar(size),
+        None => progress::counter(),
+    };
+    pb.set_position(offset);
+
+    let start_time = Instant::now();
+    let mut total_bytes = offset;
+    let mut checkpoint = offset;
+    let mut wire_bytes = 0;
+    let mut hole_bytes = 0;
+    let mut reused_bytes = 0;
+
+    let received = async {
+        loop {
+            let buffer = match recv_chunk(stream, codec, &mut wire_bytes).await? {
+                Chunk::Data(buffer) => buffer,
+                Chunk::Blocks { index, count } => {
+                    let read = match basis.as_mut() {
+                        Some(basis) => basis.read(index, count).await,
+                        None => Err(std::io::Error::new(std::io::ErrorKind::InvalidData, "sender referred to blocks of a copy it was not offered")),
+                    };
+                    match read {
+                        Ok(buffer) => {
+                            reused_bytes += buffer.len() as u64;
+                            buffer
+                        }
+                        Err(e) => return Err(reject(stream, StatusCode::Rejected, e).await),
+                    }
+                }
+                Chunk::Hole(hole_end) => {
+                    // Only a file of known size has holes; an archive is sent in full.
+                    if hole_end < total_bytes || file_size.is_none_or(|size| hole_end > size) {
+                        let error = std::io::Error::new(std::io::ErrorKind::InvalidData, format!("sender skipped to offset {}, out of order", hole_end));
+                        return Err(reject(stream, StatusCode::Rejected, error).await);
+                    }
+                    if let Some(max_size) = destination.max_size.filter(|max_size| hole_end > *max_size) {
+                        return Err(reject(stream, StatusCode::Rejected, over_limit(&header.name, hole_end, max_size)).await);
+                    }
+                    // A hole costs nothing to send but is hashed as zeros, so
+                    // a file's holes are held to what an archive may unpack to.
+                    let holes = hole_bytes + (hole_end - total_bytes);
+                    if holes > destination.limits.max_bytes {
+                        let error = std::io::Error::new(
+                            std::io::ErrorKind::PermissionDenied,
+                            format!("{:?} has more than the {} bytes of holes allowed", header.name, destination.limits.max_bytes),
+                        );
+                        return Err(reject(stream, StatusCode::Rejected, error).await);
+                    }
+                    // Everything from `offset` on is written afresh, so seeking
+                    // past the hole leaves one without anything to punch out.
+                    if let Err(e) = file.seek(SeekFrom::Start(hole_end)).await {
+                        return Err(reject(stream, StatusCode::WriteError, e).await);
+                    }
+                    hasher = sparse::hash_zeros(std::mem::take(&mut hasher), hole_end - total_bytes).await?;
+                    hole_bytes += hole_end - total_bytes;
+                    total_bytes = hole_end;
+                    pb.set_position(total_bytes);
+                    continue;
+                }
+                Chunk::End(sha256) => break Ok(sha256),
+            };
+            let received = total_bytes + buffer.len() as u64;
+            if let Some(size) = announced.filter(|size| received > *size) {
+                let error = std::io::Error::new(std::io::ErrorKind::InvalidData, format!("sender went past the announced {} bytes", size));
+                return Err(reject(stream, StatusCode::Rejected, error).await);
+            }
+            if let Some(max_size) = destination.max_size.filter(|max_size| received > *max_size) {
+                return Err(reject(stream, StatusCode::Rejected, over_limit(&header.name, received, max_size)).await);
+            }
+            if let Err(e) = file.write_all(&buffer).await {
+                return Err(reject(stream, StatusCode::WriteError, e).await);
+            }
+            total_bytes += buffer.len() as u64;
+            pb.set_position(total_bytes);
+            hasher.update(&buffer);
+
+            // Only bytes that have reached the disk count towards the resume offset.
+            // An archive is built afresh every time, so it never resumes.
+            if let Some(size) = file_size.filter(|_| total_bytes - checkpoint >= CHECKPOINT_INTERVAL) {
+                let state = PartialState { size, offset: total_bytes };
+                if let Err(e) = file.sync_data().await {
+                    return Err(reject(stream, StatusCode::WriteError, e).await);
+                }
+                if let Err(e) = resume::save_state(&output_file_path, &state).await {
+                    return Err(reject(stream, StatusCode::WriteError, e).await);
+                }
+                checkpoint = total_bytes;
+            }
+        }
+    };
+    let received_hash = match received.await {
+        Ok(hash) => hash,
+        Err(e) => {
+            // Keep the partial file only if a later attempt can resume from it.
+            if checkpoint == 0 {
+                resume::discard_partial(&output_file_path).await;
+            }
+            return Err(e);
+        }
+    };
+
+    pb.finish_and_clear();
+
+    let duration = start_time.elapsed();
+    let speed = (total_bytes - offset) as f64 / duration.as_secs_f64() / 1024.0 / 1024.0; // MB/s
+    say!("Transfer complete in {:.2?}", duration);
+    say!("Average speed: {:.2} MB/s", speed);
+    if reused_bytes > 0 {
+        say!("Received only the changes: {}, reusing {} of the copy it replaces", HumanBytes(total_bytes - offset - hole_bytes - reused_bytes), HumanBytes(reused_bytes));
+    }
+    if codec != Codec::None && wire_bytes > 0 {
+        say!("Compressed with {}", compress::describe(codec, total_bytes - offset - hole_bytes - reused_bytes, wire_bytes));
+    }
+
+    // A hole at the very end is only there once the file is as long as it should be.
+    if let Err(e) = file.set_len(total_bytes).await {
+        return Err(reject(stream, StatusCode::WriteError, e).await);
+    }
+    if let Err(e) = file.sync_all().await {
+        return Err(reject(stream, StatusCode::WriteError, e).await);
+    }
+    // Windows won't replace a file that is still open.
+    drop(file);
+    drop(basis);
+
+    let calculated_hash = hasher.finalize();
+    if let Some(size) = file_size.filter(|size| total_bytes != *size) {
+        resume::discard_partial(&output_file_path).await;
+        let message = format!("expected {} bytes but received {}", size, total_bytes);
+        say!("Warning: {}", message);
+        return Err(reject(stream, StatusCode::HashMismatch, std::io::Error::other(message)).await);
+    }
+    if calculated_hash[..] != received_hash {
+        resume::discard_partial(&output_file_path).await;
+        say!("Warning: File integrity check failed");
+        let error = std::io::Error::other(format!("SHA-256 of {:?} does not match the sender's", output_file_path));
+        return Err(reject(stream, StatusCode::HashMismatch, error).await);
+    }
+    say!("File integrity verified");
+
+    if extract {
+        let note = match extract_zip(&partial_path, destination, policy, announced).await {
+            Ok(note) => note,
+            Err(e) => return Err(reject(stream, StatusCode::Rejected, e).await),
+        };
+        stream.send_status(StatusCode::Ok, note).await?;
+        return Ok(Outcome::Received { bytes: total_bytes });
+    }
+
+    // Applied before the file is put in place, so that it never appears without them.
+    let (partial, attributes, preserve) = (partial_path.clone(), header.clone(), destination.preserve);
+    tokio::task::spawn_blocking(move || metadata::apply(&partial, &attributes, preserve))
+        .await
+        .map_err(std::io::Error::other)?;
+
+    if let Err(e) = tokio::fs::rename(&partial_path, &output_file_path).await {
+        return Err(reject(stream, StatusCode::WriteError, e).await);
+    }
+    resume::remove_state(&output_file_path).await;
+    sync_parent(&output_file_path).await;
+    saved.lock().unwrap().insert(header.name.clone(), output_file_path.clone());
+    if let Some(index) = &destination.dedup {
+        index.insert(calculated_hash.into(), output_file_path.clone());
+    }
+
+    // Tell the sender if the file ended up under a different name.
+    let note = if output_file_path == requested_path { String::new() } else { saved_as(&header.name, &output_file_path) };
+    stream.send_status(StatusCode::Ok, note).await?;
+
+    say!("File received and saved to {:?}", output_file_path);
+    Ok(Outcome::Received { bytes: total_bytes - offset })
+}
+
+/// With `--dedup`, looks for a file with the content `header` announces, and
+/// if there is one, saves it as `output_file_path` and tells the sender the
+/// body isn't needed. Returns `None` if the body is still to come.
+async fn reuse(
+    stream: &mut Stream,
+    header: &FileHeader,
+    destination: &Destination,
+    saved: &Saved,
+    output_file_path: &Path,
+    requested_path: &Path,
+) -> tokio::io::Result<Option<Outcome>> {
+    let (Some(index), Some(sha256), None) = (&destination.dedup, header.sha256, header.archive) else {
+        return Ok(None);
+    };
+    let partial_path = resume::partial_path(output_file_path);
+    let Some(source) = index.place(sha256, header.size.unwrap_or(0), &destination.root, &partial_path).await else {
+        return Ok(None);
+    };
+    // A link shares the attributes of the file it links to, which are not this one's to change.
+    if index.mode == DedupMode::Copy {
+        let (partial, attributes, preserve) = (partial_path.clone(), header.clone(), destination.preserve);
+        tokio::task::spawn_blocking(move || metadata::apply(&partial, &attributes, preserve))
+            .await
+            .map_err(std::io::Error::other)?;
+    }
+    if let Err(e) = tokio::fs::rename(&partial_path, output_file_path).await {
+        let _ = tokio::fs::remove_file(&partial_path).await;
+        return Err(reject(stream, StatusCode::WriteError, e).await);
+    }
+    // Renaming a link over the file it links to leaves both names in place.
+    if source == output_file_path {
+        let _ = tokio::fs::remove_file(&partial_path).await;
+    }
+    // Whatever an interrupted transfer left behind has just been replaced.
+    resume::remove_state(output_file_path).await;
+    sync_parent(output_file_path).await;
+    saved.lock().unwrap().insert(header.name.clone(), output_file_path.to_path_buf());
+    index.insert(sha256, output_file_path.to_path_buf());
+
+    let saved_as = if output_file_path == requested_path { String::new() } else { saved_as(&header.name, output_file_path) };
+    stream.send(Message::AlreadyHave { saved_as }).await?;
+    say!("Already had {:?}: saved {:?} from {:?}", header.name, output_file_path, source);
+    Ok(Some(Outcome::Received { bytes: 0 }))
+}
+
+/// Unpacks a tar archive into the destination as it arrives. Nothing is put in
+/// place until all of it has arrived and its hash checks out.
+async fn receive_tar(stream: &mut Stream, header: FileHeader, destination: &Destination, peer: &str) -> tokio::io::Result<Outcome> {
+    let announced = match approved_size(destination, &header.name) {
+        Ok(announced) => announced,
+        Err(e) => return Err(reject(stream, StatusCode::Rejected, e).await),
+    };
+    if let Err(e) = resolve_path(destination, &header.name, peer) {
+        return Err(reject(stream, StatusCode::Rejected, e).await);
+    }
+    if let Err(e) = tokio::fs::create_dir_all(&destination.root).await {
+        return Err(reject(stream, StatusCode::WriteError, e).await);
+    }
+
+    // Archives are never resumed.
+    stream.send(Message::Offer { offset: 0, sha256: Sha256::new().finalize().into() }).await?;
+    let codec = match stream.recv().await? {
+        Message::Start { offset: 0, codec } => codec,
+        Message::Start { offset, .. } => {
+            let error = std::io::Error::new(std::io::ErrorKind::InvalidData, format!("sender asked to start at unoffered offset {}", offset));
+            return Err(reject(stream, StatusCode::Rejected, error).await);
+        }
+        other => return Err(protocol::unexpected(&other)),
+    };
+
+    let policy = header.on_conflict.unwrap_or(destination.on_conflict);
+    let limits = within(destination.limits, announced);
+    let (chunks, unpacking) = archive::unpack_tar(destination.root.clone(), policy, limits, destination.max_size, destination.preserve);
+    let pb = progress::counter();
+    let start_time = Instant::now();
+    let mut hasher = Sha256::new();
+    let mut total_bytes = 0;
+    let mut wire_bytes = 0;
+
+    let received = async {
+        loop {
+            let buffer = match recv_chunk(stream, codec, &mut wire_bytes).await? {
+                Chunk::Data(buffer) => buffer,
+                Chunk::Hole(_) | Chunk::Blocks { .. } => {
+                    let error = std::io::Error::new(std::io::ErrorKind::InvalidData, "an archive is always sent in full");
+                    return Err(reject(stream, StatusCode::Rejected, error).await);
+                }
+                Chunk::End(sha256) => break Ok(Some(sha256)),
+            };
+            total_bytes += buffer.len() as u64;
+            if let Some(size) = announced.filter(|size| total_bytes > *size) {
+                let error = std::io::Error::new(std::io::ErrorKind::InvalidData, format!("sender went past the announced {} bytes", size));
+                return Err(reject(stream, StatusCode::Rejected, error).await);
+            }
+            pb.set_position(total_bytes);
+            hasher.update(&buffer);
+            // The unpacker only stops listening when it has run into a problem.
+            if chunks.send(buffer).await.is_err() {
+                break Ok(None);
+            }
+        }
+    };
+    let received_hash = received.await;
+    drop(chunks);
+    pb.finish_and_clear();
+    let unpacked = unpacking.await.map_err(std::io::Error::other)?;
+    let (received_hash, unpacked) = match (received_hash, unpacked) {
+        (Err(e), _) => return Err(e),
+        (Ok(_), Err(e)) => return Err(reject(stream, StatusCode::Rejected, e).await),
+        (Ok(Some(hash)), Ok(unpacked)) => (hash, unpacked),
+        (Ok(None), Ok(_)) => {
+            let error = std::io::Error::new(std::io::ErrorKind::InvalidData, "archive ended before its data did");
+            return Err(reject(stream, StatusCode::Rejected, error).await);
+        }
+    };
+
+    let duration = start_time.elapsed();
+    say!("Transfer complete in {:.2?}", duration);
+    say!("Average speed: {:.2} MB/s", total_bytes as f64 / duration.as_secs_f64() / 1024.0 / 1024.0);
+    if codec != Codec::None {
+        say!("Compressed with {}", compress::describe(codec, total_bytes, wire_bytes));
+    }
+    if hasher.finalize()[..] != received_hash {
+        say!("Warning: File integrity check failed");
+        let error = std::io::Error::other(format!("SHA-256 of {:?} does not match the sender's", header.name));
+        return Err(reject(stream, StatusCode::HashMismatch, error).await);
+    }
+    say!("File integrity verified");
+
+    let extracted = match tokio::task::spawn_blocking(move || unpacked.commit()).await.map_err(std::io::Error::other)? {
+        Ok(extracted) => extracted,
+        Err(e) => return Err(reject(stream, StatusCode::WriteError, e).await),
+    };
+    stream.send_status(StatusCode::Ok, describe_extracted(&extracted)).await?;
+    say!("Extracted {} files ({} bytes) into {:?}", extracted.files, extracted.bytes, destination.root);
+    Ok(Outcome::Received { bytes: total_bytes })
+}
+
+/// A piece of a file's body, or the end of it with the sender's SHA-256.
+enum Chunk {
+    Data(Vec<u8>),
+    /// A hole in a sparse file, up to where the data resumes.
+    Hole(u64),
+    /// Blocks of the copy being replaced, which the sender has too.
+    Blocks { index: u64, count: u32 },
+    End([u8; 32]),
+}
+
+/// Receives the next piece of a body sent with `codec`, decompressed, adding
+/// what it took on the wire to `wire_bytes`.
+async fn recv_chunk(stream: &mut Stream, codec: Codec, wire_bytes: &mut u64) -> std::io::Result<Chunk> {
+    match stream.recv().await? {
+        Message::Data(buffer) => {
+            *wire_bytes += buffer.len() as u64;
+            Ok(Chunk::Data(buffer))
+        }
+        Message::CompressedData { raw_len, data } => {
+            *wire_bytes += data.len() as u64;
+            let raw_len = raw_len as usize;
+            if raw_len > CHUNK_SIZE {
+                let error = std::io::Error::new(std::io::ErrorKind::InvalidData, format!("compressed chunk of {} bytes is too large", raw_len));
+                return Err(reject(stream, StatusCode::Rejected, error).await);
+            }
+            let unpacked = tokio::task::spawn_blocking(move || compress::unpack(codec, &data, raw_len))
+                .await
+                .map_err(std::io::Error::other)?;
+            match unpacked {
+                Ok(buffer) => Ok(Chunk::Data(buffer)),
+                Err(e) => Err(reject(stream, StatusCode::Rejected, e).await),
+            }
+        }
+        Message::Extent { offset } => Ok(Chunk::Hole(offset)),
+        Message::BlockRef { index, count } => Ok(Chunk::Blocks { index, count }),
+        Message::End { sha256 } => Ok(Chunk::End(sha256)),
+        // The sender could not finish, and says why.
+        Message::Status { code, message } => Err(protocol::status_error(code, &message)),
+        other => Err(protocol::unexpected(&other)),
+    }
+}
+
+/// Unpacks a received zip archive into the destination, no larger than it was
+/// approved at, and removes it. Returns what was extracted, for the sender.
+async fn extract_zip(path: &Path, destination: &Destination, policy: ConflictPolicy, approved: Option<u64>) -> std::io::Result<String> {
+    let limits = within(destination.limits, approved);
+    let archive = path.to_path_buf();
+    let root = destination.root.clone();
+    let max_size = destination.max_size;
+    let extracted = tokio::task::spawn_blocking(move || archive::extract_zip(&archive, &root, policy, limits, max_size))
+        .await
+        .map_err(std::io::Error::other);
+    let _ = tokio::fs::remove_file(path).await;
+    let extracted = extracted??;
+
+    say!("Extracted {} files ({} bytes) into {:?}", extracted.files, extracted.bytes, destination.root);
+    Ok(describe_extracted(&extracted))
+}
+
+fn describe_extracted(extracted: &Extracted) -> String {
+    let mut note = format!("extracted {} files ({} bytes)", extracted.files, extracted.bytes);
+    if extracted.skipped > 0 {
+        note.push_str(&format!(", skipping {}", extracted.skipped));
+    }
+    note
+}
+
+fn over_limit(name: &str, size: u64, max_size: u64) -> std::io::Error {
+    std::io::Error::new(
+        std::io::ErrorKind::PermissionDenied,
+        format!("{:?} is {} bytes, over the {} byte limit", name, size, max_size),
+    )
+}
+
+/// Makes a rename durable by syncing the directory that holds it. Only possible,
+/// and only needed, on Unix.
+async fn sync_parent(path: &Path) {
+    #[cfg(unix)]
+    if let Some(parent) = path.parent().filter(|parent| !parent.as_os_str().is_empty()) {
+        if let Ok(dir) = tokio::fs::File::open(parent).await {
+            let _ = dir.sync_all().await;
+        }
+    }
+    #[cfg(not(unix))]
+    let _ = path;
+}
+
+/// Applies the conflict policy when the destination already exists, just as
+/// for the files in an archive. Returns the path to write to, or `None` once
+/// the sender has been told the file is skipped.
+async fn resolve_conflict(
+    stream: &mut Stream,
+    policy: ConflictPolicy,
+    header: &FileHeader,
+    path: PathBuf,
+) -> tokio::io::Result<Option<PathBuf>> {
+    match conflict::resolve(path.clone(), policy, header.mtime) {
+        Ok(Some(resolved)) => {
+            if resolved != path {
+                say!("{:?} already exists, saving as {:?}", path, resolved);
+            }
+            Ok(Some(resolved))
+        }
+        Ok(None) => {
+            // Let the sender compare against what we already have without
+            // sending any data. A link is never read through.
+            let (size, sha256) = match tokio::fs::symlink_metadata(&path).await {
+                Ok(existing) if existing.is_file() => match resume::hash_prefix(&path, existing.len()).await {
+                    Ok(hasher) => (existing.len(), hasher.finalize().into()),
+                    Err(e) => return Err(reject(stream, StatusCode::WriteError, e).await),
+                },
+                _ => (0, Sha256::new().finalize().into()),
+            };
+            stream.send(Message::Exists { size, sha256 }).await?;
+            say!("Skipped {:?}: already exists", path);
+            Ok(None)
+        }
+        Err(e) => Err(reject(stream, StatusCode::Rejected, e).await),
+    }
+}
+
+async fn receive_directory(stream: &mut Stream, name: &str, destination: &Destination, peer: &str) -> tokio::io::Result<Outcome> {
+    if let Err(e) = check_approved(destination, name, None) {
+        return Err(reject(stream, StatusCode::Rejected, e).await);
+    }
+    let path = match resolve_path(destination, name, peer) {
+        Ok(path) => path,
+        Err(e) => return Err(reject(stream, StatusCode::Rejected, e).await),
+    };
+    if let Err(e) = tokio::fs::create_dir_all(&path).await {
+        return Err(reject(stream, StatusCode::WriteError, e).await);
+    }
+    stream.send_status(StatusCode::Ok, "").await?;
+    say!("Directory created at {:?}", path);
+    Ok(Outcome::Created)
+}
+
+/// Recreates a symbolic link, as long as it points somewhere inside the destination.
+async fn receive_symlink(
+    stream: &mut Stream,
+    name: &str,
+    target: &str,
+    on_conflict: Option<ConflictPolicy>,
+    destination: &Destination,
+    peer: &str,
+) -> tokio::io::Result<Outcome> {
+    if let Err(e) = check_approved(destination, name, Some(0)) {
+        return Err(reject(stream, StatusCode::Rejected, e).await);
+    }
+    let path = match resolve_link_path(destination, name, peer) {
+        Ok(path) => path,
+        Err(e) => return Err(reject(stream, StatusCode::Rejected, e).await),
+    };
+    if !paths::link_stays_within(Path::new(name), target) {
+        say_err!("Rejected symbolic link {:?} from {}: its target {:?} is outside the destination", name, peer, target);
+        let error = std::io::Error::new(std::io::ErrorKind::PermissionDenied, format!("{:?} points to {:?}, outside the destination", name, target));
+        return Err(reject(stream, StatusCode::Rejected, error).await);
+    }
+    let policy = on_conflict.unwrap_or(destination.on_conflict);
+    let outcome = place_link(stream, name, path, policy, None, |partial| paths::symlink(target, partial)).await?;
+    say!("Symbolic link {:?} -> {:?} received", name, target);
+    Ok(outcome)
+}
+
+/// Gives a file received earlier in the batch another name.
+async fn receive_hard_link(
+    stream: &mut Stream,
+    name: &str,
+    target: &str,
+    on_conflict: Option<ConflictPolicy>,
+    destination: &Destination,
+    saved: &Saved,
+    peer: &str,
+) -> tokio::io::Result<Outcome> {
+    if let Err(e) = check_approved(destination, name, Some(0)) {
+        return Err(reject(stream, StatusCode::Rejected, e).await);
+    }
+    let path = match resolve_link_path(destination, name, peer) {
+        Ok(path) => path,
+        Err(e) => return Err(reject(stream, StatusCode::Rejected, e).await),
+    };
+    // Only files from this very batch can be linked to, never anything else in the destination.
+    let Some(original) = saved.lock().unwrap().get(target).cloned() else {
+        let error = std::io::Error::new(std::io::ErrorKind::NotFound, format!("{:?} was not received in this batch", target));
+        return Err(reject(stream, StatusCode::Rejected, error).await);
+    };
+    let policy = on_conflict.unwrap_or(destination.on_conflict);
+    let mtime = std::fs::metadata(&original).and_then(|metadata| metadata.modified()).ok();
+    let outcome = place_link(stream, name, path, policy, mtime, |partial| std::fs::hard_link(&original, partial)).await?;
+    say!("Hard link {:?} to {:?} received", name, target);
+    Ok(outcome)
+}
+
+/// Has `create` make a link next to `path`, then puts it in place in one step,
+/// replacing whatever link or file the conflict policy lets it.
+async fn place_link(
+    stream: &mut Stream,
+    name: &str,
+    path: PathBuf,
+    policy: ConflictPolicy,
+    mtime: Option<SystemTime>,
+    create: impl FnOnce(&Path) -> std::io::Result<()>,
+) -> tokio::io::Result<Outcome> {
+    if let Some(parent) = path.parent() {
+        if let Err(e) = tokio::fs::create_dir_all(parent).await {
+            return Err(reject(stream, StatusCode::WriteError, e).await);
+        }
+    }
+    let placed = match conflict::resolve(path.clone(), policy, mtime) {
+        Ok(Some(placed)) => placed,
+        Ok(None) => {
+            stream.send_status(StatusCode::Ok, "kept the one it had").await?;
+            say!("Skipped {:?}: already exists", path);
+            return Ok(Outcome::Skipped);
+        }
+        Err(e) => return Err(reject(stream, StatusCode::Rejected, e).await),
+    };
+    let partial = resume::partial_path(&placed);
+    let created = match std::fs::remove_file(&partial) {
+        Err(e) if e.kind() != std::io::ErrorKind::NotFound => Err(e),
+        _ => create(&partial).and_then(|()| std::fs::rename(&partial, &placed)),
+    };
+    if let Err(e) = created {
+        let _ = std::fs::remove_file(&partial);
+        return Err(reject(stream, StatusCode::WriteError, e).await);
+    }
+    sync_parent(&placed).await;
+
+    let note = if placed == path { String::new() } else { format!("saved it as '{}'", saved_as(name, &placed)) };
+    stream.send_status(StatusCode::Ok, note).await?;
+    Ok(Outcome::Received { bytes: 0 })
+}
+
+/// The name an entry sent as `name` was saved under at `path`, for the sender.
+fn saved_as(name: &str, path: &Path) -> String {
+    let saved_as = path.file_name().unwrap_or_default().to_string_lossy();
+    match name.rsplit_once('/') {
+        Some((parent, _)) => format!("{}/{}", parent, saved_as),
+        None => saved_as.into_owned(),
+    }
+}
+
+/// Refuses entries that were not in an approved batch, and files that grew
+/// beyond the size they were approved at.
+fn check_approved(destination: &Destination, name: &str, size: Option<u64>) -> std::io::Result<()> {
+    let Some(approved) = &destination.approved else {
+        return Ok(());
+    };
+    let allowed = match (approved.get(name), size) {
+        (Some(Some(approved_size)), Some(size)) => size <= *approved_size,
+        (Some(None), None) => true,
+        _ => false,
+    };
+    if allowed {
+        Ok(())
+    } else {
+        Err(std::io::Error::new(std::io::ErrorKind::PermissionDenied, format!("{:?} is not part of the accepted batch", name)))
+    }
+}
+
+/// The size an archive was approved at, refusing it if it was not in the
+/// approved batch. `None` when the batch did not have to be approved.
+fn approved_size(destination: &Destination, name: &str) -> std::io::Result<Option<u64>> {
+    let Some(approved) = &destination.approved else {
+        return Ok(None);
+    };
+    match approved.get(name) {
+        Some(Some(size)) => Ok(Some(*size)),
+        _ => Err(std::io::Error::new(std::io::ErrorKind::PermissionDenied, format!("{:?} is not part of the accepted batch", name))),
+    }
+}
+
+/// `limits`, with what an archive unpacks to held to the size it was approved at.
+fn within(limits: Extraction, approved: Option<u64>) -> Extraction {
+    match approved {
+        Some(size) => Extraction { max_bytes: limits.max_bytes.min(size), ..limits },
+        None => limits,
+    }
+}
+
+/// Where an entry with the given relative name is saved. Names that could
+/// escape the destination directory are logged and refused.
+fn resolve_path(destination: &Destination, name: &str, peer: &str) -> std::io::Result<PathBuf> {
+    paths::sanitize(name)
+        .and_then(|relative| paths::resolve(&destination.root, &relative))
+        .map_err(|reason| unsafe_path(name, peer, reason))
+}
+
+/// Like `resolve_path`, but for a link, which replaces a link already at its
+/// name rather than being written through it. Only what leads up to it has to
+/// be free of links.
+fn resolve_link_path(destination: &Destination, name: &str, peer: &str) -> std::io::Result<PathBuf> {
+    paths::sanitize(name)
+        .and_then(|relative| {
+            let parent = paths::resolve(&destination.root, relative.parent().unwrap_or(Path::new("")))?;
+            Ok(parent.join(relative.file_name().unwrap_or_default()))
+        })
+        .map_err(|reason| unsafe_path(name, peer, reason))
+}
+
+fn unsafe_path(name: &str, peer: &str, reason: String) -> std::io::Error {
+    say_err!("Rejected unsafe path {:?} from {}: {}", name, peer, reason);
+    std::io::Error::new(std::io::ErrorKind::PermissionDenied, format!("unsafe path {:?}: {}", name, reason))
+}
+
+/// Reports a failed or refused transfer to the sender. Anything it still sends
+/// on the stream afterwards is discarded once the stream is dropped.
+async fn reject(stream: &Stream, code: StatusCode, error: std::io::Error) -> std::io::Error {
+    let _ = stream.send_status(code, error.to_string()).await;
+    error
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mux;
+
+    const LIMITS: Extraction = Extraction { max_bytes: 1024 * 1024, max_ratio: 100 };
+
+    /// Starts receiving a file announced at `size` into a directory of its own,
+    /// returning the sender's end of the stream and the receiver's result.
+    async fn receiving(name: &str, size: u64) -> (PathBuf, Session, Stream, tokio::task::JoinHandle<tokio::io::Result<Outcome>>) {
+        let root = std::env::temp_dir().join(format!("streamline-receive-{}-{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&root);
+        let destination = Destination {
+            root: root.clone(),
+            on_conflict: ConflictPolicy::Fail,
+            max_size: None,
+            approved: None,
+            extract: false,
+            limits: LIMITS,
+            preserve: Preserve { times: false, permissions: false, xattrs: false },
+            dedup: None,
+        };
+        let (sending, received) = tokio::io::duplex(64 * 1024);
+        let (reader, writer) = tokio::io::split(sending);
+        let sender = mux::start(reader, writer, None, 0);
+        let (reader, writer) = tokio::io::split(received);
+        let receiver = mux::start(reader, writer, None, 0);
+
+        let mut stream = sender.open();
+        let header = FileHeader {
+            name: name.to_string(),
+            size: Some(size),
+            mtime: None,
+            on_conflict: None,
+            archive: None,
+            atime: None,
+            mode: None,
+            xattrs: Vec::new(),
+            sha256: None,
+        };
+        stream.send(Message::Header(header)).await.unwrap();
+        let result = tokio::spawn(async move {
+            let (incoming, opening) = receiver.accept().await.unwrap();
+            receive_entry(incoming, opening, &destination, &Saved::default(), "peer").await
+        });
+        assert!(matches!(stream.recv().await.unwrap(), Message::Offer { offset: 0, .. }));
+        stream.send(Message::Start { offset: 0, codec: Codec::None }).await.unwrap();
+        (root, sender, stream, result)
+    }
+
+    #[tokio::test]
+    async fn holes_arrive_as_zeros() {
+        let (root, _sender, stream, result) = receiving("holes", 512 * 1024 + 4).await;
+        stream.send(Message::Extent { offset: 512 * 1024 }).await.unwrap();
+        stream.send(Message::Data(b"data".to_vec())).await.unwrap();
+        let mut content = vec![0; 512 * 1024];
+        content.extend_from_slice(b"data");
+        stream.send(Message::End { sha256: Sha256::digest(&content).into() }).await.unwrap();
+
+        assert!(matches!(result.await.unwrap(), Ok(Outcome::Received { bytes }) if bytes == content.len() as u64));
+        assert_eq!(std::fs::read(root.join("holes")).unwrap(), content);
+        std::fs::remove_dir_all(root).unwrap();
+    }
+
+    #[tokio::test]
+    async fn holes_past_the_limit_are_refused_before_they_are_hashed() {
+        // Hashing this many zeros would take years.
+        let (root, _sender, stream, result) = receiving("huge", 1 << 62).await;
+        stream.send(Message::Extent { offset: 1 << 62 }).await.unwrap();
+
+        let error = tokio::time::timeout(std::time::Duration::from_secs(10), result).await.unwrap().unwrap().unwrap_err();
+        assert!(error.to_string().contains("bytes of holes allowed"), "{}", error);
+        assert!(!root.join("huge").exists());
+        std::fs::remove_dir_all(root).unwrap();
+    }
+}
@@ -0,0 +1,207 @@
+use std::collections::{BTreeMap, HashSet};
+use std::path::{Path, PathBuf};
+use sha2::{Sha256, Digest};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+use crate::CHUNK_SIZE;
+use crate::walk::{Entry, EntryKind};
+
+const PARTIAL_SUFFIX: &str = "streamline-tmp";
+const STATE_SUFFIX: &str = "streamline-resume";
+
+/// Progress of a partially received file, kept next to it until the transfer completes.
+pub struct PartialState {
+    pub size: u64,
+    pub offset: u64,
+}
+
+/// The hidden file an incoming transfer is written to before it replaces `destination`.
+pub fn partial_path(destination: &Path) -> PathBuf {
+    hidden_sibling(destination, PARTIAL_SUFFIX)
+}
+
+pub fn state_path(destination: &Path) -> PathBuf {
+    hidden_sibling(destination, STATE_SUFFIX)
+}
+
+/// Whether a file name is one of the hidden files kept for a transfer in progress.
+pub fn is_partial_name(name: &str) -> bool {
+    name.starts_with('.') && [PARTIAL_SUFFIX, STATE_SUFFIX].iter().any(|suffix| name.ends_with(&format!(".{}", suffix)))
+}
+
+fn hidden_sibling(destination: &Path, suffix: &str) -> PathBuf {
+    let name = destination.file_name().unwrap_or_default().to_string_lossy();
+    destination.with_file_name(format!(".{}.{}", name, suffix))
+}
+
+/// Returns the recorded state, or `None` if there is none or it cannot be parsed.
+pub async fn load_state(destination: &Path) -> Option<PartialState> {
+    let contents = tokio::fs::read_to_string(state_path(destination)).await.ok()?;
+    let fields: BTreeMap<&str, u64> = contents
+        .lines()
+        .filter_map(|line| line.split_once('='))
+        .filter_map(|(key, value)| Some((key.trim(), value.trim().parse().ok()?)))
+        .collect();
+    Some(PartialState {
+        size: *fields.get("size")?,
+        offset: *fields.get("offset")?,
+    })
+}
+
+pub async fn save_state(destination: &Path, state: &PartialState) -> tokio::io::Result<()> {
+    let contents = format!("size={}\noffset={}\n", state.size, state.offset);
+    tokio::fs::write(state_path(destination), contents).await
+}
+
+pub async fn remove_state(destination: &Path) {
+    let _ = tokio::fs::remove_file(state_path(destination)).await;
+}
+
+/// Removes the partial file and its state, so the next attempt starts from scratch.
+pub async fn discard_partial(destination: &Path) {
+    let _ = tokio::fs::remove_file(partial_path(destination)).await;
+    remove_state(destination).await;
+}
+
+/// Hashes the first `len` bytes of a file, returning the hasher so the caller can
+/// carry on with the rest of the content.
+pub async fn hash_prefix(path: &Path, len: u64) -> tokio::io::Result<Sha256> {
+    let mut file = tokio::fs::File::open(path).await?;
+    let mut hasher = Sha256::new();
+    let mut remaining = len;
+    let mut buffer = vec![0; CHUNK_SIZE];
+    while remaining > 0 {
+        let want = CHUNK_SIZE.min(remaining as usize);
+        let n = file.read(&mut buffer[..want]).await?;
+        if n == 0 {
+            return Err(std::io::Error::new(std::io::ErrorKind::UnexpectedEof, "file is shorter than the resume offset"));
+        }
+        hasher.update(&buffer[..n]);
+        remaining -= n as u64;
+    }
+    Ok(hasher)
+}
+
+/// A batch `client` was asked to send, journalled as it goes so that
+/// `client --resume` can pick it back up. Every batch has a journal of its
+/// own, named after where it goes and when it started, so clients running side
+/// by side never write over each other's.
+pub struct BatchJournal {
+    pub address: String,
+    pub pending: Vec<Entry>,
+    /// How many entries had already been delivered when the journal was loaded.
+    pub done: usize,
+    path: PathBuf,
+    /// The journal, open for appending, and how many entries are yet to be delivered.
+    progress: tokio::sync::Mutex<(tokio::fs::File, usize)>,
+}
+
+fn journal_dir() -> PathBuf {
+    crate::streamline_dir().join("batches")
+}
+
+/// What the journals of batches sent to `address` are named after.
+fn destination_key(address: &str) -> String {
+    Sha256::digest(address.as_bytes())[..8].iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+impl BatchJournal {
+    /// Starts the journal of a new batch: its address and entries, then the
+    /// name of each entry as it is delivered.
+    pub async fn create(address: &str, entries: Vec<Entry>) -> tokio::io::Result<Self> {
+        let mut contents = format!("address {}\n", address);
+        for entry in &entries {
+            contents.push_str(&format!("pending {}\n", format_entry(entry)));
+        }
+        let started = std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).unwrap_or_default();
+        let path = journal_dir().join(format!("{}-{}-{}", destination_key(address), started.as_nanos(), std::process::id()));
+        tokio::fs::create_dir_all(journal_dir()).await?;
+        tokio::fs::write(&path, contents).await?;
+        BatchJournal::open(address.to_string(), entries, 0, path).await
+    }
+
+    /// Loads the journal of the batch most recently sent, to `address` if given,
+    /// that was interrupted.
+    pub async fn load(address: Option<&str>) -> tokio::io::Result<Self> {
+        let none = |reason: String| std::io::Error::new(std::io::ErrorKind::NotFound, format!("no interrupted batch to resume ({})", reason));
+        let prefix = address.map(|address| format!("{}-", destination_key(address)));
+        let mut latest = None;
+        let mut journals = tokio::fs::read_dir(journal_dir()).await.map_err(|e| none(e.to_string()))?;
+        while let Some(journal) = journals.next_entry().await? {
+            let name = journal.file_name();
+            if prefix.as_ref().is_some_and(|prefix| !name.to_string_lossy().starts_with(prefix.as_str())) {
+                continue;
+            }
+            let modified = journal.metadata().await?.modified()?;
+            if latest.as_ref().is_none_or(|(latest, _)| modified > *latest) {
+                latest = Some((modified, journal.path()));
+            }
+        }
+        let Some((_, path)) = latest else {
+            return Err(none(match address {
+                Some(address) => format!("none was sent to {}", address),
+                None => "every batch was delivered".to_string(),
+            }));
+        };
+
+        let contents = tokio::fs::read_to_string(&path).await?;
+        let mut address = String::new();
+        let mut entries = Vec::new();
+        let mut delivered = HashSet::new();
+        for line in contents.lines() {
+            match line.split_once(' ') {
+                Some(("address", value)) => address = value.to_string(),
+                Some(("pending", entry)) => entries.extend(parse_entry(entry)),
+                Some(("done", name)) => {
+                    delivered.insert(name);
+                }
+                _ => {}
+            }
+        }
+        if address.is_empty() {
+            return Err(std::io::Error::new(std::io::ErrorKind::InvalidData, format!("batch journal {:?} has no address", path)));
+        }
+        let (done, pending): (Vec<_>, Vec<_>) = entries.into_iter().partition(|entry| delivered.contains(entry.name.as_str()));
+        BatchJournal::open(address, pending, done.len(), path).await
+    }
+
+    async fn open(address: String, pending: Vec<Entry>, done: usize, path: PathBuf) -> tokio::io::Result<Self> {
+        let file = tokio::fs::OpenOptions::new().append(true).open(&path).await?;
+        let remaining = pending.len();
+        Ok(BatchJournal { address, pending, done, path, progress: tokio::sync::Mutex::new((file, remaining)) })
+    }
+
+    /// Records a delivered entry; the journal is deleted once nothing is pending.
+    pub async fn complete(&self, name: &str) -> tokio::io::Result<()> {
+        let mut progress = self.progress.lock().await;
+        let (file, remaining) = &mut *progress;
+        *remaining = remaining.saturating_sub(1);
+        if *remaining == 0 {
+            return tokio::fs::remove_file(&self.path).await;
+        }
+        file.write_all(format!("done {}\n", name).as_bytes()).await
+    }
+}
+
+// Entries are journalled as `<kind> <name>\t<path>`. `collect_entries` never
+// produces names with control characters, so the first tab is a safe separator.
+fn format_entry(entry: &Entry) -> String {
+    let kind = match entry.kind {
+        EntryKind::File => "file",
+        EntryKind::Directory => "dir",
+        EntryKind::Symlink => "link",
+    };
+    format!("{} {}\t{}", kind, entry.name, entry.path.display())
+}
+
+fn parse_entry(line: &str) -> Option<Entry> {
+    let (kind, rest) = line.split_once(' ')?;
+    let (name, path) = rest.split_once('\t')?;
+    let kind = match kind {
+        "file" => EntryKind::File,
+        "dir" => EntryKind::Directory,
+        "link" => EntryKind::Symlink,
+        _ => return None,
+    };
+    Some(Entry { path: PathBuf::from(path), name: name.to_string(), kind })
+}
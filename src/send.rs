@@ -0,0 +1,606 @@
+use std::collections::{HashSet, VecDeque};
+use std::fs::File;
+use std::io::{BufReader, Read, Seek, SeekFrom};
+use std::path::Path;
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime};
+use futures::future::join_all;
+use indicatif::HumanBytes;
+use sha2::{Sha256, Digest};
+use tokio::sync::{mpsc, Semaphore};
+
+use crate::archive::{self, ArchiveFormat};
+use crate::compress::{self, Codec, Compression, Mode};
+use crate::conflict::ConflictPolicy;
+use crate::delta::{self, BlockSum};
+use crate::mux::{Session, Stream};
+use crate::protocol::{self, FileHeader, Message, StatusCode};
+use crate::resume::{self, BatchJournal};
+use crate::sparse::{self, Holes};
+use crate::walk::{Entry, EntryKind};
+use crate::{metadata, progress, CHUNK_SIZE, MAX_PARALLEL_TRANSFERS};
+
+/// Settings shared by every transfer in a batch.
+pub struct SendOptions {
+    pub on_conflict: Option<ConflictPolicy>,
+    pub compression: Compression,
+    /// Send extended attributes along with each file (`--xattrs`).
+    pub xattrs: bool,
+    /// Send a file with several names once, and have the receiver link its
+    /// other names to it (`--links preserve`).
+    pub hard_links: bool,
+}
+
+/// What became of an entry the receiver did not refuse.
+enum Sent {
+    /// Delivered, with how compression went if it was used.
+    Delivered(Option<Traffic>),
+    /// Skipped, because the receiver already had a file by that name.
+    Skipped,
+}
+
+/// How much of a compressed file's body went over the wire, before and after compression.
+struct Traffic {
+    raw: u64,
+    wire: u64,
+}
+
+/// Where the body of a transfer comes from.
+enum Body {
+    /// A file, read from `position` on. Its holes are skipped if it has any.
+    File { reader: BufReader<File>, position: u64, holes: Option<Holes> },
+    /// A file as it differs from the receiver's copy.
+    Delta(mpsc::Receiver<std::io::Result<delta::Op>>),
+    /// An archive, in chunks as it is built.
+    Archive(mpsc::Receiver<std::io::Result<Vec<u8>>>),
+}
+
+/// A piece of a body, in the order the receiver writes them.
+enum Piece {
+    /// Bytes to send, which are empty at the end.
+    Data(Vec<u8>),
+    /// A hole in a sparse file, up to where the data resumes.
+    Hole(u64),
+    /// Bytes the receiver has already, as `count` blocks of its copy from
+    /// `index` on.
+    Blocks { index: u64, count: u32, data: Vec<u8> },
+}
+
+impl Body {
+    /// Reads the next piece, with at most `CHUNK_SIZE` bytes to send.
+    async fn next_piece(&mut self) -> std::io::Result<Piece> {
+        match self {
+            Body::File { reader, position, holes } => {
+                let mut want = CHUNK_SIZE;
+                if let Some(holes) = holes {
+                    if holes.data_left(*position) == 0 {
+                        let hole_end = holes.skip(reader.get_ref(), *position)?;
+                        // Looking for holes moves the file's position; reading goes on from here.
+                        *position = hole_end.unwrap_or(*position);
+                        reader.seek(SeekFrom::Start(*position))?;
+                        if let Some(hole_end) = hole_end {
+                            return Ok(Piece::Hole(hole_end));
+                        }
+                    }
+                    want = want.min(holes.data_left(*position) as usize);
+                }
+                let mut buffer = vec![0; want];
+                let n = reader.read(&mut buffer)?;
+                buffer.truncate(n);
+                *position += n as u64;
+                Ok(Piece::Data(buffer))
+            }
+            Body::Delta(ops) => match ops.recv().await.transpose()? {
+                Some(delta::Op::Literal(data)) => Ok(Piece::Data(data)),
+                Some(delta::Op::Blocks { index, count, data }) => Ok(Piece::Blocks { index, count, data }),
+                None => Ok(Piece::Data(Vec::new())),
+            },
+            Body::Archive(chunks) => Ok(Piece::Data(chunks.recv().await.unwrap_or(Ok(Vec::new()))?)),
+        }
+    }
+}
+
+/// The manifest describing `entries`: files with their size, empty directories
+/// with `None`. Links are announced as empty files.
+pub fn manifest(entries: &[Entry]) -> Vec<(String, Option<u64>)> {
+    entries
+        .iter()
+        .map(|entry| {
+            // A file that can't be read now fails when it is sent, with a better error.
+            let size = match entry.kind {
+                EntryKind::File => Some(std::fs::metadata(&entry.path).map(|metadata| metadata.len()).unwrap_or(0)),
+                EntryKind::Directory => None,
+                EntryKind::Symlink => Some(0),
+            };
+            (entry.name.clone(), size)
+        })
+        .collect()
+}
+
+/// Tells the receiver what the batch holds and waits for it to be accepted,
+/// which may take a person answering on the other end.
+pub async fn announce_batch(session: &Session, manifest: &[(String, Option<u64>)]) -> tokio::io::Result<()> {
+    for (name, size) in manifest {
+        let entry = Message::ManifestEntry { name: name.clone(), size: size.unwrap_or(0), is_dir: size.is_none() };
+        session.send_control(entry).await?;
+    }
+    session.send_control(Message::ManifestEnd).await?;
+    let decision = match tokio::time::timeout(Duration::from_secs(1), session.recv_control()).await {
+        Ok(decision) => decision?,
+        Err(_) => {
+            say!("Waiting for the receiver to accept the batch...");
+            session.recv_control().await?
+        }
+    };
+    match decision {
+        Message::Status { code: StatusCode::Ok, .. } => Ok(()),
+        Message::Status { code, message } => Err(protocol::status_error(code, &message)),
+        other => Err(protocol::unexpected(&other)),
+    }
+}
+
+/// Sends `entries` over `session`, several at a time, then ends the batch and
+/// prints the receiver's summary of it. `journal`, if given, is updated as each
+/// entry completes.
+pub async fn send_batch(
+    session: &Session,
+    entries: Vec<Entry>,
+    options: &SendOptions,
+    peer: &str,
+    journal: Option<Arc<BatchJournal>>,
+) -> tokio::io::Result<()> {
+    let (entries, other_names) = if options.hard_links { split_hard_links(entries) } else { (entries, Vec::new()) };
+    let semaphore = Semaphore::new(MAX_PARALLEL_TRANSFERS);
+
+    // With `original`, a file is sent as another name for that one.
+    let transfer = |entry: Entry, original: Option<String>| {
+        let semaphore = &semaphore;
+        let journal = journal.clone();
+        async move {
+            let _permit = semaphore.acquire().await.unwrap();
+            let mut stream = session.open();
+            let sent = match (entry.kind, original) {
+                (EntryKind::File, Some(original)) => send_hard_link(&mut stream, &entry.name, &original, options, peer).await?,
+                (EntryKind::File, None) => send_file(&mut stream, &entry.path, &entry.name, options, session.capabilities(), peer).await?,
+                (EntryKind::Directory, _) => send_directory(&mut stream, &entry.name, peer).await?,
+                (EntryKind::Symlink, _) => send_symlink(&mut stream, &entry, options, peer).await?,
+            };
+            if let Some(journal) = journal {
+                if let Err(e) = journal.complete(&entry.name).await {
+                    say_err!("Warning: could not update batch journal: {}", e);
+                }
+            }
+            Ok::<_, std::io::Error>((entry.name, sent))
+        }
+    };
+
+    let mut results = join_all(entries.into_iter().map(|entry| transfer(entry, None))).await;
+    // Other names are linked once the file itself is there. If it never
+    // arrived, or the receiver kept a copy of its own, they are sent in full.
+    let delivered: HashSet<String> = results
+        .iter()
+        .filter_map(|result| match result {
+            Ok((name, Sent::Delivered(_))) => Some(name.clone()),
+            _ => None,
+        })
+        .collect();
+    let linked = other_names.into_iter().map(|(entry, original)| {
+        let original = delivered.contains(&original).then_some(original);
+        transfer(entry, original)
+    });
+    results.extend(join_all(linked).await);
+
+    let total = results.len();
+    let mut failed = 0;
+    let mut compressed = Vec::new();
+
+    for result in results {
+        match result {
+            Ok((_, Sent::Delivered(traffic))) => compressed.extend(traffic),
+            Ok((_, Sent::Skipped)) => {}
+            Err(e) => {
+                say_err!("Error sending file: {}", e);
+                failed += 1;
+            }
+        }
+    }
+
+    end_batch(session, peer, &compressed).await?;
+
+    if failed > 0 {
+        return Err(std::io::Error::other(format!("{} of {} files failed", failed, total)));
+    }
+    Ok(())
+}
+
+/// Sends `entries` as a single archive called `name`, built as it is sent, then
+/// ends the batch like `send_batch`.
+pub async fn send_archive(
+    session: &Session,
+    name: &str,
+    format: ArchiveFormat,
+    entries: Vec<Entry>,
+    options: &SendOptions,
+    peer: &str,
+) -> tokio::io::Result<()> {
+    let mut stream = session.open();
+    let sent = send_archive_body(&mut stream, name, format, entries, options, session.capabilities(), peer).await;
+    drop(stream);
+    end_batch(session, peer, sent.as_ref().map_or(&[], |traffic| traffic.as_slice())).await?;
+    sent.map(|_| ())
+}
+
+async fn send_archive_body(
+    stream: &mut Stream,
+    name: &str,
+    format: ArchiveFormat,
+    entries: Vec<Entry>,
+    options: &SendOptions,
+    capabilities: u32,
+    peer: &str,
+) -> tokio::io::Result<Option<Traffic>> {
+    stream.send(Message::Header(FileHeader {
+        name: name.to_string(),
+        size: None,
+        mtime: Some(SystemTime::now()),
+        on_conflict: options.on_conflict,
+        archive: Some(format),
+        atime: None,
+        mode: None,
+        xattrs: Vec::new(),
+        sha256: None,
+    })).await?;
+    // An archive is built afresh each time, so there is never anything to resume from.
+    match stream.recv().await? {
+        Message::Offer { .. } => {}
+        Message::Exists { size, .. } => {
+            say!("Skipped '{}': already on {} ({} bytes)", name, peer, size);
+            return Ok(None);
+        }
+        Message::Status { code, message } => {
+            return Err(protocol::status_error(code, &format!("'{}': {}", name, message)));
+        }
+        other => return Err(protocol::unexpected(&other)),
+    }
+
+    let files = entries.len();
+    let body = Body::Archive(archive::build(format, entries));
+    let traffic = send_body(stream, name, body, None, (0, Sha256::new()), options.compression, capabilities).await?;
+
+    let note = stream.recv_status().await
+        .map_err(|e| std::io::Error::new(e.kind(), format!("'{}': {}", name, e)))?;
+    if note.is_empty() {
+        say!("Archive integrity verified: '{}' ({} entries) sent to {}", name, files, peer);
+    } else {
+        say!("Archive integrity verified: '{}' ({} entries) sent to {}, which {}", name, files, peer, note);
+    }
+    Ok(traffic)
+}
+
+/// Tells the receiver the batch is over and prints its summary of it, along
+/// with how much was saved by compressing `compressed`.
+async fn end_batch(session: &Session, peer: &str, compressed: &[Traffic]) -> tokio::io::Result<()> {
+    session.send_control(Message::BatchEnd).await?;
+    let summary = match session.recv_control().await? {
+        Message::BatchSummary(summary) => summary,
+        other => return Err(protocol::unexpected(&other)),
+    };
+    say!(
+        "Batch complete on {}: {} received ({} bytes), {} skipped, {} failed",
+        peer, summary.received, summary.bytes, summary.skipped, summary.failed
+    );
+    if compressed.len() > 1 {
+        let raw: u64 = compressed.iter().map(|traffic| traffic.raw).sum();
+        let wire: u64 = compressed.iter().map(|traffic| traffic.wire).sum();
+        say!(
+            "Compressed {} files: {} sent as {} ({:.0}%)",
+            compressed.len(), HumanBytes(raw), HumanBytes(wire), wire as f64 * 100.0 / raw.max(1) as f64
+        );
+    }
+    Ok(())
+}
+
+/// Separates the files that are another name for a file earlier in the batch,
+/// each paired with the name of that file. Only Unix can tell.
+fn split_hard_links(entries: Vec<Entry>) -> (Vec<Entry>, Vec<(Entry, String)>) {
+    #[cfg(unix)]
+    {
+        use std::collections::hash_map::{Entry as Slot, HashMap};
+        use std::os::unix::fs::MetadataExt;
+
+        let mut first_names: HashMap<(u64, u64), String> = HashMap::new();
+        let mut unique = Vec::new();
+        let mut other_names = Vec::new();
+        for entry in entries {
+            let inode = std::fs::metadata(&entry.path)
+                .ok()
+                .filter(|metadata| entry.kind == EntryKind::File && metadata.nlink() > 1)
+                .map(|metadata| (metadata.dev(), metadata.ino()));
+            match inode.map(|inode| first_names.entry(inode)) {
+                Some(Slot::Occupied(first)) => other_names.push((entry, first.get().clone())),
+                Some(Slot::Vacant(slot)) => {
+                    slot.insert(entry.name.clone());
+                    unique.push(entry);
+                }
+                None => unique.push(entry),
+            }
+        }
+        (unique, other_names)
+    }
+    #[cfg(not(unix))]
+    (entries, Vec::new())
+}
+
+async fn send_directory(stream: &mut Stream, name: &str, peer: &str) -> tokio::io::Result<Sent> {
+    stream.send(Message::Directory { name: name.to_string() }).await?;
+    stream.recv_status().await
+        .map_err(|e| std::io::Error::new(e.kind(), format!("'{}': {}", name, e)))?;
+    say!("Directory '{}' created on {}", name, peer);
+    Ok(Sent::Delivered(None))
+}
+
+/// Sends a symbolic link as a link, for the receiver to recreate.
+async fn send_symlink(stream: &mut Stream, entry: &Entry, options: &SendOptions, peer: &str) -> tokio::io::Result<Sent> {
+    let target = std::fs::read_link(&entry.path)?;
+    let target = target.to_str().ok_or_else(|| {
+        std::io::Error::new(std::io::ErrorKind::InvalidData, format!("'{}' points to a name that is not valid UTF-8", entry.name))
+    })?;
+    let link = Message::Symlink { name: entry.name.clone(), target: target.to_string(), on_conflict: options.on_conflict };
+    send_link(stream, link, &entry.name, peer).await
+}
+
+/// Asks the receiver to give `original`, which it has just received, the name
+/// `name` as well.
+async fn send_hard_link(stream: &mut Stream, name: &str, original: &str, options: &SendOptions, peer: &str) -> tokio::io::Result<Sent> {
+    let link = Message::HardLink { name: name.to_string(), target: original.to_string(), on_conflict: options.on_conflict };
+    send_link(stream, link, name, peer).await
+}
+
+async fn send_link(stream: &mut Stream, link: Message, name: &str, peer: &str) -> tokio::io::Result<Sent> {
+    stream.send(link).await?;
+    let note = stream.recv_status().await
+        .map_err(|e| std::io::Error::new(e.kind(), format!("'{}': {}", name, e)))?;
+    if note.is_empty() {
+        say!("Link '{}' created on {}", name, peer);
+    } else {
+        say!("Link '{}' sent to {}, which {}", name, peer, note);
+    }
+    Ok(Sent::Delivered(None))
+}
+
+/// Sends one file, compressed if `options` ask for it and the peer's
+/// `capabilities` allow.
+async fn send_file(
+    stream: &mut Stream,
+    path: &Path,
+    file_name: &str,
+    options: &SendOptions,
+    capabilities: u32,
+    peer: &str,
+) -> tokio::io::Result<Sent> {
+    let file = File::open(path)?;
+    let metadata = file.metadata()?;
+    let file_size = metadata.len();
+    // A receiver that indexes its files by content may have this one already.
+    let sha256 = if capabilities & protocol::CAP_CONTENT_INDEX != 0 {
+        Some(resume::hash_prefix(path, file_size).await?.finalize().into())
+    } else {
+        None
+    };
+    stream.send(Message::Header(FileHeader {
+        name: file_name.to_string(),
+        size: Some(file_size),
+        mtime: metadata.modified().ok(),
+        on_conflict: options.on_conflict,
+        archive: None,
+        atime: metadata.accessed().ok(),
+        mode: metadata::mode(&metadata),
+        xattrs: if options.xattrs { metadata::read_xattrs(path) } else { Vec::new() },
+        sha256,
+    })).await?;
+
+    // A receiver about to replace a copy of its own describes that first.
+    let mut reply = stream.recv().await?;
+    let mut basis: Option<(u32, Vec<BlockSum>)> = None;
+    while let Message::Signatures { block_size, sums } = reply {
+        let (size, all) = basis.get_or_insert_with(|| (block_size, Vec::new()));
+        if block_size != *size
+            || !(delta::MIN_BLOCK_SIZE..=delta::MAX_BLOCK_SIZE).contains(&block_size)
+            || all.len() + sums.len() > delta::MAX_BLOCKS
+        {
+            let error = format!("'{}': receiver described its copy in blocks out of bounds", file_name);
+            return Err(std::io::Error::new(std::io::ErrorKind::InvalidData, error));
+        }
+        all.extend(sums);
+        reply = stream.recv().await?;
+    }
+
+    // Take up the receiver's offer to resume only if our copy starts with the same bytes.
+    let (offset, hasher) = match reply {
+        Message::Offer { offset: 0, .. } => (0, Sha256::new()),
+        Message::Offer { offset, sha256 } => match resume::hash_prefix(path, offset).await {
+            Ok(prefix) if prefix.clone().finalize()[..] == sha256 => (offset, prefix),
+            _ => (0, Sha256::new()),
+        },
+        Message::Exists { size, sha256 } => {
+            let identical = size == file_size
+                && resume::hash_prefix(path, size).await.is_ok_and(|local| local.finalize()[..] == sha256);
+            let comparison = if identical { "identical" } else { "differs from this copy" };
+            say!("Skipped '{}': already on {} ({} bytes, {})", file_name, peer, size, comparison);
+            return Ok(Sent::Skipped);
+        }
+        Message::AlreadyHave { saved_as } => {
+            if saved_as.is_empty() {
+                say!("Not sending '{}': {} already had the same content, and used it", file_name, peer);
+            } else {
+                say!("Not sending '{}': {} already had the same content, and used it for '{}'", file_name, peer, saved_as);
+            }
+            return Ok(Sent::Delivered(None));
+        }
+        Message::Status { code, message } => {
+            return Err(protocol::status_error(code, &format!("'{}': {}", file_name, message)));
+        }
+        other => return Err(protocol::unexpected(&other)),
+    };
+
+    let body = match basis.filter(|_| offset == 0) {
+        Some((block_size, sums)) => Body::Delta(delta::diff(file, block_size, sums)),
+        None => {
+            let mut reader = BufReader::new(file);
+            if offset > 0 {
+                reader.seek(SeekFrom::Start(offset))?;
+                say!("Resuming '{}' from byte {}", file_name, offset);
+            }
+            Body::File { reader, position: offset, holes: Holes::find(&metadata) }
+        }
+    };
+    let traffic = send_body(stream, file_name, body, Some(file_size), (offset, hasher), options.compression, capabilities).await?;
+
+    let saved_as = stream.recv_status().await
+        .map_err(|e| std::io::Error::new(e.kind(), format!("'{}': {}", file_name, e)))?;
+
+    if saved_as.is_empty() {
+        say!("File integrity verified: '{}' sent to {}", file_name, peer);
+    } else {
+        say!("File integrity verified: '{}' sent to {} and saved as '{}'", file_name, peer, saved_as);
+    }
+    Ok(Sent::Delivered(traffic))
+}
+
+/// Sends `body` from `offset` on, `hasher` holding the hash of what comes before
+/// it, then its SHA-256. Returns how compression went, if it was used.
+async fn send_body(
+    stream: &mut Stream,
+    name: &str,
+    mut body: Body,
+    size: Option<u64>,
+    (offset, mut hasher): (u64, Sha256),
+    compression: Compression,
+    capabilities: u32,
+) -> tokio::io::Result<Option<Traffic>> {
+    // Pieces are read ahead up to the first with some of the file in it, so
+    // that auto mode can tell from it whether the file is worth compressing.
+    let mut ahead = VecDeque::new();
+    loop {
+        let piece = match body.next_piece().await {
+            Ok(piece) => piece,
+            Err(e) => return Err(abandon(stream, name, e).await),
+        };
+        let hole = matches!(piece, Piece::Hole(_));
+        ahead.push_back(piece);
+        if !hole {
+            break;
+        }
+    }
+    let first_chunk = match ahead.back() {
+        Some(Piece::Data(data) | Piece::Blocks { data, .. }) => data.as_slice(),
+        _ => &[],
+    };
+    let codec = compression.choose(capabilities, first_chunk);
+    if compression.mode == Mode::Auto && codec == Codec::None && !first_chunk.is_empty() {
+        say!("Not compressing '{}': it looks compressed already", name);
+    }
+    stream.send(Message::Start { offset, codec }).await?;
+
+    let pb = match size {
+        Some(size) => progress::bar(size),
+        None => progress::counter(),
+    };
+    pb.set_position(offset);
+
+    let start_time = Instant::now();
+    let mut total_bytes = offset;
+    let mut wire_bytes = 0;
+    let mut hole_bytes = 0;
+    let mut reused_bytes = 0;
+
+    loop {
+        let piece = match ahead.pop_front() {
+            Some(piece) => piece,
+            None => match body.next_piece().await {
+                Ok(piece) => piece,
+                Err(e) => {
+                    pb.abandon();
+                    return Err(abandon(stream, name, e).await);
+                }
+            },
+        };
+        // The hash is always of the file itself, however it travels.
+        let (message, n) = match piece {
+            // Only where the data resumes is sent; the receiver leaves a hole up to it.
+            Piece::Hole(hole_end) => {
+                let n = hole_end - total_bytes;
+                hasher = sparse::hash_zeros(hasher, n).await?;
+                hole_bytes += n;
+                (Message::Extent { offset: hole_end }, n)
+            }
+            Piece::Blocks { index, count, data } => {
+                hasher.update(&data);
+                reused_bytes += data.len() as u64;
+                (Message::BlockRef { index, count }, data.len() as u64)
+            }
+            Piece::Data(buffer) if buffer.is_empty() => break,
+            Piece::Data(buffer) => {
+                hasher.update(&buffer);
+                let n = buffer.len();
+                let message = if codec == Codec::None {
+                    Message::Data(buffer)
+                } else {
+                    tokio::task::spawn_blocking(move || compress::pack(codec, compression.level, buffer))
+                        .await
+                        .map_err(std::io::Error::other)??
+                };
+                wire_bytes += match &message {
+                    Message::CompressedData { data, .. } => data.len(),
+                    _ => n,
+                } as u64;
+                (message, n as u64)
+            }
+        };
+
+        // The receiver only speaks before the end of the body if it gave up on the file.
+        if let Some(reply) = stream.send_unless_interrupted(message).await? {
+            pb.abandon();
+            return Err(given_up(name, reply));
+        }
+        total_bytes += n;
+        pb.set_position(total_bytes);
+    }
+    let hash = hasher.finalize();
+    stream.send(Message::End { sha256: hash.into() }).await?;
+
+    pb.finish_and_clear();
+
+    let duration = start_time.elapsed();
+    let speed = (total_bytes - offset) as f64 / duration.as_secs_f64() / 1024.0 / 1024.0; // MB/s
+    say!("Transfer complete in {:.2?}", duration);
+    say!("Average speed: {:.2} MB/s", speed);
+    if hole_bytes > 0 {
+        say!("Skipped {} of holes in '{}'", HumanBytes(hole_bytes), name);
+    }
+    let sent_bytes = total_bytes - offset - hole_bytes - reused_bytes;
+    if reused_bytes > 0 {
+        say!("Sent only the changes to '{}': {}, reusing {} the receiver had", name, HumanBytes(sent_bytes), HumanBytes(reused_bytes));
+    }
+    let traffic = (codec != Codec::None).then_some(Traffic { raw: sent_bytes, wire: wire_bytes });
+    if let Some(traffic) = traffic.as_ref().filter(|traffic| traffic.raw > 0) {
+        say!("Compressed with {}", compress::describe(codec, traffic.raw, traffic.wire));
+    }
+    Ok(traffic)
+}
+
+/// The error for a body the receiver gave up on, from what it said instead.
+fn given_up(name: &str, reply: Message) -> std::io::Error {
+    let error = match reply {
+        Message::Status { code, message } => protocol::status_error(code, &message),
+        other => protocol::unexpected(&other),
+    };
+    std::io::Error::new(error.kind(), format!("'{}': {}", name, error))
+}
+
+/// Tells the receiver the rest of the body is not coming, which it would
+/// otherwise wait for forever.
+async fn abandon(stream: &Stream, name: &str, error: std::io::Error) -> std::io::Error {
+    let _ = stream.send_status(StatusCode::ReadError, error.to_string()).await;
+    std::io::Error::new(error.kind(), format!("'{}': {}", name, error))
+}
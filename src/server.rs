@@ -0,0 +1,427 @@
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::net::{TcpListener, TcpStream};
+
+use crate::archive::{ArchiveFormat, Extraction};
+use crate::auth::{Operation, Token, Tokens};
+use crate::compress::Compression;
+use crate::conflict::ConflictPolicy;
+use crate::dedup::{ContentIndex, DedupMode};
+use crate::discovery::{self, Announcement};
+use crate::limits::{Admission, Cidr, Limits};
+use crate::mux::{self, Session};
+use crate::pairing::{self, Failed, PairingCode, SessionKeys, Side};
+use crate::protocol::{self, Message, StatusCode};
+use crate::metadata::Preserve;
+use crate::prompt::Prompt;
+use crate::receive::{self, Destination, Manifest};
+use crate::send::{self, SendOptions};
+use crate::tls::{self, Conn, ServerTls};
+use crate::{list, paths, resume, walk};
+
+/// Settings for `streamline server`, shared by every connection.
+pub struct ServerConfig {
+    pub output_path: Option<String>,
+    pub on_conflict: ConflictPolicy,
+    /// Directory clients may download from. Nothing in it is ever written to.
+    pub export: Option<PathBuf>,
+    /// `None` only when running with `--insecure`.
+    pub tls: Option<ServerTls>,
+    /// With `--code`, clients must pair with this code before anything else.
+    pub pairing: Option<PairingCode>,
+    /// With `--tokens`, every request must come with one of these tokens.
+    pub tokens: Option<Tokens>,
+    pub limits: Limits,
+    /// The name the server advertises on the local network, or `None` with `--no-discovery`.
+    pub name: Option<String>,
+    /// With `--ask`, every incoming batch waits for a yes on the terminal.
+    pub ask: Option<Prompt>,
+    /// How files sent to `get` clients are compressed.
+    pub compression: Compression,
+    /// With `--extract`, archives sent with `client --zip` are unpacked instead of stored.
+    pub extract: bool,
+    /// What one archive may unpack to.
+    pub extraction: Extraction,
+    /// Which attributes of received files are kept, all unless turned off with `--no-times` and the like.
+    pub preserve: Preserve,
+    /// With `--dedup`, files the output directory has already are taken from
+    /// there instead of being sent again.
+    pub dedup: Option<DedupMode>,
+}
+
+impl ServerConfig {
+    fn output_root(&self) -> PathBuf {
+        self.output_path.as_ref().map(PathBuf::from).unwrap_or_default()
+    }
+
+    /// The output directory, with the current directory listed as "." rather than "".
+    fn listing_root(&self) -> PathBuf {
+        match self.output_root() {
+            root if root.as_os_str().is_empty() => PathBuf::from("."),
+            root => root,
+        }
+    }
+}
+
+pub async fn start_server(address: &str, config: ServerConfig) -> tokio::io::Result<()> {
+    let listener = TcpListener::bind(address).await?;
+    println!("Server listening on {}", address);
+    match &config.tls {
+        Some(tls) => println!("TLS certificate fingerprint: {}", tls.fingerprint),
+        None => println!("Warning: running with --insecure; transfers are not encrypted"),
+    }
+    if let Some(pairing) = &config.pairing {
+        println!("Pairing code: {}", pairing.current());
+    }
+    if let Some(export) = &config.export {
+        println!("Exporting {:?} for download", export);
+    }
+    if let Some(tokens) = &config.tokens {
+        println!("Accepting {} access tokens", tokens.count());
+    }
+    if config.extract {
+        println!("Extracting zip archives");
+    }
+    let extraction = &config.extraction;
+    println!("Unpacking archives up to {} bytes and {}x compression each", extraction.max_bytes, extraction.max_ratio);
+    if let Some(mode) = config.dedup {
+        let how = if mode == DedupMode::Link { "linking" } else { "copying" };
+        println!("Indexing the output directory by content, {} files it has already instead of receiving them", how);
+    }
+    print_limits(&config.limits);
+    if let Some(name) = &config.name {
+        let announcement = Announcement {
+            name: name.clone(),
+            ip: Some(listener.local_addr()?.ip()).filter(|ip| !ip.is_unspecified()),
+            port: listener.local_addr()?.port(),
+            fingerprint: config.tls.as_ref().map(|tls| tls.fingerprint.to_string()),
+            pairing: config.pairing.is_some(),
+            tokens: config.tokens.is_some(),
+        };
+        println!("Advertising as @{} on the local network", name);
+        let limits = config.limits.clone();
+        tokio::spawn(async move {
+            if let Err(e) = discovery::advertise(announcement, limits).await {
+                say_err!("Warning: not advertising on the local network: {}", e);
+            }
+        });
+    }
+    let index = config.dedup.map(|mode| ContentIndex::build(config.output_root(), mode));
+    let admission = Admission::new(config.limits.clone());
+    let config = Arc::new(config);
+
+    loop {
+        let (socket, peer) = match listener.accept().await {
+            Ok(accepted) => accepted,
+            Err(e) => {
+                // Typically out of file descriptors; give connections a moment to close.
+                say_err!("Could not accept a connection: {}", e);
+                tokio::time::sleep(Duration::from_millis(100)).await;
+                continue;
+            }
+        };
+        // Refused before anything is read, so a refused peer costs next to nothing.
+        let permit = match admission.admit(peer.ip()) {
+            Ok(permit) => permit,
+            Err(refused) => {
+                if refused.report {
+                    say_err!("Refused connection from {}: {}", peer, refused.reason);
+                }
+                continue;
+            }
+        };
+        let config = config.clone();
+        let index = index.clone();
+        tokio::spawn(async move {
+            if let Err(e) = handle_connection(socket, &config, index).await {
+                say_err!("Error in session with {}: {}", peer, e);
+            }
+            drop(permit);
+        });
+    }
+}
+
+fn print_limits(limits: &Limits) {
+    let list = |cidrs: &[Cidr]| cidrs.iter().map(Cidr::to_string).collect::<Vec<_>>().join(", ");
+    if !limits.allow.is_empty() {
+        println!("Only accepting connections from {}", list(&limits.allow));
+    }
+    if !limits.deny.is_empty() {
+        println!("Refusing connections from {}", list(&limits.deny));
+    }
+    let limit = |value: usize| if value == 0 { "unlimited".to_string() } else { value.to_string() };
+    println!(
+        "Connection limits: {} in total, {} per address, {} new per address per minute",
+        limit(limits.max_connections), limit(limits.max_per_address), limit(limits.per_minute)
+    );
+}
+
+/// Serves one session: a batch pushed by the client, one pulled from the export,
+/// a listing of the output directory or a deletion from it.
+async fn handle_connection(socket: TcpStream, config: &ServerConfig, index: Option<Arc<ContentIndex>>) -> tokio::io::Result<()> {
+    let peer_addr = socket.peer_addr()?;
+    let peer = peer_addr.to_string();
+    let mut conn: Box<dyn Conn> = match &config.tls {
+        Some(tls) => tls::accept(tls, socket).await?,
+        None => Box::new(socket),
+    };
+    let hello = protocol::handshake(&mut conn, config.pairing.is_some(), index.is_some()).await?;
+    let keys = match (&config.pairing, hello.pairing) {
+        (Some(code), true) => Some(pair(&mut conn, code, &peer, peer_addr.ip()).await?),
+        (Some(_), false) => return Err(std::io::Error::new(std::io::ErrorKind::PermissionDenied, "client has no pairing code")),
+        (None, true) => return Err(std::io::Error::new(std::io::ErrorKind::PermissionDenied, "client wants to pair, but --code is not in use")),
+        (None, false) => None,
+    };
+    let (reader, writer) = tokio::io::split(conn);
+    let paired = keys.is_some();
+    let session = mux::start(reader, writer, keys, hello.capabilities);
+
+    // A token, if the client has one, comes just before its request.
+    let mut request = session.recv_control().await?;
+    let mut presented = None;
+    if let Message::Auth { token } = request {
+        presented = Some(token);
+        request = session.recv_control().await?;
+    }
+    let operation = match &request {
+        Message::Push => Operation::Push,
+        Message::Pull { .. } => Operation::Pull,
+        Message::List { .. } => Operation::List,
+        Message::Delete { .. } => Operation::Delete,
+        other => return Err(protocol::unexpected(other)),
+    };
+    let token = match authorize(config, operation, presented.as_deref()) {
+        Ok(token) => token,
+        Err(reason) => return refuse(&session, operation, &peer, reason).await,
+    };
+    if let Some(token) = token {
+        say!("Accepted {} from {} with token '{}'", operation.name(), peer, token.label);
+    }
+    let identity = match token {
+        Some(token) => format!("token '{}'", token.label),
+        None if paired => "paired with the code".to_string(),
+        None => "no token".to_string(),
+    };
+    // A token confined to a subdirectory sees it as the whole output directory or export.
+    let scoped = |root: PathBuf| match token.and_then(|token| token.dir.as_ref()) {
+        Some(dir) => paths::resolve(&root, dir),
+        None => Ok(root),
+    };
+
+    match request {
+        Message::Push => {
+            let root = match scoped(config.output_root()) {
+                Ok(root) => root,
+                Err(reason) => return refuse(&session, operation, &peer, reason).await,
+            };
+            accept(&session).await?;
+            let manifest = match Manifest::read(&session).await {
+                Ok(manifest) => manifest,
+                Err(e) if e.kind() == std::io::ErrorKind::PermissionDenied => return refuse(&session, operation, &peer, e.to_string()).await,
+                Err(e) => return Err(e),
+            };
+            let mut approved = None;
+            if let Some(prompt) = &config.ask {
+                if let Err(reason) = prompt.confirm_batch(&peer, &identity, &manifest).await {
+                    say!("Declined batch from {}", peer);
+                    return session.send_control(Message::Status { code: StatusCode::Declined, message: reason }).await;
+                }
+                approved = Some(manifest.into_approved());
+            }
+            let destination = Arc::new(Destination {
+                root,
+                on_conflict: config.on_conflict,
+                max_size: token.and_then(|token| token.max_size),
+                approved,
+                extract: config.extract,
+                limits: config.extraction,
+                preserve: config.preserve,
+                dedup: index,
+            });
+            accept(&session).await?;
+            let summary = receive::receive_batch(&session, destination, &peer).await?;
+            say!(
+                "Batch from {} complete: {} received ({} bytes), {} skipped, {} failed",
+                peer, summary.received, summary.bytes, summary.skipped, summary.failed
+            );
+            Ok(())
+        }
+        Message::Pull { path, archive } => {
+            let root = config.export.clone().ok_or_else(|| "this server does not export any files".to_string()).and_then(scoped);
+            serve_pull(&session, root, &path, archive, config.compression, &peer).await
+        }
+        Message::List { path, hashes } => serve_list(&session, scoped(config.listing_root()), &path, hashes, &peer).await,
+        Message::Delete { path } => serve_delete(&session, scoped(config.output_root()), &path, &peer).await,
+        other => Err(protocol::unexpected(&other)),
+    }
+}
+
+/// Checks the token the client presented, if any, against the server's token
+/// file. Without one, anyone may push, pull and list, but not delete.
+fn authorize<'a>(config: &'a ServerConfig, operation: Operation, presented: Option<&str>) -> Result<Option<&'a Token>, String> {
+    let Some(tokens) = &config.tokens else {
+        if operation == Operation::Delete {
+            return Err("deleting needs a token, and this server does not use any".to_string());
+        }
+        return Ok(None);
+    };
+    let presented = presented.ok_or("this server requires a token; pass it with --token")?;
+    let token = tokens.find(presented).ok_or("unknown token")?;
+    if token.expired() {
+        return Err(format!("token '{}' has expired", token.label));
+    }
+    if !token.allows(operation) {
+        return Err(format!("token '{}' does not allow {}", token.label, operation.name()));
+    }
+    Ok(Some(token))
+}
+
+async fn accept(session: &Session) -> tokio::io::Result<()> {
+    session.send_control(Message::Status { code: StatusCode::Ok, message: String::new() }).await
+}
+
+async fn refuse(session: &Session, operation: Operation, peer: &str, reason: String) -> tokio::io::Result<()> {
+    say_err!("Refused {} from {}: {}", operation.name(), peer, reason);
+    session.send_control(Message::Status { code: StatusCode::Rejected, message: reason }).await
+}
+
+async fn pair(conn: &mut Box<dyn Conn>, code: &PairingCode, peer: &str, ip: IpAddr) -> tokio::io::Result<SessionKeys> {
+    if let Some(wait) = code.locked_out(ip) {
+        let minutes = wait.as_secs().div_ceil(60);
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::PermissionDenied,
+            format!("too many failed pairing attempts from this address; it may try again in {} minute{}", minutes, if minutes == 1 { "" } else { "s" }),
+        ));
+    }
+    let paired = pairing::pair(conn, &code.current(), Side::Server).await;
+    match &paired {
+        Ok(_) => code.succeeded(ip),
+        Err(_) => match code.failed(ip) {
+            Failed::Counted => {}
+            Failed::LockedOut => say_err!("Too many failed pairing attempts from {}; refusing to pair with it for a while", peer),
+            Failed::Replaced(replacement) => {
+                say_err!("Too many failed pairing attempts altogether; the old code no longer works");
+                println!("Pairing code: {}", replacement);
+            }
+        },
+    }
+    paired
+}
+
+/// Sends the requested file or directory from the export, confined to it the
+/// same way incoming names are confined to the output directory.
+async fn serve_pull(
+    session: &Session,
+    root: Result<PathBuf, String>,
+    path: &str,
+    archive: Option<ArchiveFormat>,
+    compression: Compression,
+    peer: &str,
+) -> tokio::io::Result<()> {
+    let entries = match root.and_then(|root| exported_entries(&root, path, archive)) {
+        Ok(entries) => entries,
+        Err(reason) => return refuse(session, Operation::Pull, peer, reason).await,
+    };
+    accept(session).await?;
+    say!("Sending {:?} to {} ({} entries)", path, peer, entries.len());
+    let options = SendOptions { on_conflict: None, compression, xattrs: false, hard_links: false };
+    match archive {
+        Some(format) => {
+            let name = format!("{}.{}", path.rsplit('/').next().unwrap_or(path), format.extension());
+            send::send_archive(session, &name, format, entries, &options, peer).await
+        }
+        None => send::send_batch(session, entries, &options, peer, None).await,
+    }
+}
+
+fn exported_entries(root: &Path, path: &str, archive: Option<ArchiveFormat>) -> Result<Vec<walk::Entry>, String> {
+    let local = paths::sanitize(path).and_then(|relative| paths::resolve(root, &relative))?;
+    if !local.exists() {
+        return Err(format!("'{}' does not exist", path));
+    }
+    let filters = walk::Filters::new(&[], &[])?;
+    // Links inside the export could point anywhere, so they are only ever
+    // sent as links, in tar archives, and otherwise left out.
+    let collected = match archive {
+        Some(ArchiveFormat::Tar) => walk::collect_tree(&[local], &filters),
+        _ => walk::collect_entries(&[local], &filters, walk::Links::Skip),
+    };
+    collected.map_err(|e| format!("'{}': {}", path, e))
+}
+
+/// Lists part of the output directory, confined to it just like uploads.
+async fn serve_list(session: &Session, root: Result<PathBuf, String>, path: &str, hashes: bool, peer: &str) -> tokio::io::Result<()> {
+    let entries = match root {
+        Ok(root) => list::list(&root, path, hashes).await,
+        Err(reason) => Err(reason),
+    };
+    let entries = match entries {
+        Ok(entries) => entries,
+        Err(reason) => return refuse(session, Operation::List, peer, reason).await,
+    };
+    accept(session).await?;
+    for entry in entries {
+        session.send_control(Message::ListEntry(entry)).await?;
+    }
+    session.send_control(Message::Status { code: StatusCode::Ok, message: String::new() }).await
+}
+
+/// Deletes a file, or a directory and everything in it, from the output
+/// directory. The root itself can't be named, so it is never deleted.
+async fn serve_delete(session: &Session, root: Result<PathBuf, String>, path: &str, peer: &str) -> tokio::io::Result<()> {
+    let deleted = async {
+        let relative = paths::sanitize(path.trim_end_matches('/'))?;
+        let local = paths::resolve(&root?, &relative)?;
+        let metadata = tokio::fs::symlink_metadata(&local).await.map_err(|_| format!("'{}' does not exist", path))?;
+        let removed = if metadata.is_dir() {
+            tokio::fs::remove_dir_all(&local).await
+        } else {
+            let removed = tokio::fs::remove_file(&local).await;
+            resume::discard_partial(&local).await;
+            removed
+        };
+        removed.map_err(|e| format!("'{}': {}", path, e))
+    };
+    match deleted.await {
+        Ok(()) => {
+            say!("Deleted {:?} for {}", path, peer);
+            accept(session).await
+        }
+        Err(reason) => refuse(session, Operation::Delete, peer, reason).await,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[cfg(unix)]
+    #[test]
+    fn links_never_lead_out_of_the_export() {
+        let dir = std::env::temp_dir().join(format!("streamline-export-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(dir.join("export/shared")).unwrap();
+        std::fs::create_dir_all(dir.join("private")).unwrap();
+        std::fs::write(dir.join("export/shared/notes"), b"notes").unwrap();
+        std::fs::write(dir.join("private/secret"), b"secret").unwrap();
+        std::os::unix::fs::symlink(dir.join("private"), dir.join("export/shared/private")).unwrap();
+        std::os::unix::fs::symlink("../../private/secret", dir.join("export/shared/secret")).unwrap();
+        let root = dir.join("export");
+
+        for archive in [None, Some(ArchiveFormat::Zip)] {
+            let entries = exported_entries(&root, "shared", archive).unwrap();
+            let names: Vec<_> = entries.iter().map(|entry| entry.name.as_str()).collect();
+            assert_eq!(names, ["shared/notes"]);
+        }
+        // A tar keeps them as links, which the receiver checks; what they point to stays behind.
+        let entries = exported_entries(&root, "shared", Some(ArchiveFormat::Tar)).unwrap();
+        let links: Vec<_> = entries.iter().filter(|entry| entry.kind == walk::EntryKind::Symlink).map(|entry| entry.name.as_str()).collect();
+        assert_eq!(links, ["shared/private", "shared/secret"]);
+        assert!(!entries.iter().any(|entry| entry.name.starts_with("shared/private/")));
+
+        assert!(exported_entries(&root, "shared/private", None).is_err());
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
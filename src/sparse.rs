@@ -0,0 +1,119 @@
+use std::fs::{File, Metadata};
+use std::io::{Read, Seek, SeekFrom, Write};
+use sha2::{Digest, Sha256};
+
+/// Zeros to hash in place of a hole, a block at a time.
+static ZEROS: [u8; 64 * 1024] = [0; 64 * 1024];
+
+/// Finds the holes in a sparse file as it is read, so that they can be skipped
+/// rather than read and sent as zeros.
+pub struct Holes {
+    len: u64,
+    /// Where the data region being read ends and the next hole begins.
+    data_end: u64,
+}
+
+impl Holes {
+    /// Returns `None` unless the file takes up less space than its length, as
+    /// only a file with holes does, and the platform can tell where they are.
+    pub fn find(metadata: &Metadata) -> Option<Self> {
+        #[cfg(any(target_os = "linux", target_os = "android", target_os = "macos", target_os = "freebsd"))]
+        {
+            use std::os::unix::fs::MetadataExt;
+            (metadata.blocks() * 512 < metadata.len()).then_some(Holes { len: metadata.len(), data_end: 0 })
+        }
+        #[cfg(not(any(target_os = "linux", target_os = "android", target_os = "macos", target_os = "freebsd")))]
+        {
+            let _ = metadata;
+            None
+        }
+    }
+
+    /// Called with everything up to `position` read: if a hole starts there,
+    /// returns where the data after it begins, or the end of the file if none
+    /// does. Moves the file's position, so the caller must seek before reading.
+    pub fn skip(&mut self, file: &File, position: u64) -> std::io::Result<Option<u64>> {
+        if position < self.data_end || position >= self.len {
+            return Ok(None);
+        }
+        let data = seek(file, position, Whence::Data)?.unwrap_or(self.len).min(self.len);
+        self.data_end = if data < self.len { seek(file, data, Whence::Hole)?.unwrap_or(self.len).min(self.len) } else { self.len };
+        Ok((data > position).then_some(data))
+    }
+
+    /// How much can be read from `position` before the next hole.
+    pub fn data_left(&self, position: u64) -> u64 {
+        self.data_end.saturating_sub(position)
+    }
+}
+
+enum Whence {
+    Data,
+    Hole,
+}
+
+/// Where the next data or hole at or after `offset` starts, or `None` if
+/// there is no more data.
+#[cfg(any(target_os = "linux", target_os = "android", target_os = "macos", target_os = "freebsd"))]
+fn seek(file: &File, offset: u64, whence: Whence) -> std::io::Result<Option<u64>> {
+    use std::os::fd::AsRawFd;
+    let whence = match whence {
+        Whence::Data => libc::SEEK_DATA,
+        Whence::Hole => libc::SEEK_HOLE,
+    };
+    // SAFETY: lseek only moves the position of a descriptor the file owns.
+    let found = unsafe { libc::lseek(file.as_raw_fd(), offset as libc::off_t, whence) };
+    if found >= 0 {
+        return Ok(Some(found as u64));
+    }
+    let error = std::io::Error::last_os_error();
+    match error.raw_os_error() {
+        Some(libc::ENXIO) => Ok(None),
+        _ => Err(error),
+    }
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "android", target_os = "macos", target_os = "freebsd")))]
+fn seek(_file: &File, _offset: u64, _whence: Whence) -> std::io::Result<Option<u64>> {
+    Ok(None)
+}
+
+/// Adds `len` zeros to `hasher`, for a hole, which keeps the hash over the
+/// file's content however it travels. Run on a blocking thread, since a hole
+/// can be many gigabytes.
+pub async fn hash_zeros(mut hasher: Sha256, len: u64) -> std::io::Result<Sha256> {
+    tokio::task::spawn_blocking(move || {
+        let mut remaining = len;
+        while remaining > 0 {
+            let n = remaining.min(ZEROS.len() as u64) as usize;
+            hasher.update(&ZEROS[..n]);
+            remaining -= n as u64;
+        }
+        hasher
+    })
+    .await
+    .map_err(std::io::Error::other)
+}
+
+/// Copies `reader` into `file`, seeking over runs of zeros instead of writing
+/// them so that they become holes again, and returns how much was copied.
+pub fn copy_sparse(reader: &mut impl Read, file: &mut File) -> std::io::Result<u64> {
+    let mut block = Vec::with_capacity(ZEROS.len());
+    let mut copied = 0;
+    loop {
+        block.clear();
+        let n = (&mut *reader).take(ZEROS.len() as u64).read_to_end(&mut block)?;
+        if n == 0 {
+            break;
+        }
+        if block[..] == ZEROS[..n] {
+            file.seek(SeekFrom::Current(n as i64))?;
+        } else {
+            file.write_all(&block)?;
+        }
+        copied += n as u64;
+    }
+    // A hole at the end is only there once the file is long enough to hold it.
+    file.set_len(copied)?;
+    Ok(copied)
+}
@@ -0,0 +1,189 @@
+use std::io::{Error, ErrorKind};
+use std::path::Path;
+use std::sync::Arc;
+use rustls::client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier};
+use rustls::crypto::{verify_tls12_signature, verify_tls13_signature, CryptoProvider};
+use rustls::pki_types::pem::PemObject;
+use rustls::pki_types::{CertificateDer, PrivateKeyDer, ServerName, UnixTime};
+use rustls::{DigitallySignedStruct, SignatureScheme};
+use sha2::{Digest, Sha256};
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::TcpStream;
+use tokio_rustls::{TlsAcceptor, TlsConnector};
+
+use crate::protocol::HANDSHAKE_TIMEOUT;
+use crate::streamline_dir;
+
+/// Any connection a session can run over: TLS, or plain TCP with `--insecure`.
+pub trait Conn: AsyncRead + AsyncWrite + Unpin + Send {}
+
+impl<T: AsyncRead + AsyncWrite + Unpin + Send> Conn for T {}
+
+/// SHA-256 of a certificate, which identifies a server whether or not its
+/// certificate is self-signed.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub struct Fingerprint([u8; 32]);
+
+impl Fingerprint {
+    fn of(certificate: &CertificateDer) -> Self {
+        Fingerprint(Sha256::digest(certificate).into())
+    }
+
+    /// Accepts the form printed by the server, with or without the `SHA256:`
+    /// prefix and colons.
+    pub fn parse(value: &str) -> Result<Self, String> {
+        let hex: String = value
+            .strip_prefix("SHA256:")
+            .unwrap_or(value)
+            .chars()
+            .filter(|c| *c != ':')
+            .collect();
+        let invalid = || format!("'{}' is not a SHA-256 certificate fingerprint", value);
+        if hex.len() != 64 || !hex.is_ascii() {
+            return Err(invalid());
+        }
+        let mut bytes = [0u8; 32];
+        for (i, byte) in bytes.iter_mut().enumerate() {
+            *byte = u8::from_str_radix(&hex[i * 2..i * 2 + 2], 16).map_err(|_| invalid())?;
+        }
+        Ok(Fingerprint(bytes))
+    }
+}
+
+impl std::fmt::Display for Fingerprint {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        let hex: Vec<String> = self.0.iter().map(|byte| format!("{:02X}", byte)).collect();
+        write!(f, "SHA256:{}", hex.join(":"))
+    }
+}
+
+/// The server's side of TLS: what it accepts connections with, and how clients can recognise it.
+pub struct ServerTls {
+    pub acceptor: TlsAcceptor,
+    pub fingerprint: Fingerprint,
+}
+
+/// Loads the server's certificate and key, or, if neither path is given, the
+/// self-signed pair kept in the Streamline directory, generating it on first use.
+pub fn server(cert: Option<&Path>, key: Option<&Path>) -> std::io::Result<ServerTls> {
+    let (cert_path, key_path) = match (cert, key) {
+        (Some(cert), Some(key)) => (cert.to_path_buf(), key.to_path_buf()),
+        (None, None) => {
+            let dir = streamline_dir();
+            let paths = (dir.join("server-cert.pem"), dir.join("server-key.pem"));
+            if !paths.0.exists() || !paths.1.exists() {
+                generate_certificate(&paths.0, &paths.1)?;
+            }
+            paths
+        }
+        _ => return Err(Error::new(ErrorKind::InvalidInput, "--cert and --key must be given together")),
+    };
+
+    let certs = CertificateDer::pem_file_iter(&cert_path)
+        .and_then(|certs| certs.collect::<Result<Vec<_>, _>>())
+        .map_err(|e| Error::new(ErrorKind::InvalidData, format!("{}: {}", cert_path.display(), e)))?;
+    let key = PrivateKeyDer::from_pem_file(&key_path)
+        .map_err(|e| Error::new(ErrorKind::InvalidData, format!("{}: {}", key_path.display(), e)))?;
+    let fingerprint = certs
+        .first()
+        .map(Fingerprint::of)
+        .ok_or_else(|| Error::new(ErrorKind::InvalidData, format!("{}: no certificate found", cert_path.display())))?;
+
+    let config = rustls::ServerConfig::builder()
+        .with_no_client_auth()
+        .with_single_cert(certs, key)
+        .map_err(|e| Error::new(ErrorKind::InvalidData, e))?;
+    Ok(ServerTls { acceptor: TlsAcceptor::from(Arc::new(config)), fingerprint })
+}
+
+fn generate_certificate(cert_path: &Path, key_path: &Path) -> std::io::Result<()> {
+    let generated = rcgen::generate_simple_self_signed(vec!["streamline".to_string()]).map_err(Error::other)?;
+    if let Some(dir) = cert_path.parent() {
+        std::fs::create_dir_all(dir)?;
+    }
+
+    let mut options = std::fs::OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+    std::io::Write::write_all(&mut options.open(key_path)?, generated.key_pair.serialize_pem().as_bytes())?;
+    std::fs::write(cert_path, generated.cert.pem())?;
+    println!("Generated a self-signed certificate in {}", cert_path.display());
+    Ok(())
+}
+
+pub async fn accept(tls: &ServerTls, socket: TcpStream) -> std::io::Result<Box<dyn Conn>> {
+    let stream = match tokio::time::timeout(HANDSHAKE_TIMEOUT, tls.acceptor.accept(socket)).await {
+        Err(_) => return Err(Error::new(ErrorKind::TimedOut, "no TLS handshake from client")),
+        Ok(result) => result.map_err(|e| {
+            Error::new(e.kind(), format!("TLS handshake failed: {} (is the client using --insecure?)", e))
+        })?,
+    };
+    Ok(Box::new(stream))
+}
+
+/// Opens a TLS connection over `socket` and returns it with the fingerprint of
+/// the certificate the server presented. The certificate itself is not checked
+/// against any authority; pinning its fingerprint is what identifies the server.
+pub async fn connect(socket: TcpStream, address: &str) -> std::io::Result<(Box<dyn Conn>, Fingerprint)> {
+    let config = rustls::ClientConfig::builder()
+        .dangerous()
+        .with_custom_certificate_verifier(Arc::new(AnyCertificate(rustls::crypto::ring::default_provider())))
+        .with_no_client_auth();
+
+    let host = address.rsplit_once(':').map_or(address, |(host, _)| host);
+    let name = ServerName::try_from(host.trim_start_matches('[').trim_end_matches(']').to_string())
+        .unwrap_or_else(|_| ServerName::try_from("streamline").unwrap());
+    let stream = TlsConnector::from(Arc::new(config)).connect(name, socket).await.map_err(|e| {
+        Error::new(e.kind(), format!("TLS handshake failed: {} (the server may have refused this address; if it runs with --insecure, pass --insecure too)", e))
+    })?;
+
+    let fingerprint = stream
+        .get_ref()
+        .1
+        .peer_certificates()
+        .and_then(|certs| certs.first())
+        .map(Fingerprint::of)
+        .ok_or_else(|| Error::new(ErrorKind::InvalidData, "server presented no certificate"))?;
+    Ok((Box::new(stream), fingerprint))
+}
+
+/// Accepts whatever certificate the server presents, but still checks that the
+/// server holds its private key. The caller decides whether to trust it.
+#[derive(Debug)]
+struct AnyCertificate(CryptoProvider);
+
+impl ServerCertVerifier for AnyCertificate {
+    fn verify_server_cert(
+        &self,
+        _end_entity: &CertificateDer,
+        _intermediates: &[CertificateDer],
+        _server_name: &ServerName,
+        _ocsp_response: &[u8],
+        _now: UnixTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        Ok(ServerCertVerified::assertion())
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        verify_tls12_signature(message, cert, dss, &self.0.signature_verification_algorithms)
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        verify_tls13_signature(message, cert, dss, &self.0.signature_verification_algorithms)
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.0.signature_verification_algorithms.supported_schemes()
+    }
+}
@@ -0,0 +1,209 @@
+use std::collections::HashSet;
+use std::path::{Path, PathBuf};
+use globset::{Glob, GlobSet, GlobSetBuilder};
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum EntryKind {
+    File,
+    Directory,
+    /// A symbolic link, sent as a link rather than what it points to.
+    Symlink,
+}
+
+/// What `--links` does with the symbolic links inside the directories being sent.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum Links {
+    /// Send what they point to, as if it were there instead. The default.
+    Follow,
+    /// Recreate them as links, and send files with several names once.
+    Preserve,
+    Skip,
+}
+
+impl Links {
+    pub fn parse(value: &str) -> Result<Self, String> {
+        match value {
+            "follow" => Ok(Links::Follow),
+            "preserve" => Ok(Links::Preserve),
+            "skip" => Ok(Links::Skip),
+            other => Err(format!("unknown --links mode '{}' (expected preserve, follow or skip)", other)),
+        }
+    }
+}
+
+/// Something to send: a local path and the relative, `/`-separated name it is
+/// given on the receiver.
+#[derive(Clone)]
+pub struct Entry {
+    pub path: PathBuf,
+    pub name: String,
+    pub kind: EntryKind,
+}
+
+/// Decides which files are sent. Patterns are matched against the entry's
+/// relative name, so `*.log` matches log files at any depth.
+pub struct Filters {
+    include: Option<GlobSet>,
+    exclude: GlobSet,
+}
+
+impl Filters {
+    pub fn new(include: &[String], exclude: &[String]) -> Result<Self, String> {
+        let include = if include.is_empty() { None } else { Some(build_set(include)?) };
+        Ok(Filters { include, exclude: build_set(exclude)? })
+    }
+
+    fn excludes(&self, name: &str) -> bool {
+        self.exclude.is_match(name)
+    }
+
+    fn includes_file(&self, name: &str) -> bool {
+        !self.excludes(name) && self.include.as_ref().is_none_or(|include| include.is_match(name))
+    }
+}
+
+fn build_set(patterns: &[String]) -> Result<GlobSet, String> {
+    let mut builder = GlobSetBuilder::new();
+    for pattern in patterns {
+        builder.add(Glob::new(pattern).map_err(|e| format!("invalid pattern '{}': {}", pattern, e))?);
+    }
+    builder.build().map_err(|e| e.to_string())
+}
+
+/// Expands the paths given on the command line into the entries to send.
+///
+/// A directory is walked recursively and its files are named relative to the
+/// directory's parent, so `send some/dir` recreates `dir/...` on the receiver.
+/// Directories with nothing in them are sent too, so the tree survives intact.
+/// Symbolic links below the given paths are handled as `links` says.
+pub fn collect_entries<P: AsRef<Path>>(paths: &[P], filters: &Filters, links: Links) -> std::io::Result<Vec<Entry>> {
+    collect(paths, filters, false, links)
+}
+
+/// Like `collect_entries`, but for archives that carry the tree itself: every
+/// directory is listed ahead of what is in it, and symbolic links below the
+/// given paths are listed as links rather than followed.
+pub fn collect_tree<P: AsRef<Path>>(paths: &[P], filters: &Filters) -> std::io::Result<Vec<Entry>> {
+    collect(paths, filters, true, Links::Preserve)
+}
+
+fn collect<P: AsRef<Path>>(paths: &[P], filters: &Filters, tree: bool, links: Links) -> std::io::Result<Vec<Entry>> {
+    let mut entries = Vec::new();
+    let mut visited = HashSet::new();
+    for path in paths {
+        // Made absolute so the batch journal works from any directory, but symlinks
+        // are left alone so a link is sent under its own name.
+        let mut path = std::path::absolute(path)?;
+        if path.file_name().is_none() {
+            path = std::fs::canonicalize(&path)?;
+        }
+        if !path.exists() {
+            return Err(std::io::Error::new(std::io::ErrorKind::NotFound, format!("{}: no such file or directory", path.display())));
+        }
+        let name = path
+            .file_name()
+            .and_then(|name| name.to_str())
+            .filter(|name| !name.chars().any(char::is_control))
+            .ok_or_else(|| std::io::Error::new(std::io::ErrorKind::InvalidInput, format!("{:?} has no usable file name", path)))?
+            .to_string();
+
+        if path.is_dir() {
+            walk(&path, &name, filters, tree, links, &mut visited, &mut entries)?;
+        } else if filters.includes_file(&name) {
+            entries.push(Entry { path, name, kind: EntryKind::File });
+        }
+    }
+    Ok(entries)
+}
+
+/// `visited` holds the directories walked so far, which a followed link can
+/// lead back to, and round in circles if it leads to one above it.
+fn walk(
+    dir: &Path,
+    name: &str,
+    filters: &Filters,
+    tree: bool,
+    links: Links,
+    visited: &mut HashSet<(u64, u64)>,
+    entries: &mut Vec<Entry>,
+) -> std::io::Result<()> {
+    if filters.excludes(name) {
+        return Ok(());
+    }
+    if let Some(id) = identity(dir)? {
+        if !visited.insert(id) {
+            say_err!("Warning: skipping {:?}: it leads to a directory that is already being sent", dir);
+            return Ok(());
+        }
+    }
+
+    let mut children: Vec<_> = std::fs::read_dir(dir)?.collect::<Result<_, _>>()?;
+    if tree || children.is_empty() {
+        entries.push(Entry { path: dir.to_path_buf(), name: name.to_string(), kind: EntryKind::Directory });
+    }
+    if children.is_empty() {
+        return Ok(());
+    }
+    children.sort_by_key(|child| child.file_name());
+
+    for child in children {
+        let Some(child_name) = child.file_name().to_str().map(|n| format!("{}/{}", name, n)) else {
+            say_err!("Warning: skipping {:?}: file name is not valid UTF-8", child.path());
+            continue;
+        };
+        if child_name.chars().any(char::is_control) {
+            say_err!("Warning: skipping {:?}: file name contains control characters", child.path());
+            continue;
+        }
+        let path = child.path();
+        let file_type = child.file_type()?;
+        if file_type.is_symlink() && links != Links::Follow {
+            if links == Links::Preserve && filters.includes_file(&child_name) {
+                entries.push(Entry { path, name: child_name, kind: EntryKind::Symlink });
+            }
+            continue;
+        }
+        let is_dir = if file_type.is_symlink() { path.is_dir() } else { file_type.is_dir() };
+        if is_dir {
+            walk(&path, &child_name, filters, tree, links, visited, entries)?;
+        } else if filters.includes_file(&child_name) {
+            entries.push(Entry { path, name: child_name, kind: EntryKind::File });
+        }
+    }
+    Ok(())
+}
+
+/// The device and inode of a directory, wherever it is reached from.
+#[cfg(unix)]
+fn identity(dir: &Path) -> std::io::Result<Option<(u64, u64)>> {
+    use std::os::unix::fs::MetadataExt;
+    let metadata = std::fs::metadata(dir)?;
+    Ok(Some((metadata.dev(), metadata.ino())))
+}
+
+#[cfg(not(unix))]
+fn identity(_dir: &Path) -> std::io::Result<Option<(u64, u64)>> {
+    Ok(None)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[cfg(unix)]
+    #[test]
+    fn links_back_up_the_tree_are_walked_once() {
+        let dir = std::env::temp_dir().join(format!("streamline-walk-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(dir.join("top/sub")).unwrap();
+        std::fs::write(dir.join("top/sub/file"), b"file").unwrap();
+        std::os::unix::fs::symlink("..", dir.join("top/sub/up")).unwrap();
+        std::os::unix::fs::symlink("sub", dir.join("top/sub-again")).unwrap();
+
+        let filters = Filters::new(&[], &[]).unwrap();
+        let entries = collect_entries(&[dir.join("top")], &filters, Links::Follow).unwrap();
+        let names: Vec<_> = entries.iter().map(|entry| entry.name.as_str()).collect();
+        assert_eq!(names, ["top/sub/file"]);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}